@@ -1,9 +1,6 @@
-pub const PHYS_VIRT_OFFSET: usize = 0;
+pub const PHYS_VIRT_OFFSET: usize = 0xffff_ffc0_0000_0000;
 
-// FIXME: For now, the stack size is not restricted.
-// pub const BOOT_KERNEL_STACK_SIZE: usize = 4096 * 4; // 16K
-pub const KERNEL_HEAP_SIZE: usize = 0x40_0000; // 4M
+pub const MAX_CPUS: usize = 8;
 
-pub const PHYS_MEMORY_BASE: usize = 0x8000_0000;
-pub const PHYS_MEMORY_SIZE: usize = 0x400_0000; // 64M
-pub const PHYS_MEMORY_END: usize = PHYS_MEMORY_BASE + PHYS_MEMORY_SIZE;
+pub const BOOT_KERNEL_STACK_SIZE: usize = 4096 * 16; // 64K, per CPU
+pub const KERNEL_HEAP_SIZE: usize = 0x40_0000; // 4M
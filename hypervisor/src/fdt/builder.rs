@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+
+use super::*;
+use crate::hv::RvmResult;
+use crate::rvm_err;
+
+/// Builds a flattened device tree blob, node by node.
+///
+/// Nodes are opened with [`begin_node`](Self::begin_node) and closed with
+/// [`end_node`](Self::end_node). Properties belong to the innermost open
+/// node. The first node must be the root node, with an empty name.
+pub struct FdtBuilder {
+    struct_block: Vec<u8>,
+    strings_block: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        Self {
+            struct_block: Vec::new(),
+            strings_block: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_token(FDT_BEGIN_NODE);
+        self.struct_block.extend_from_slice(name.as_bytes());
+        self.struct_block.push(0);
+        self.align_struct_block();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    /// Add a property with raw bytes as its value.
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_token(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.struct_block.extend_from_slice(value);
+        self.align_struct_block();
+    }
+
+    /// Add a property with no value, like `interrupt-controller`.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Add a property made of a list of 32-bit cells, like `reg` or
+    /// `interrupts-extended`.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_string_list(name, &[value]);
+    }
+
+    /// Add a property made of a list of NUL-terminated strings, like
+    /// `compatible`.
+    pub fn property_string_list(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Finish the structure block and assemble the whole blob.
+    pub fn finish(mut self) -> RvmResult<Vec<u8>> {
+        if self.depth != 0 {
+            return rvm_err!(BadState, "FDT node not closed");
+        }
+        self.push_token(FDT_END);
+
+        // The memory reservation block only has its terminating entry.
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.struct_block.len();
+        let totalsize = off_dt_strings + self.strings_block.len();
+
+        let mut fdt = Vec::with_capacity(totalsize);
+        for field in [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys: CPU 0 boots.
+            0,
+            self.strings_block.len() as u32,
+            self.struct_block.len() as u32,
+        ] {
+            fdt.extend_from_slice(&field.to_be_bytes());
+        }
+        fdt.extend_from_slice(&[0; 16]);
+        fdt.extend_from_slice(&self.struct_block);
+        fdt.extend_from_slice(&self.strings_block);
+        Ok(fdt)
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.struct_block.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn push_token(&mut self, token: u32) {
+        self.push_u32(token);
+    }
+
+    fn align_struct_block(&mut self) {
+        while self.struct_block.len() % 4 != 0 {
+            self.struct_block.push(0);
+        }
+    }
+
+    /// Find `name` in the strings block, or append it.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings_block.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings_block.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings_block.len();
+        self.strings_block.extend_from_slice(name.as_bytes());
+        self.strings_block.push(0);
+        offset as u32
+    }
+}
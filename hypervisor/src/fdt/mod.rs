@@ -0,0 +1,19 @@
+//! Flattened device tree (FDT) support.
+//!
+//! See the [Devicetree Specification](https://www.devicetree.org/specifications/).
+
+mod builder;
+mod parser;
+
+pub use builder::FdtBuilder;
+pub use parser::{Fdt, FdtNode, FdtProperty};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
@@ -0,0 +1,307 @@
+use core::str;
+
+use super::*;
+use crate::hv::RvmResult;
+use crate::{rvm_err, rvm_err_type};
+
+const FDT_NOP: u32 = 0x4;
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+fn be64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Read a NUL-terminated string at the start of `data`.
+fn cstr(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&b| b == 0)?;
+    str::from_utf8(&data[..len]).ok()
+}
+
+/// A read-only view of a flattened device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    totalsize: usize,
+    struct_block: &'a [u8],
+    strings_block: &'a [u8],
+    mem_rsvmap: &'a [u8],
+    boot_cpuid_phys: u32,
+}
+
+impl<'a> Fdt<'a> {
+    /// Parse the blob at `ptr`, whose size is read from its header.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a device tree blob that stays mapped and unchanged
+    /// for `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> RvmResult<Self> {
+        let header = core::slice::from_raw_parts(ptr, FDT_HEADER_SIZE);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return rvm_err!(InvalidParam, "bad FDT magic");
+        }
+        let totalsize = be32(header, 4).unwrap() as usize;
+        Self::from_bytes(core::slice::from_raw_parts(ptr, totalsize))
+    }
+
+    pub fn from_bytes(data: &'a [u8]) -> RvmResult<Self> {
+        let field = |index: usize| {
+            be32(data, index * 4)
+                .map(|v| v as usize)
+                .ok_or_else(|| rvm_err_type!(InvalidParam, "FDT header truncated"))
+        };
+        if field(0)? != FDT_MAGIC as usize {
+            return rvm_err!(InvalidParam, "bad FDT magic");
+        }
+        if field(6)? > FDT_VERSION as usize {
+            return rvm_err!(Unsupported, "FDT version not supported");
+        }
+        let totalsize = field(1)?;
+        let off_dt_struct = field(2)?;
+        let off_dt_strings = field(3)?;
+        let off_mem_rsvmap = field(4)?;
+        let size_dt_strings = field(8)?;
+        let size_dt_struct = field(9)?;
+
+        let block = |start: usize, size: usize| {
+            data.get(start..start + size)
+                .filter(|_| start + size <= totalsize)
+                .ok_or_else(|| rvm_err_type!(InvalidParam, "FDT block out of range"))
+        };
+        Ok(Self {
+            totalsize,
+            struct_block: block(off_dt_struct, size_dt_struct)?,
+            strings_block: block(off_dt_strings, size_dt_strings)?,
+            mem_rsvmap: block(off_mem_rsvmap, off_dt_struct.saturating_sub(off_mem_rsvmap))?,
+            boot_cpuid_phys: field(7)? as u32,
+        })
+    }
+
+    /// Size of the whole blob in bytes.
+    pub fn total_size(&self) -> usize {
+        self.totalsize
+    }
+
+    pub fn boot_cpuid_phys(&self) -> u32 {
+        self.boot_cpuid_phys
+    }
+
+    /// Entries of the memory reservation block, as (address, size).
+    pub fn mem_reservations(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let rsvmap = self.mem_rsvmap;
+        (0..rsvmap.len() / 16)
+            .map(move |i| {
+                (
+                    be64(rsvmap, i * 16).unwrap(),
+                    be64(rsvmap, i * 16 + 8).unwrap(),
+                )
+            })
+            .take_while(|&(address, size)| address != 0 || size != 0)
+    }
+
+    /// The root node.
+    pub fn root(&self) -> Option<FdtNode<'a>> {
+        match be32(self.struct_block, 0)? {
+            FDT_BEGIN_NODE => self.node_at(0),
+            _ => None,
+        }
+    }
+
+    /// Find a node by its full path, like `/cpus` or `/soc/plic@c000000`.
+    /// A component without a unit address also matches a node with one.
+    pub fn find_node(&self, path: &str) -> Option<FdtNode<'a>> {
+        let mut node = self.root()?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|child| {
+                let name = child.name();
+                name == component || name.split('@').next() == Some(component)
+            })?;
+        }
+        Some(node)
+    }
+
+    fn string_at(&self, offset: usize) -> Option<&'a str> {
+        cstr(self.strings_block.get(offset..)?)
+    }
+
+    /// Parse the node whose `FDT_BEGIN_NODE` token is at `offset`.
+    fn node_at(&self, offset: usize) -> Option<FdtNode<'a>> {
+        let name = cstr(self.struct_block.get(offset + 4..)?)?;
+        Some(FdtNode {
+            fdt: *self,
+            name,
+            props_offset: align4(offset + 4 + name.len() + 1),
+        })
+    }
+
+    /// Return the offset of the token after the property at `offset`.
+    fn skip_prop(&self, offset: usize) -> Option<usize> {
+        let len = be32(self.struct_block, offset + 4)? as usize;
+        Some(align4(offset + 12 + len))
+    }
+
+    /// Return the offset of the token after the node at `offset`, including
+    /// all its descendants.
+    fn skip_node(&self, offset: usize) -> Option<usize> {
+        let mut offset = self.node_at(offset)?.props_offset;
+        let mut depth = 1;
+        while depth > 0 {
+            match be32(self.struct_block, offset)? {
+                FDT_BEGIN_NODE => {
+                    depth += 1;
+                    offset = self.node_at(offset)?.props_offset;
+                }
+                FDT_END_NODE => {
+                    depth -= 1;
+                    offset += 4;
+                }
+                FDT_PROP => offset = self.skip_prop(offset)?,
+                FDT_NOP => offset += 4,
+                _ => return None,
+            }
+        }
+        Some(offset)
+    }
+}
+
+/// A node in a [`Fdt`].
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// Offset of the first token after the node name.
+    props_offset: usize,
+}
+
+/// A property of a [`FdtNode`].
+#[derive(Clone, Copy)]
+pub struct FdtProperty<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> FdtProperty<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        be32(self.value, 0)
+    }
+
+    /// The first string of the value.
+    pub fn as_str(&self) -> Option<&'a str> {
+        cstr(self.value)
+    }
+
+    /// All NUL-terminated strings of the value, like `compatible`.
+    pub fn as_str_list(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| str::from_utf8(s).ok())
+    }
+
+    /// Read a number made of `cells` 32-bit cells at cell `index`.
+    pub fn cells_at(&self, index: usize, cells: usize) -> Option<u64> {
+        (0..cells).try_fold(0u64, |acc, i| {
+            Some((acc << 32) | be32(self.value, (index + i) * 4)? as u64)
+        })
+    }
+}
+
+impl<'a> FdtNode<'a> {
+    /// The node name, including the unit address.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = FdtProperty<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut offset = self.props_offset;
+        core::iter::from_fn(move || loop {
+            match be32(fdt.struct_block, offset)? {
+                FDT_PROP => {
+                    let len = be32(fdt.struct_block, offset + 4)? as usize;
+                    let name_offset = be32(fdt.struct_block, offset + 8)? as usize;
+                    let value = fdt.struct_block.get(offset + 12..offset + 12 + len)?;
+                    offset = align4(offset + 12 + len);
+                    return Some(FdtProperty {
+                        name: fdt.string_at(name_offset)?,
+                        value,
+                    });
+                }
+                FDT_NOP => offset += 4,
+                _ => return None,
+            }
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<FdtProperty<'a>> {
+        self.properties().find(|p| p.name == name)
+    }
+
+    /// Direct children of this node.
+    pub fn children(&self) -> impl Iterator<Item = FdtNode<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut offset = self.props_offset;
+        core::iter::from_fn(move || loop {
+            match be32(fdt.struct_block, offset)? {
+                FDT_BEGIN_NODE => {
+                    let node = fdt.node_at(offset)?;
+                    offset = fdt.skip_node(offset)?;
+                    return Some(node);
+                }
+                FDT_PROP => offset = fdt.skip_prop(offset)?,
+                FDT_NOP => offset += 4,
+                _ => return None,
+            }
+        })
+    }
+
+    /// Whether any string of `compatible` is in `compatibles`.
+    pub fn is_compatible(&self, compatibles: &[&str]) -> bool {
+        self.property("compatible")
+            .is_some_and(|p| p.as_str_list().any(|c| compatibles.contains(&c)))
+    }
+
+    /// Whether the node is enabled, i.e. `status` is missing or `okay`.
+    pub fn is_available(&self) -> bool {
+        self.property("status")
+            .and_then(|p| p.as_str())
+            .is_none_or(|s| s == "okay" || s == "ok")
+    }
+
+    /// Value of `#address-cells` for the children of this node.
+    pub fn address_cells(&self) -> usize {
+        self.property("#address-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(2) as usize
+    }
+
+    /// Value of `#size-cells` for the children of this node.
+    pub fn size_cells(&self) -> usize {
+        self.property("#size-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1) as usize
+    }
+
+    /// Entries of `reg`, as (address, size), decoded with the
+    /// `#address-cells` and `#size-cells` of `parent`.
+    pub fn reg(&self, parent: &FdtNode) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let (address_cells, size_cells) = (parent.address_cells(), parent.size_cells());
+        let entry_cells = address_cells + size_cells;
+        let reg = self.property("reg");
+        let count = reg.map_or(0, |p| p.value.len() / 4 / entry_cells.max(1));
+        (0..count).filter_map(move |i| {
+            let reg = reg?;
+            let address = reg.cells_at(i * entry_cells, address_cells)?;
+            let size = reg.cells_at(i * entry_cells + address_cells, size_cells)?;
+            Some((address as usize, size as usize))
+        })
+    }
+}
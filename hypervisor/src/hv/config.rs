@@ -0,0 +1,39 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{GuestPhysAddr, MachineISAFlags};
+
+/// A memory-mapped device exposed to the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestDeviceConfig {
+    pub base: GuestPhysAddr,
+    pub size: usize,
+    /// Interrupt source number on the guest PLIC.
+    pub irq: u32,
+}
+
+/// The platform-level interrupt controller exposed to the guest.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestPlicConfig {
+    pub base: GuestPhysAddr,
+    pub size: usize,
+    /// Number of interrupt sources, excluding the reserved source 0.
+    pub num_sources: u32,
+}
+
+/// Static description of a VM, used to build its memory and device tree.
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub cpu_count: usize,
+    /// ISA extensions advertised to the guest in `riscv,isa`.
+    pub isa: MachineISAFlags,
+    /// Frequency of the `time` CSR as seen by the guest, in Hz.
+    pub timebase_frequency: u32,
+    /// Guest RAM banks, as (base, size).
+    pub ram: Vec<(GuestPhysAddr, usize)>,
+    /// Kernel command line, passed in `/chosen/bootargs`.
+    pub bootargs: String,
+    pub plic: Option<GuestPlicConfig>,
+    pub uart: Option<GuestDeviceConfig>,
+    pub virtio_mmio: Vec<GuestDeviceConfig>,
+}
@@ -0,0 +1,73 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use super::MmioDevice;
+use crate::hv::{GuestPhysAddr, RvmResult};
+use crate::{rvm_err, rvm_err_type};
+
+struct MmioRange {
+    size: usize,
+    device: Arc<dyn MmioDevice>,
+}
+
+/// The emulated devices of a VM, keyed by the guest physical range they
+/// claim.
+#[derive(Default)]
+pub struct MmioBus {
+    ranges: BTreeMap<GuestPhysAddr, MmioRange>,
+}
+
+impl MmioBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Dispatch accesses to `[base, base + size)` to `device`.
+    pub fn register(
+        &mut self,
+        base: GuestPhysAddr,
+        size: usize,
+        device: Arc<dyn MmioDevice>,
+    ) -> RvmResult {
+        if size == 0 {
+            return rvm_err!(InvalidParam, "empty MMIO range");
+        }
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "MMIO range overflows"))?;
+        let overlapped = self
+            .ranges
+            .range(..end)
+            .next_back()
+            .is_some_and(|(&start, range)| base < start + range.size);
+        if overlapped {
+            return rvm_err!(AlreadyExists, "MMIO range overlapped");
+        }
+        self.ranges.insert(base, MmioRange { size, device });
+        Ok(())
+    }
+
+    /// Find the device that claims `gpa`, and the offset of `gpa` in it.
+    pub fn find(&self, gpa: GuestPhysAddr) -> Option<(&Arc<dyn MmioDevice>, usize)> {
+        let (&start, range) = self.ranges.range(..=gpa).next_back()?;
+        (gpa - start < range.size).then(|| (&range.device, gpa - start))
+    }
+
+    fn find_or_err(&self, gpa: GuestPhysAddr) -> RvmResult<(&Arc<dyn MmioDevice>, usize)> {
+        self.find(gpa).ok_or_else(|| {
+            rvm_err_type!(InvalidParam, format_args!("no MMIO device at {:#x}", gpa))
+        })
+    }
+
+    /// Read `width` bytes at `gpa` from the device that claims it.
+    pub fn read(&self, gpa: GuestPhysAddr, width: usize) -> RvmResult<u64> {
+        let (device, offset) = self.find_or_err(gpa)?;
+        device.read(offset, width)
+    }
+
+    /// Write `width` bytes of `value` at `gpa` to the device that claims it.
+    pub fn write(&self, gpa: GuestPhysAddr, width: usize, value: u64) -> RvmResult {
+        let (device, offset) = self.find_or_err(gpa)?;
+        device.write(offset, width, value)
+    }
+}
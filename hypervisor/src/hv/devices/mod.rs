@@ -0,0 +1,30 @@
+//! Devices emulated by the hypervisor for guests.
+
+mod bus;
+mod plic;
+mod uart;
+pub mod virtio;
+
+pub use bus::MmioBus;
+pub use plic::{plic_size, VirtPlic};
+pub use uart::VirtUart16550;
+
+use crate::hv::RvmResult;
+
+/// A device whose registers are emulated on guest accesses to an unmapped
+/// range of guest physical memory.
+pub trait MmioDevice: Send + Sync {
+    /// Read `width` bytes (1, 2, 4 or 8) at `offset` from the start of the
+    /// device.
+    fn read(&self, offset: usize, width: usize) -> RvmResult<u64>;
+
+    /// Write the low `width` bytes of `value` at `offset` from the start of
+    /// the device.
+    fn write(&self, offset: usize, width: usize, value: u64) -> RvmResult;
+}
+
+/// Receives the interrupt lines of emulated devices, like [`VirtPlic`].
+pub trait IrqSink: Send + Sync {
+    /// Drive the line of interrupt source `irq` high or low.
+    fn set_irq(&self, irq: u32, level: bool);
+}
@@ -0,0 +1,288 @@
+//! Software model of the SiFive platform-level interrupt controller.
+//!
+//! Context `i` is the S-mode context of vCPU `i`, as described in the guest
+//! device tree, and is delivered to it as `VSEIP`. Sources are level
+//! triggered: a source is pending while its line is high and it is not being
+//! serviced, i.e. claimed and not yet completed.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{IrqSink, MmioDevice};
+use crate::hv::RvmResult;
+use crate::rvm_err;
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// Implemented bits of source priorities and context thresholds.
+const PRIORITY_MASK: u32 = 0x7;
+
+/// Size of the register space for `num_contexts` contexts.
+pub const fn plic_size(num_contexts: usize) -> usize {
+    CONTEXT_BASE + num_contexts * CONTEXT_STRIDE
+}
+
+/// Called with a context and its new interrupt line level.
+type ContextNotifier = Box<dyn Fn(usize, bool) + Send + Sync>;
+
+struct PlicContext {
+    /// One bit per source.
+    enable: Vec<u32>,
+    threshold: u32,
+    /// Level of the interrupt line, as last notified.
+    line: bool,
+}
+
+struct PlicState {
+    /// Indexed by source, source 0 does not exist.
+    priority: Vec<u32>,
+    /// One bit per source, for each of `level`, `pending` and `claimed`.
+    level: Vec<u32>,
+    pending: Vec<u32>,
+    claimed: Vec<u32>,
+    contexts: Vec<PlicContext>,
+}
+
+fn test_bit(bits: &[u32], index: usize) -> bool {
+    bits[index / 32] & (1 << (index % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32], index: usize, value: bool) {
+    if value {
+        bits[index / 32] |= 1 << (index % 32);
+    } else {
+        bits[index / 32] &= !(1 << (index % 32));
+    }
+}
+
+impl PlicState {
+    /// The pending and enabled source of the highest priority above the
+    /// threshold of `context`, the lowest ID among equals.
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let ctx = &self.contexts[context];
+        let mut best = None;
+        let mut best_priority = ctx.threshold;
+        for src in 1..self.priority.len() {
+            if test_bit(&self.pending, src)
+                && test_bit(&ctx.enable, src)
+                && self.priority[src] > best_priority
+            {
+                best = Some(src);
+                best_priority = self.priority[src];
+            }
+        }
+        best
+    }
+
+    /// Recompute the line of every context, and return those that changed.
+    fn update(&mut self) -> Vec<(usize, bool)> {
+        let mut changed = Vec::new();
+        for context in 0..self.contexts.len() {
+            let line = self.best_source(context).is_some();
+            if self.contexts[context].line != line {
+                self.contexts[context].line = line;
+                changed.push((context, line));
+            }
+        }
+        changed
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(src) => {
+                set_bit(&mut self.pending, src, false);
+                set_bit(&mut self.claimed, src, true);
+                src as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, src: usize) {
+        // Completions of sources not enabled for the context are ignored.
+        if src == 0 || src >= self.priority.len() || !test_bit(&self.contexts[context].enable, src)
+        {
+            return;
+        }
+        set_bit(&mut self.claimed, src, false);
+        if test_bit(&self.level, src) {
+            set_bit(&mut self.pending, src, true);
+        }
+    }
+}
+
+/// An emulated PLIC, shared by all vCPUs of a VM and the devices that raise
+/// interrupts.
+pub struct VirtPlic {
+    num_sources: usize,
+    state: Mutex<PlicState>,
+    notify: ContextNotifier,
+}
+
+impl VirtPlic {
+    /// Create a PLIC with sources `1..=num_sources` and `num_contexts`
+    /// contexts. `notify` is called when the interrupt line of a context
+    /// changes.
+    pub fn new(
+        num_sources: usize,
+        num_contexts: usize,
+        notify: impl Fn(usize, bool) + Send + Sync + 'static,
+    ) -> Self {
+        let words = (num_sources + 1).div_ceil(32);
+        let contexts = (0..num_contexts)
+            .map(|_| PlicContext {
+                enable: vec![0; words],
+                threshold: 0,
+                line: false,
+            })
+            .collect();
+        Self {
+            num_sources,
+            state: Mutex::new(PlicState {
+                priority: vec![0; num_sources + 1],
+                level: vec![0; words],
+                pending: vec![0; words],
+                claimed: vec![0; words],
+                contexts,
+            }),
+            notify: Box::new(notify),
+        }
+    }
+
+    pub fn num_sources(&self) -> usize {
+        self.num_sources
+    }
+
+    /// Run `f` on the state, then notify the contexts whose line changed,
+    /// without the lock held.
+    fn with_state<R>(&self, f: impl FnOnce(&mut PlicState) -> R) -> R {
+        let (ret, changed) = {
+            let mut state = self.state.lock();
+            let ret = f(&mut state);
+            (ret, state.update())
+        };
+        for (context, line) in changed {
+            (self.notify)(context, line);
+        }
+        ret
+    }
+}
+
+impl MmioDevice for VirtPlic {
+    /// Read the 32-bit register at `offset`.
+    fn read(&self, offset: usize, width: usize) -> RvmResult<u64> {
+        if width != 4 || offset % 4 != 0 {
+            return rvm_err!(InvalidParam, "PLIC access not 32-bit");
+        }
+        let value = self.with_state(|state| {
+            let words = state.pending.len();
+            let num_contexts = state.contexts.len();
+            match offset {
+                PRIORITY_BASE..PENDING_BASE => {
+                    let src = (offset - PRIORITY_BASE) / 4;
+                    state.priority.get(src).copied().unwrap_or(0)
+                }
+                PENDING_BASE..ENABLE_BASE => {
+                    let word = (offset - PENDING_BASE) / 4;
+                    state.pending.get(word).copied().unwrap_or(0)
+                }
+                ENABLE_BASE..CONTEXT_BASE => {
+                    let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                    let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                    match state.contexts.get(context) {
+                        Some(ctx) if word < words => ctx.enable[word],
+                        _ => 0,
+                    }
+                }
+                _ => {
+                    let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                    if context >= num_contexts {
+                        return 0;
+                    }
+                    match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                        CONTEXT_THRESHOLD => state.contexts[context].threshold,
+                        CONTEXT_CLAIM => state.claim(context),
+                        _ => 0,
+                    }
+                }
+            }
+        });
+        Ok(value as u64)
+    }
+
+    /// Write the 32-bit register at `offset`. Read-only and reserved
+    /// registers ignore writes.
+    fn write(&self, offset: usize, width: usize, value: u64) -> RvmResult {
+        if width != 4 || offset % 4 != 0 {
+            return rvm_err!(InvalidParam, "PLIC access not 32-bit");
+        }
+        let value = value as u32;
+        // Only sources `1..=num_sources` exist.
+        let num_sources = self.num_sources;
+        let source_mask = |word: usize| {
+            (0..32)
+                .filter(|bit| (1..=num_sources).contains(&(word * 32 + bit)))
+                .fold(0u32, |mask, bit| mask | 1 << bit)
+        };
+        self.with_state(|state| {
+            let words = state.pending.len();
+            let num_contexts = state.contexts.len();
+            match offset {
+                PRIORITY_BASE..PENDING_BASE => {
+                    let src = (offset - PRIORITY_BASE) / 4;
+                    if src != 0 && src < state.priority.len() {
+                        state.priority[src] = value & PRIORITY_MASK;
+                    }
+                }
+                PENDING_BASE..ENABLE_BASE => {}
+                ENABLE_BASE..CONTEXT_BASE => {
+                    let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                    let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                    if context < num_contexts && word < words {
+                        state.contexts[context].enable[word] = value & source_mask(word);
+                    }
+                }
+                _ => {
+                    let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                    if context >= num_contexts {
+                        return;
+                    }
+                    match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                        CONTEXT_THRESHOLD => {
+                            state.contexts[context].threshold = value & PRIORITY_MASK
+                        }
+                        CONTEXT_CLAIM => state.complete(context, value as usize),
+                        _ => {}
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl IrqSink for VirtPlic {
+    fn set_irq(&self, irq: u32, level: bool) {
+        let src = irq as usize;
+        if src == 0 || src > self.num_sources {
+            warn!("[RVM] PLIC source {} out of range", irq);
+            return;
+        }
+        self.with_state(|state| {
+            set_bit(&mut state.level, src, level);
+            if !test_bit(&state.claimed, src) {
+                set_bit(&mut state.pending, src, level);
+            }
+        });
+    }
+}
@@ -0,0 +1,282 @@
+//! Software model of an NS16550A UART, the guest console.
+//!
+//! Transmitted bytes reach the console sink at once, so the transmitter is
+//! always empty. Received bytes are queued in a 16-byte FIFO by the host
+//! console. Registers are 8 bits wide, with a register shift of 0.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{IrqSink, MmioDevice};
+use crate::hv::RvmResult;
+use crate::rvm_err;
+
+/// Receiver buffer (read), transmitter holding (write), or divisor latch LSB
+/// with `LCR.DLAB`.
+const UART_RBR_THR_DLL: usize = 0;
+/// Interrupt enable, or divisor latch MSB with `LCR.DLAB`.
+const UART_IER_DLM: usize = 1;
+/// Interrupt identification (read), or FIFO control (write).
+const UART_IIR_FCR: usize = 2;
+const UART_LCR: usize = 3;
+const UART_MCR: usize = 4;
+const UART_LSR: usize = 5;
+const UART_MSR: usize = 6;
+const UART_SCR: usize = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE_FIFO: u8 = 1 << 0;
+const FCR_CLEAR_RCVR: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+const RX_FIFO_SIZE: usize = 16;
+/// Output is passed to the console sink by lines, or when this much is
+/// buffered.
+const TX_BUFFER_SIZE: usize = 128;
+
+/// Called with guest console output.
+type ConsoleSink = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+#[derive(Default)]
+struct UartState {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    fifo_enabled: bool,
+    /// The transmitter empty interrupt, raised when the transmitter becomes
+    /// empty and cleared by reading it in `IIR` or writing `THR`.
+    thr_empty: bool,
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    /// Level of the interrupt line, as last driven.
+    line: bool,
+}
+
+impl UartState {
+    fn iir(&self) -> u8 {
+        let id = if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thr_empty {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        };
+        match self.fifo_enabled {
+            true => id | IIR_FIFO_ENABLED,
+            false => id,
+        }
+    }
+
+    fn lsr(&self) -> u8 {
+        let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+        dr | LSR_THRE | LSR_TEMT
+    }
+
+    /// Modem status: the modem control outputs in loopback mode, otherwise a
+    /// peer that is always ready.
+    fn msr(&self) -> u8 {
+        if self.mcr & MCR_LOOP == 0 {
+            return MSR_DCD | MSR_DSR | MSR_CTS;
+        }
+        [
+            (MCR_RTS, MSR_CTS),
+            (MCR_DTR, MSR_DSR),
+            (MCR_OUT1, MSR_RI),
+            (MCR_OUT2, MSR_DCD),
+        ]
+        .iter()
+        .filter(|(mcr, _)| self.mcr & mcr != 0)
+        .fold(0, |msr, (_, bit)| msr | bit)
+    }
+
+    fn push_rx(&mut self, byte: u8) -> bool {
+        if self.rx.len() >= RX_FIFO_SIZE {
+            return false;
+        }
+        self.rx.push_back(byte);
+        true
+    }
+
+    /// Transmit `byte`, and return whether the output should be flushed.
+    fn transmit(&mut self, byte: u8) -> bool {
+        self.thr_empty = true;
+        if self.mcr & MCR_LOOP != 0 {
+            self.push_rx(byte);
+            return false;
+        }
+        self.tx.push(byte);
+        byte == b'\n' || self.tx.len() >= TX_BUFFER_SIZE
+    }
+
+    /// Recompute the interrupt line, and return it if it changed.
+    fn update(&mut self) -> Option<bool> {
+        let line = self.iir() & IIR_NO_INT == 0;
+        if self.line != line {
+            self.line = line;
+            return Some(line);
+        }
+        None
+    }
+}
+
+/// An emulated 16550 UART, with its interrupt routed to source `irq` of an
+/// interrupt controller.
+pub struct VirtUart16550 {
+    irq: u32,
+    irq_sink: Option<Arc<dyn IrqSink>>,
+    state: Mutex<UartState>,
+    output: ConsoleSink,
+}
+
+impl VirtUart16550 {
+    /// Create a UART that raises source `irq` of `irq_sink`, if any, and
+    /// writes to `output`.
+    pub fn new(
+        irq: u32,
+        irq_sink: Option<Arc<dyn IrqSink>>,
+        output: impl Fn(&[u8]) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            irq,
+            irq_sink,
+            state: Mutex::new(UartState::default()),
+            output: Box::new(output),
+        }
+    }
+
+    /// Run `f` on the state, then drive the interrupt line and flush the
+    /// output if asked to, without the lock held.
+    fn with_state<R>(&self, f: impl FnOnce(&mut UartState) -> (R, bool)) -> R {
+        let (ret, output, line) = {
+            let mut state = self.state.lock();
+            let (ret, flush) = f(&mut state);
+            let output = match flush {
+                true => core::mem::take(&mut state.tx),
+                false => Vec::new(),
+            };
+            (ret, output, state.update())
+        };
+        if !output.is_empty() {
+            (self.output)(&output);
+        }
+        if let (Some(line), Some(sink)) = (line, &self.irq_sink) {
+            sink.set_irq(self.irq, line);
+        }
+        ret
+    }
+
+    /// Whether the receive FIFO has room for another byte.
+    pub fn can_receive(&self) -> bool {
+        self.state.lock().rx.len() < RX_FIFO_SIZE
+    }
+
+    /// Queue `byte` of console input, returns `false` if the receive FIFO is
+    /// full.
+    pub fn receive(&self, byte: u8) -> bool {
+        self.with_state(|state| (state.push_rx(byte), false))
+    }
+
+    /// Pass buffered output to the console sink.
+    pub fn flush(&self) {
+        self.with_state(|_| ((), true))
+    }
+}
+
+impl MmioDevice for VirtUart16550 {
+    fn read(&self, offset: usize, width: usize) -> RvmResult<u64> {
+        if width != 1 {
+            return rvm_err!(InvalidParam, "UART access not 8-bit");
+        }
+        let value = self.with_state(|state| {
+            let dlab = state.lcr & LCR_DLAB != 0;
+            let value = match offset {
+                UART_RBR_THR_DLL if dlab => state.dll,
+                UART_RBR_THR_DLL => state.rx.pop_front().unwrap_or(0),
+                UART_IER_DLM if dlab => state.dlm,
+                UART_IER_DLM => state.ier,
+                UART_IIR_FCR => {
+                    let iir = state.iir();
+                    if iir & !IIR_FIFO_ENABLED == IIR_THRI {
+                        state.thr_empty = false;
+                    }
+                    iir
+                }
+                UART_LCR => state.lcr,
+                UART_MCR => state.mcr,
+                UART_LSR => state.lsr(),
+                UART_MSR => state.msr(),
+                UART_SCR => state.scr,
+                _ => 0,
+            };
+            (value, false)
+        });
+        Ok(value as u64)
+    }
+
+    fn write(&self, offset: usize, width: usize, value: u64) -> RvmResult {
+        if width != 1 {
+            return rvm_err!(InvalidParam, "UART access not 8-bit");
+        }
+        let value = value as u8;
+        self.with_state(|state| {
+            let dlab = state.lcr & LCR_DLAB != 0;
+            let mut flush = false;
+            match offset {
+                UART_RBR_THR_DLL if dlab => state.dll = value,
+                UART_RBR_THR_DLL => flush = state.transmit(value),
+                UART_IER_DLM if dlab => state.dlm = value,
+                UART_IER_DLM => {
+                    // Enabling the interrupt of an empty transmitter raises it.
+                    if state.ier & IER_THRI == 0 && value & IER_THRI != 0 {
+                        state.thr_empty = true;
+                    }
+                    state.ier = value & IER_MASK;
+                }
+                UART_IIR_FCR => {
+                    state.fifo_enabled = value & FCR_ENABLE_FIFO != 0;
+                    if value & FCR_CLEAR_RCVR != 0 {
+                        state.rx.clear();
+                    }
+                }
+                UART_LCR => state.lcr = value,
+                UART_MCR => state.mcr = value & MCR_MASK,
+                UART_SCR => state.scr = value,
+                _ => {}
+            }
+            ((), flush)
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+//! The virtio console device, with multiport support.
+//!
+//! Without `VIRTIO_CONSOLE_F_MULTIPORT` only port 0 exists. With it, the
+//! driver discovers the ports through control messages, and each port has a
+//! receive and a transmit queue.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{DescChain, QueueContext, VirtioDevice};
+use crate::hv::host_console::{self, GuestConsole};
+use crate::hv::RvmResult;
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Size of `struct virtio_console_control`: `id: u32, event: u16, value: u16`.
+const CONTROL_MSG_SIZE: usize = 8;
+
+/// Most input passed to the guest in one receive buffer.
+const RX_CHUNK_SIZE: usize = 256;
+
+const PORT0_RX_QUEUE: usize = 0;
+const PORT0_TX_QUEUE: usize = 1;
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+/// Receive queue of port `port`, its transmit queue is the next one.
+const fn rx_queue(port: usize) -> usize {
+    match port {
+        0 => PORT0_RX_QUEUE,
+        _ => 2 * (port + 1),
+    }
+}
+
+/// The host end of a console port.
+pub trait ConsolePort: Send + Sync {
+    /// Name given to the driver, e.g. to be found in `/dev/virtio-ports`.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether the port is a console, `hvc` in Linux.
+    fn is_console(&self) -> bool {
+        false
+    }
+
+    /// Output of the guest.
+    fn write(&self, data: &[u8]);
+
+    /// Fill `buf` with input for the guest, returns how many bytes were read.
+    fn read(&self, buf: &mut [u8]) -> usize;
+}
+
+/// The console port of the guest, connected to the host console.
+pub struct HostConsolePort;
+
+impl ConsolePort for HostConsolePort {
+    fn is_console(&self) -> bool {
+        true
+    }
+
+    fn write(&self, data: &[u8]) {
+        host_console::write(GuestConsole::Virtio, data);
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        host_console::read(GuestConsole::Virtio, buf)
+    }
+}
+
+/// A named port that buffers data in both directions, for the hypervisor to
+/// exchange messages with an agent in the guest. The hypervisor end is shared
+/// with the device, see [`crate::hv::mgmt`].
+pub struct ChannelPort {
+    name: &'static str,
+    /// Received from the guest.
+    input: Mutex<VecDeque<u8>>,
+    /// To be sent to the guest.
+    output: Mutex<VecDeque<u8>>,
+}
+
+impl ChannelPort {
+    /// Bytes buffered in each direction, beyond which new data is dropped.
+    const BUFFER_SIZE: usize = 4096;
+
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            input: Mutex::new(VecDeque::new()),
+            output: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Queue `data` for the guest, returns how many bytes fit.
+    pub fn send(&self, data: &[u8]) -> usize {
+        let mut output = self.output.lock();
+        let len = data.len().min(Self::BUFFER_SIZE - output.len());
+        output.extend(&data[..len]);
+        len
+    }
+
+    /// Take what the guest sent.
+    pub fn recv(&self) -> Vec<u8> {
+        self.input.lock().drain(..).collect()
+    }
+}
+
+impl ConsolePort for ChannelPort {
+    fn name(&self) -> Option<&str> {
+        Some(self.name)
+    }
+
+    fn write(&self, data: &[u8]) {
+        let mut input = self.input.lock();
+        let len = data.len().min(Self::BUFFER_SIZE - input.len());
+        input.extend(&data[..len]);
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut output = self.output.lock();
+        let len = buf.len().min(output.len());
+        for (dst, src) in buf.iter_mut().zip(output.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+}
+
+struct ConsoleState {
+    /// Control messages waiting for a buffer on the control receive queue.
+    control_out: VecDeque<Vec<u8>>,
+    /// Per port, a receive buffer taken from the driver but not filled yet.
+    rx_chains: Vec<Option<DescChain>>,
+}
+
+/// A virtio console whose ports are connected to host ends.
+pub struct VirtioConsole {
+    ports: Vec<Arc<dyn ConsolePort>>,
+    state: Mutex<ConsoleState>,
+}
+
+fn control_msg(id: usize, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CONTROL_MSG_SIZE);
+    msg.extend_from_slice(&(id as u32).to_le_bytes());
+    msg.extend_from_slice(&event.to_le_bytes());
+    msg.extend_from_slice(&value.to_le_bytes());
+    msg
+}
+
+impl VirtioConsole {
+    /// Create a console with `ports`, port 0 being the first one. Ports
+    /// beyond port 0 are only seen by drivers that support multiport.
+    pub fn new(ports: Vec<Arc<dyn ConsolePort>>) -> Self {
+        let num_ports = ports.len();
+        assert!(num_ports > 0);
+        Self {
+            ports,
+            state: Mutex::new(ConsoleState {
+                control_out: VecDeque::new(),
+                rx_chains: (0..num_ports).map(|_| None).collect(),
+            }),
+        }
+    }
+
+    fn num_active_ports(&self, ctx: &QueueContext) -> usize {
+        match ctx.driver_features() & VIRTIO_CONSOLE_F_MULTIPORT {
+            0 => 1,
+            _ => self.ports.len(),
+        }
+    }
+
+    /// Pass the input of `port` to the guest while it has receive buffers.
+    fn receive(&self, state: &mut ConsoleState, port: usize, ctx: &mut QueueContext) -> RvmResult {
+        let queue = rx_queue(port);
+        loop {
+            let chain = match state.rx_chains[port].take() {
+                Some(chain) => chain,
+                None => match ctx.pop(queue)? {
+                    Some(chain) => chain,
+                    None => return Ok(()),
+                },
+            };
+            let mut buf = [0; RX_CHUNK_SIZE];
+            let max_len = chain.writable_len().min(RX_CHUNK_SIZE);
+            if max_len == 0 {
+                // Nothing fits, give it back rather than holding the port.
+                ctx.push_used(queue, &chain, 0)?;
+                continue;
+            }
+            let len = self.ports[port].read(&mut buf[..max_len]);
+            if len == 0 {
+                state.rx_chains[port] = Some(chain);
+                return Ok(());
+            }
+            let written = chain.write_all(ctx.mem_mut(), &buf[..len])?;
+            ctx.push_used(queue, &chain, written as u32)?;
+        }
+    }
+
+    /// Pass the output of the guest on `port` to its host end.
+    fn transmit(&self, port: usize, ctx: &mut QueueContext) -> RvmResult {
+        let queue = rx_queue(port) + 1;
+        while let Some(chain) = ctx.pop(queue)? {
+            self.ports[port].write(&chain.read_all(ctx.mem())?);
+            ctx.push_used(queue, &chain, 0)?;
+        }
+        Ok(())
+    }
+
+    fn handle_control(&self, state: &mut ConsoleState, msg: &[u8]) {
+        if msg.len() < CONTROL_MSG_SIZE {
+            return;
+        }
+        let id = u32::from_le_bytes(msg[0..4].try_into().unwrap()) as usize;
+        let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+        let out = &mut state.control_out;
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() {
+                    out.push_back(control_msg(port, VIRTIO_CONSOLE_DEVICE_ADD, 1));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && id < self.ports.len() => {
+                let port = &self.ports[id];
+                if port.is_console() {
+                    out.push_back(control_msg(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                }
+                if let Some(name) = port.name() {
+                    let mut msg = control_msg(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    msg.extend_from_slice(name.as_bytes());
+                    out.push_back(msg);
+                }
+                // The host end is always connected.
+                out.push_back(control_msg(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle the control messages of the driver.
+    fn control_transmit(&self, state: &mut ConsoleState, ctx: &mut QueueContext) -> RvmResult {
+        while let Some(chain) = ctx.pop(CONTROL_TX_QUEUE)? {
+            self.handle_control(state, &chain.read_all(ctx.mem())?);
+            ctx.push_used(CONTROL_TX_QUEUE, &chain, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Send queued control messages while the driver has buffers for them.
+    fn control_receive(&self, state: &mut ConsoleState, ctx: &mut QueueContext) -> RvmResult {
+        while !state.control_out.is_empty() {
+            let Some(chain) = ctx.pop(CONTROL_RX_QUEUE)? else {
+                break;
+            };
+            let msg = state.control_out.pop_front().unwrap();
+            let written = chain.write_all(ctx.mem_mut(), &msg)?;
+            ctx.push_used(CONTROL_RX_QUEUE, &chain, written as u32)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn num_queues(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    /// `struct virtio_console_config`: `cols: u16, rows: u16,
+    /// max_nr_ports: u32, emerg_wr: u32`.
+    fn read_config(&self, offset: usize, width: usize) -> u64 {
+        let mut config = [0; 12];
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        let mut value = [0; 8];
+        if let Some(bytes) = config.get(offset..offset + width.min(8)) {
+            value[..bytes.len()].copy_from_slice(bytes);
+        }
+        u64::from_le_bytes(value)
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        state.control_out.clear();
+        state.rx_chains.iter_mut().for_each(|chain| *chain = None);
+    }
+
+    fn queue_notify(&self, queue: usize, ctx: &mut QueueContext) -> RvmResult {
+        let mut state = self.state.lock();
+        let multiport = ctx.driver_features() & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        match queue {
+            CONTROL_RX_QUEUE if multiport => self.control_receive(&mut state, ctx),
+            CONTROL_TX_QUEUE if multiport => {
+                self.control_transmit(&mut state, ctx)?;
+                self.control_receive(&mut state, ctx)
+            }
+            CONTROL_RX_QUEUE | CONTROL_TX_QUEUE => Ok(()),
+            _ => {
+                let port = match queue {
+                    PORT0_RX_QUEUE | PORT0_TX_QUEUE => 0,
+                    _ => queue / 2 - 1,
+                };
+                if port >= self.num_active_ports(ctx) {
+                    return Ok(());
+                }
+                match queue == rx_queue(port) {
+                    true => self.receive(&mut state, port, ctx),
+                    false => self.transmit(port, ctx),
+                }
+            }
+        }
+    }
+
+    fn poll(&self, ctx: &mut QueueContext) -> RvmResult {
+        let mut state = self.state.lock();
+        for port in 0..self.num_active_ports(ctx) {
+            self.receive(&mut state, port, ctx)?;
+        }
+        if ctx.driver_features() & VIRTIO_CONSOLE_F_MULTIPORT != 0 {
+            self.control_receive(&mut state, ctx)?;
+        }
+        Ok(())
+    }
+}
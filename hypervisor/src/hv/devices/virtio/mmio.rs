@@ -0,0 +1,303 @@
+//! The virtio-mmio transport, version 2 (non-legacy).
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::queue::{QueueContext, Virtqueue, QUEUE_SIZE_MAX};
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+use crate::hv::devices::{IrqSink, MmioDevice};
+use crate::hv::RvmResult;
+use crate::mm::GuestPhysMemorySet;
+use crate::rvm_err;
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
+const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+/// "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+/// "RVM" in little endian.
+const VENDOR_ID: u32 = 0x004d_5652;
+
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+struct TransportState {
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    interrupt_status: u32,
+    /// Level of the interrupt line, as last driven.
+    line: bool,
+}
+
+impl TransportState {
+    fn new(num_queues: usize) -> Self {
+        Self {
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: (0..num_queues).map(|_| Virtqueue::new()).collect(),
+            interrupt_status: 0,
+            line: false,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_DEVICE_NEEDS_RESET == 0
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+}
+
+/// Replace the low or high half of `value` with `half`.
+fn set_half(value: &mut usize, half: u32, high: bool) {
+    *value = match high {
+        true => (*value & 0xffff_ffff) | (half as usize) << 32,
+        false => (*value & !0xffff_ffff) | half as usize,
+    };
+}
+
+/// A virtio device exposed to the guest through the virtio-mmio registers,
+/// with its interrupt routed to source `irq` of an interrupt controller.
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    mem: Arc<Mutex<GuestPhysMemorySet>>,
+    irq: u32,
+    irq_sink: Option<Arc<dyn IrqSink>>,
+    state: Mutex<TransportState>,
+}
+
+impl VirtioMmio {
+    /// Create the transport of `device`, whose virtqueues are in guest memory
+    /// `mem`.
+    pub fn new(
+        device: Box<dyn VirtioDevice>,
+        mem: Arc<Mutex<GuestPhysMemorySet>>,
+        irq: u32,
+        irq_sink: Option<Arc<dyn IrqSink>>,
+    ) -> Self {
+        let state = TransportState::new(device.num_queues());
+        Self {
+            device,
+            mem,
+            irq,
+            irq_sink,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Run `f` on the state, then drive the interrupt line without the lock
+    /// held.
+    fn with_state<R>(&self, f: impl FnOnce(&mut TransportState) -> R) -> R {
+        let (ret, line) = {
+            let mut state = self.state.lock();
+            let ret = f(&mut state);
+            let line = state.interrupt_status != 0;
+            let changed = state.line != line;
+            state.line = line;
+            (ret, changed.then_some(line))
+        };
+        if let (Some(line), Some(sink)) = (line, &self.irq_sink) {
+            sink.set_irq(self.irq, line);
+        }
+        ret
+    }
+
+    /// Give the device its virtqueues, if the driver is done setting them up.
+    /// A device error makes it need a reset.
+    fn run_device(
+        &self,
+        state: &mut TransportState,
+        f: impl FnOnce(&dyn VirtioDevice, &mut QueueContext) -> RvmResult,
+    ) {
+        if !state.is_active() {
+            return;
+        }
+        let mut mem = self.mem.lock();
+        let mut ctx = QueueContext {
+            queues: &mut state.queues,
+            mem: &mut mem,
+            driver_features: state.driver_features,
+            notify: false,
+        };
+        let result = f(self.device.as_ref(), &mut ctx);
+        if ctx.notify {
+            state.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        if result.is_err() {
+            state.status |= STATUS_DEVICE_NEEDS_RESET;
+            state.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+    }
+
+    /// Process the work of the device that does not come from the driver.
+    pub fn poll(&self) {
+        self.with_state(|state| self.run_device(state, |dev, ctx| dev.poll(ctx)))
+    }
+
+    fn reset(&self, state: &mut TransportState) {
+        *state = TransportState {
+            line: state.line,
+            ..TransportState::new(self.device.num_queues())
+        };
+        self.device.reset();
+    }
+
+    fn write_status(&self, state: &mut TransportState, status: u32) {
+        if status == 0 {
+            self.reset(state);
+            return;
+        }
+        let old = state.status;
+        state.status = status;
+        // Only virtio 1.x drivers are supported.
+        if status & STATUS_FEATURES_OK != 0 && state.driver_features & VIRTIO_F_VERSION_1 == 0 {
+            state.status &= !STATUS_FEATURES_OK;
+        }
+        if old & STATUS_DRIVER_OK == 0 && state.status & STATUS_DRIVER_OK != 0 {
+            self.run_device(state, |dev, ctx| dev.activate(ctx));
+        }
+    }
+
+    fn read_register(&self, state: &mut TransportState, offset: usize) -> u32 {
+        let device_features = self.device.device_features() | VIRTIO_F_VERSION_1;
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_id(),
+            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => match state.device_features_sel {
+                0 => device_features as u32,
+                1 => (device_features >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => match state.selected_queue() {
+                Some(_) => QUEUE_SIZE_MAX as u32,
+                None => 0,
+            },
+            VIRTIO_MMIO_QUEUE_READY => state.selected_queue().map_or(0, |q| q.ready as u32),
+            VIRTIO_MMIO_INTERRUPT_STATUS => state.interrupt_status,
+            VIRTIO_MMIO_STATUS => state.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, state: &mut TransportState, offset: usize, value: u32) {
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => state.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let offered = self.device.device_features() | VIRTIO_F_VERSION_1;
+                let shift = match state.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                let features = (value as u64) << shift & offered;
+                state.driver_features = state.driver_features & !(0xffff_ffff << shift) | features;
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => state.driver_features_sel = value,
+            VIRTIO_MMIO_QUEUE_SEL => state.queue_sel = value,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                // The size of a ready queue cannot change, and invalid sizes
+                // are ignored.
+                let size = u16::try_from(value)
+                    .ok()
+                    .filter(|&size| Virtqueue::is_valid_size(size));
+                match (state.selected_queue(), size) {
+                    (Some(queue), Some(size)) if !queue.ready => queue.size = size,
+                    _ => {}
+                }
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if let Some(queue) = state.selected_queue() {
+                    queue.ready = value & 1 != 0 && Virtqueue::is_valid_size(queue.size);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let index = value as usize;
+                self.run_device(state, |dev, ctx| dev.queue_notify(index, ctx));
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => state.interrupt_status &= !value,
+            VIRTIO_MMIO_STATUS => self.write_status(state, value),
+            VIRTIO_MMIO_QUEUE_DESC_LOW..=VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+                let high = offset % 8 == 4;
+                if let Some(queue) = state.selected_queue() {
+                    match offset & !4 {
+                        VIRTIO_MMIO_QUEUE_DESC_LOW => set_half(&mut queue.desc_addr, value, high),
+                        VIRTIO_MMIO_QUEUE_DRIVER_LOW => {
+                            set_half(&mut queue.driver_addr, value, high)
+                        }
+                        VIRTIO_MMIO_QUEUE_DEVICE_LOW => {
+                            set_half(&mut queue.device_addr, value, high)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MmioDevice for VirtioMmio {
+    fn read(&self, offset: usize, width: usize) -> RvmResult<u64> {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            return Ok(self.device.read_config(offset - VIRTIO_MMIO_CONFIG, width));
+        }
+        if width != 4 || offset % 4 != 0 {
+            return rvm_err!(InvalidParam, "virtio-mmio register access not 32-bit");
+        }
+        Ok(self.with_state(|state| self.read_register(state, offset)) as u64)
+    }
+
+    fn write(&self, offset: usize, width: usize, value: u64) -> RvmResult {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            self.device
+                .write_config(offset - VIRTIO_MMIO_CONFIG, width, value);
+            return Ok(());
+        }
+        if width != 4 || offset % 4 != 0 {
+            return rvm_err!(InvalidParam, "virtio-mmio register access not 32-bit");
+        }
+        self.with_state(|state| self.write_register(state, offset, value as u32));
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Paravirtual devices: the virtio-mmio transport, split virtqueues, and the
+//! interface of device backends.
+
+mod console;
+mod mmio;
+mod queue;
+
+pub use console::{ChannelPort, ConsolePort, HostConsolePort, VirtioConsole};
+pub use mmio::VirtioMmio;
+pub use queue::{DescChain, QueueContext, Segments, Virtqueue, QUEUE_SIZE_MAX};
+
+use crate::hv::RvmResult;
+
+/// The device conforms to virtio 1.x, always offered by the transport.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A virtio device, behind a transport that handles feature negotiation and
+/// the virtqueues.
+pub trait VirtioDevice: Send + Sync {
+    /// The virtio device ID, e.g. 3 for a console.
+    fn device_id(&self) -> u32;
+
+    /// Device-specific feature bits offered to the driver.
+    fn device_features(&self) -> u64;
+
+    fn num_queues(&self) -> usize;
+
+    /// Read `width` bytes at `offset` in the device configuration space.
+    fn read_config(&self, offset: usize, width: usize) -> u64;
+
+    /// Write `width` bytes at `offset` in the device configuration space.
+    /// Read-only fields ignore writes.
+    fn write_config(&self, _offset: usize, _width: usize, _value: u64) {}
+
+    /// The driver set `DRIVER_OK` with the features in `ctx`.
+    fn activate(&self, _ctx: &mut QueueContext) -> RvmResult {
+        Ok(())
+    }
+
+    /// Return to the initial state, on a reset by the driver.
+    fn reset(&self) {}
+
+    /// The driver made buffers available on queue `queue`.
+    fn queue_notify(&self, queue: usize, ctx: &mut QueueContext) -> RvmResult;
+
+    /// Process work that does not come from the driver, such as input.
+    fn poll(&self, _ctx: &mut QueueContext) -> RvmResult {
+        Ok(())
+    }
+}
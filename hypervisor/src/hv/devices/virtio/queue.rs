@@ -0,0 +1,254 @@
+//! Split virtqueues, as laid out by the driver in guest memory.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::hv::{GuestPhysAddr, RvmResult};
+use crate::mm::GuestPhysMemorySet;
+use crate::{rvm_err, rvm_err_type};
+
+/// Largest queue size offered to the driver.
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+/// Largest buffer the device gathers from a descriptor chain.
+const READ_SIZE_MAX: usize = 0x10000;
+
+const DESC_SIZE: usize = 16;
+const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+const VIRTQ_DESC_F_INDIRECT: u16 = 1 << 2;
+
+/// `avail.flags`: the driver does not want used buffer notifications.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+/// Offsets in the driver (available) area.
+const AVAIL_FLAGS: usize = 0;
+const AVAIL_IDX: usize = 2;
+const AVAIL_RING: usize = 4;
+/// Offsets in the device (used) area.
+const USED_IDX: usize = 2;
+const USED_RING: usize = 4;
+const USED_ELEM_SIZE: usize = 8;
+
+fn read_u16(mem: &GuestPhysMemorySet, gpa: GuestPhysAddr) -> RvmResult<u16> {
+    let mut buf = [0; 2];
+    mem.read_bytes(gpa, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+/// A buffer made of guest memory segments, as (address, length).
+pub type Segments = Vec<(GuestPhysAddr, usize)>;
+
+/// A descriptor chain made available by the driver.
+#[derive(Debug)]
+pub struct DescChain {
+    /// Index of the first descriptor, returned in the used ring.
+    pub head: u16,
+    /// Segments the device reads from.
+    pub readable: Segments,
+    /// Segments the device writes to.
+    pub writable: Segments,
+}
+
+impl DescChain {
+    /// Total length of the writable segments.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Gather the readable segments, at most `READ_SIZE_MAX` bytes.
+    pub fn read_all(&self, mem: &GuestPhysMemorySet) -> RvmResult<Vec<u8>> {
+        let total = self
+            .readable
+            .iter()
+            .try_fold(0usize, |total, &(_, len)| total.checked_add(len))
+            .filter(|&total| total <= READ_SIZE_MAX)
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "virtqueue buffer too large"))?;
+        let mut data = Vec::with_capacity(total);
+        for &(gpa, len) in &self.readable {
+            let start = data.len();
+            data.resize(start + len, 0);
+            mem.read_bytes(gpa, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Scatter `data` to the writable segments, and return how many bytes
+    /// fit.
+    pub fn write_all(&self, mem: &mut GuestPhysMemorySet, mut data: &[u8]) -> RvmResult<usize> {
+        let mut written = 0;
+        for &(gpa, len) in &self.writable {
+            if data.is_empty() {
+                break;
+            }
+            let n = len.min(data.len());
+            mem.write_bytes(gpa, &data[..n])?;
+            data = &data[n..];
+            written += n;
+        }
+        Ok(written)
+    }
+}
+
+/// The device side of a split virtqueue.
+#[derive(Debug, Default)]
+pub struct Virtqueue {
+    /// Number of descriptors, set by the driver.
+    pub size: u16,
+    pub ready: bool,
+    pub desc_addr: GuestPhysAddr,
+    pub driver_addr: GuestPhysAddr,
+    pub device_addr: GuestPhysAddr,
+    /// The next available ring entry to consume.
+    last_avail_idx: u16,
+    /// The next used ring entry to fill.
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    pub fn new() -> Self {
+        Self {
+            size: QUEUE_SIZE_MAX,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `size` is valid for a split virtqueue: a power of 2, up to
+    /// `QUEUE_SIZE_MAX`.
+    pub fn is_valid_size(size: u16) -> bool {
+        size.is_power_of_two() && size <= QUEUE_SIZE_MAX
+    }
+
+    /// Whether the driver made a descriptor chain available that has not
+    /// been popped yet. A queue of size 0 is always empty.
+    pub fn has_available(&self, mem: &GuestPhysMemorySet) -> RvmResult<bool> {
+        if !self.ready || self.size == 0 {
+            return Ok(false);
+        }
+        Ok(read_u16(mem, self.driver_addr + AVAIL_IDX)? != self.last_avail_idx)
+    }
+
+    /// Take the next descriptor chain made available by the driver.
+    pub fn pop(&mut self, mem: &GuestPhysMemorySet) -> RvmResult<Option<DescChain>> {
+        if !self.has_available(mem)? {
+            return Ok(None);
+        }
+        // Read the ring entry and descriptors after the index that
+        // published them.
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail_idx % self.size) as usize;
+        let head = read_u16(mem, self.driver_addr + AVAIL_RING + slot * 2)?;
+        let chain = self.walk_chain(mem, head)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        Ok(Some(chain))
+    }
+
+    fn walk_chain(&self, mem: &GuestPhysMemorySet, head: u16) -> RvmResult<DescChain> {
+        let mut chain = DescChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let mut index = head;
+        // A chain has at most `size` descriptors, which also stops loops.
+        for _ in 0..self.size {
+            if index >= self.size {
+                return rvm_err!(InvalidParam, "virtqueue descriptor index out of range");
+            }
+            let mut desc = [0; DESC_SIZE];
+            mem.read_bytes(self.desc_addr + index as usize * DESC_SIZE, &mut desc)?;
+            let addr = u64::from_le_bytes(desc[0..8].try_into().unwrap()) as GuestPhysAddr;
+            let len = u32::from_le_bytes(desc[8..12].try_into().unwrap()) as usize;
+            let flags = u16::from_le_bytes(desc[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(desc[14..16].try_into().unwrap());
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return rvm_err!(Unsupported, "indirect virtqueue descriptors");
+            }
+            match flags & VIRTQ_DESC_F_WRITE {
+                0 => chain.readable.push((addr, len)),
+                _ => chain.writable.push((addr, len)),
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = next;
+        }
+        rvm_err!(InvalidParam, "virtqueue descriptor chain too long")
+    }
+
+    /// Return `chain` to the driver with `len` bytes written to it. Returns
+    /// whether the driver wants to be notified.
+    pub fn push_used(
+        &mut self,
+        mem: &mut GuestPhysMemorySet,
+        chain: &DescChain,
+        len: u32,
+    ) -> RvmResult<bool> {
+        if self.size == 0 {
+            return rvm_err!(BadState, "virtqueue has no entries");
+        }
+        let slot = (self.used_idx % self.size) as usize;
+        let mut elem = [0; USED_ELEM_SIZE];
+        elem[0..4].copy_from_slice(&(chain.head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&len.to_le_bytes());
+        mem.write_bytes(self.device_addr + USED_RING + slot * USED_ELEM_SIZE, &elem)?;
+        // Publish the entry before the index.
+        fence(Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        mem.write_bytes(self.device_addr + USED_IDX, &self.used_idx.to_le_bytes())?;
+        fence(Ordering::SeqCst);
+        let flags = read_u16(mem, self.driver_addr + AVAIL_FLAGS)?;
+        Ok(flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
+
+/// Access to the virtqueues of a device and to guest memory, given to device
+/// backends.
+pub struct QueueContext<'a> {
+    pub(super) queues: &'a mut [Virtqueue],
+    pub(super) mem: &'a mut GuestPhysMemorySet,
+    pub(super) driver_features: u64,
+    /// Set when a used buffer notification is due.
+    pub(super) notify: bool,
+}
+
+impl QueueContext<'_> {
+    /// Features accepted by the driver.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn queue(&self, index: usize) -> RvmResult<&Virtqueue> {
+        self.queues
+            .get(index)
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "invalid virtqueue index"))
+    }
+
+    pub fn mem(&self) -> &GuestPhysMemorySet {
+        self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut GuestPhysMemorySet {
+        self.mem
+    }
+
+    /// Whether queue `index` has a descriptor chain to pop.
+    pub fn has_available(&self, index: usize) -> RvmResult<bool> {
+        self.queue(index)?.has_available(self.mem)
+    }
+
+    /// Take the next descriptor chain of queue `index`.
+    pub fn pop(&mut self, index: usize) -> RvmResult<Option<DescChain>> {
+        self.queue(index)?;
+        self.queues[index].pop(self.mem)
+    }
+
+    /// Return `chain` to queue `index` with `len` bytes written to it.
+    pub fn push_used(&mut self, index: usize, chain: &DescChain, len: u32) -> RvmResult {
+        self.queue(index)?;
+        if self.queues[index].push_used(self.mem, chain, len)? {
+            self.notify = true;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Generating the device tree that a guest finds in `a1` at entry.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::config::{GuestDeviceConfig, VmConfig};
+use super::{GuestPhysAddr, RvmResult};
+use crate::fdt::FdtBuilder;
+use crate::mm::GuestPhysMemorySet;
+use crate::riscv64::trap::INTERRUPT_S_EXT;
+use crate::rvm_err;
+
+/// Input clock of the emulated NS16550, the same as QEMU's.
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+/// Split a 64-bit value into two cells, for `#address-cells = <2>` and
+/// `#size-cells = <2>`.
+fn cells64(value: usize) -> [u32; 2] {
+    [(value >> 32) as u32, value as u32]
+}
+
+fn reg_cells(base: usize, size: usize) -> [u32; 4] {
+    let [base_hi, base_lo] = cells64(base);
+    let [size_hi, size_lo] = cells64(size);
+    [base_hi, base_lo, size_hi, size_lo]
+}
+
+/// Phandle of the interrupt controller of CPU `cpu_id`.
+const fn cpu_intc_phandle(cpu_id: usize) -> u32 {
+    cpu_id as u32 + 1
+}
+
+fn add_cpus(fdt: &mut FdtBuilder, config: &VmConfig) {
+    let isa = config.isa.isa_string();
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", config.timebase_frequency);
+    for cpu_id in 0..config.cpu_count {
+        fdt.begin_node(&format!("cpu@{:x}", cpu_id));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", cpu_id as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("mmu-type", "riscv,sv39");
+
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", cpu_intc_phandle(cpu_id));
+        fdt.end_node();
+
+        fdt.end_node();
+    }
+    fdt.end_node();
+}
+
+/// Add the node of a memory-mapped device, with device-specific properties
+/// added by `extra`.
+fn add_device(
+    fdt: &mut FdtBuilder,
+    name: &str,
+    compatible: &str,
+    dev: &GuestDeviceConfig,
+    plic_phandle: Option<u32>,
+    extra: impl FnOnce(&mut FdtBuilder),
+) {
+    fdt.begin_node(&format!("{}@{:x}", name, dev.base));
+    fdt.property_string("compatible", compatible);
+    fdt.property_cells("reg", &reg_cells(dev.base, dev.size));
+    if let Some(phandle) = plic_phandle {
+        fdt.property_u32("interrupts", dev.irq);
+        fdt.property_u32("interrupt-parent", phandle);
+    }
+    extra(fdt);
+    fdt.end_node();
+}
+
+fn add_soc(fdt: &mut FdtBuilder, config: &VmConfig) {
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    let plic_phandle = config.plic.map(|plic| {
+        let phandle = cpu_intc_phandle(config.cpu_count);
+        // One S-mode context per hart, routed to its supervisor external
+        // interrupt.
+        let contexts: Vec<u32> = (0..config.cpu_count)
+            .flat_map(|cpu_id| [cpu_intc_phandle(cpu_id), INTERRUPT_S_EXT as u32])
+            .collect();
+        fdt.begin_node(&format!("plic@{:x}", plic.base));
+        fdt.property_string_list("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("reg", &reg_cells(plic.base, plic.size));
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_cells("interrupts-extended", &contexts);
+        fdt.property_u32("riscv,ndev", plic.num_sources);
+        fdt.property_u32("phandle", phandle);
+        fdt.end_node();
+        phandle
+    });
+
+    if let Some(uart) = &config.uart {
+        add_device(fdt, "serial", "ns16550a", uart, plic_phandle, |fdt| {
+            fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        });
+    }
+    for dev in &config.virtio_mmio {
+        add_device(fdt, "virtio_mmio", "virtio,mmio", dev, plic_phandle, |_| {});
+    }
+    fdt.end_node();
+}
+
+/// Build the device tree blob that describes the VM of `config`.
+pub fn create_guest_dtb(config: &VmConfig) -> RvmResult<Vec<u8>> {
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "riscv-virtio,rvm");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", &config.bootargs);
+    if let Some(uart) = &config.uart {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart.base));
+    }
+    fdt.end_node();
+
+    for &(base, size) in &config.ram {
+        fdt.begin_node(&format!("memory@{:x}", base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &reg_cells(base, size));
+        fdt.end_node();
+    }
+
+    add_cpus(&mut fdt, config);
+    add_soc(&mut fdt, config);
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Build the device tree of `config` and copy it into guest memory at
+/// `dtb_gpa`, which must be 8-byte aligned. Returns `dtb_gpa` for the
+/// guest's `a1`.
+pub fn load_guest_dtb(
+    gpm: &mut GuestPhysMemorySet,
+    config: &VmConfig,
+    dtb_gpa: GuestPhysAddr,
+) -> RvmResult<GuestPhysAddr> {
+    if dtb_gpa % 8 != 0 {
+        return rvm_err!(InvalidParam, "guest DTB address not 8-byte aligned");
+    }
+    let dtb = create_guest_dtb(config)?;
+    gpm.write_bytes(dtb_gpa, &dtb)?;
+    info!(
+        "[RVM] loaded guest DTB at [{:#x}, {:#x})",
+        dtb_gpa,
+        dtb_gpa + dtb.len()
+    );
+    Ok(dtb_gpa)
+}
@@ -0,0 +1,81 @@
+//! The host console, shared by the consoles that guests see.
+//!
+//! The SBI console, the emulated UART and the virtio console all end up on
+//! the host UART. The output of each of them is printed, but the input only
+//! goes to one of them, the active one, so that what is typed on the host is
+//! not split between consoles that poll for it. The guest reads where it
+//! writes, so the active console is the one that last wrote output.
+
+use alloc::collections::VecDeque;
+
+use spin::Mutex;
+
+use crate::riscv64::uart;
+
+/// Host input kept for the active console, beyond which it is dropped.
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// A guest console backed by the host one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestConsole {
+    /// The SBI debug console and the legacy console calls.
+    Sbi,
+    /// The emulated 16550 UART.
+    Uart,
+    /// The console port of the virtio console, `hvc0` in Linux.
+    Virtio,
+}
+
+struct HostConsole {
+    active: GuestConsole,
+    input: VecDeque<u8>,
+}
+
+impl HostConsole {
+    /// Move pending input of the host UART to the buffer.
+    fn poll(&mut self) {
+        while self.input.len() < INPUT_BUFFER_SIZE {
+            match uart::console_getchar() {
+                Some(c) => self.input.push_back(c),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Guests boot with the SBI console.
+static HOST_CONSOLE: Mutex<HostConsole> = Mutex::new(HostConsole {
+    active: GuestConsole::Sbi,
+    input: VecDeque::new(),
+});
+
+/// Print `data`, the output of guest console `console`, which becomes the
+/// active one.
+pub fn write(console: GuestConsole, data: &[u8]) {
+    HOST_CONSOLE.lock().active = console;
+    crate::logging::write_bytes(data);
+}
+
+/// Fill `buf` with host input for guest console `console`, returns how many
+/// bytes were read. Consoles other than the active one get nothing.
+pub fn read(console: GuestConsole, buf: &mut [u8]) -> usize {
+    let mut host = HOST_CONSOLE.lock();
+    if host.active != console {
+        return 0;
+    }
+    host.poll();
+    let len = buf.len().min(host.input.len());
+    for (dst, src) in buf.iter_mut().zip(host.input.drain(..len)) {
+        *dst = src;
+    }
+    len
+}
+
+/// Read one byte of host input for guest console `console`, if any.
+pub fn read_byte(console: GuestConsole) -> Option<u8> {
+    let mut c = 0;
+    match read(console, core::slice::from_mut(&mut c)) {
+        0 => None,
+        _ => Some(c),
+    }
+}
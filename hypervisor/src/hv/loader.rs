@@ -0,0 +1,429 @@
+//! Loading guest kernel images into guest physical memory.
+//!
+//! Supported formats:
+//! - Raw flat binaries, loaded at a given address and entered at its start.
+//! - ELF64 RISC-V executables, whose `PT_LOAD` segments are loaded at their
+//!   physical addresses.
+//! - Linux RISC-V `Image` files, loaded at `text_offset` from the start of
+//!   guest RAM as described in `Documentation/riscv/boot-image-header.rst`.
+
+use alloc::vec::Vec;
+
+use super::{GuestPhysAddr, RvmResult};
+use crate::mm::GuestPhysMemorySet;
+use crate::{rvm_err, rvm_err_type};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const ELF64_EHDR_SIZE: usize = 64;
+const ELF64_PHDR_SIZE: usize = 56;
+
+const LINUX_IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+const LINUX_IMAGE_MAGIC2_OFFSET: usize = 56;
+const LINUX_IMAGE_HEADER_SIZE: usize = 64;
+/// RV64 kernels must be placed at a 2M-aligned address.
+const LINUX_IMAGE_ALIGN: usize = 0x20_0000;
+
+/// The format of a guest kernel image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    Elf,
+    LinuxImage,
+}
+
+impl ImageFormat {
+    /// Guess the format of `image` from its magic numbers, falling back to a
+    /// raw binary.
+    pub fn detect(image: &[u8]) -> Self {
+        if image.starts_with(ELF_MAGIC) {
+            Self::Elf
+        } else if image.get(LINUX_IMAGE_MAGIC2_OFFSET..LINUX_IMAGE_MAGIC2_OFFSET + 4)
+            == Some(LINUX_IMAGE_MAGIC2)
+        {
+            Self::LinuxImage
+        } else {
+            Self::Raw
+        }
+    }
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> RvmResult<[u8; N]> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|b| b.try_into().unwrap())
+        .ok_or_else(|| rvm_err_type!(InvalidParam, "guest image truncated"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> RvmResult<u16> {
+    read_bytes(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> RvmResult<u32> {
+    read_bytes(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> RvmResult<usize> {
+    read_bytes(data, offset).map(|b| u64::from_le_bytes(b) as usize)
+}
+
+/// A `PT_LOAD` segment: `data` is copied to `paddr`, and the rest of its
+/// `memsz` bytes is zeroed.
+#[derive(Debug, PartialEq, Eq)]
+struct ElfSegment<'a> {
+    paddr: GuestPhysAddr,
+    data: &'a [u8],
+    memsz: usize,
+}
+
+/// The loadable parts of an ELF file, and its entry as a physical address.
+#[derive(Debug)]
+struct ElfImage<'a> {
+    entry: GuestPhysAddr,
+    segments: Vec<ElfSegment<'a>>,
+}
+
+/// Check the headers of an ELF64 RISC-V executable, and collect its `PT_LOAD`
+/// segments.
+fn parse_elf(image: &[u8]) -> RvmResult<ElfImage<'_>> {
+    if image.len() < ELF64_EHDR_SIZE || !image.starts_with(ELF_MAGIC) {
+        return rvm_err!(InvalidParam, "not an ELF file");
+    }
+    if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB {
+        return rvm_err!(Unsupported, "only little-endian ELF64 is supported");
+    }
+    if read_u16(image, 18)? != EM_RISCV {
+        return rvm_err!(Unsupported, "ELF machine is not RISC-V");
+    }
+
+    let entry = read_u64(image, 24)?;
+    let phoff = read_u64(image, 32)?;
+    let phentsize = read_u16(image, 54)? as usize;
+    let phnum = read_u16(image, 56)? as usize;
+    if phentsize < ELF64_PHDR_SIZE {
+        return rvm_err!(InvalidParam, "invalid ELF program header size");
+    }
+
+    let mut entry_gpa = None;
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|off| off.checked_add(phoff))
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "ELF program header out of file"))?;
+        if read_u32(image, ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = read_u64(image, ph + 8)?;
+        let vaddr = read_u64(image, ph + 16)?;
+        let paddr = read_u64(image, ph + 24)?;
+        let filesz = read_u64(image, ph + 32)?;
+        let memsz = read_u64(image, ph + 40)?;
+        if filesz > memsz {
+            return rvm_err!(InvalidParam, "ELF segment file size exceeds memory size");
+        }
+        let (Some(vend), Some(_)) = (vaddr.checked_add(memsz), paddr.checked_add(memsz)) else {
+            return rvm_err!(InvalidParam, "ELF segment wraps around the address space");
+        };
+        let data = offset
+            .checked_add(filesz)
+            .and_then(|end| image.get(offset..end))
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "ELF segment out of file"))?;
+
+        if (vaddr..vend).contains(&entry) {
+            entry_gpa = Some(entry - vaddr + paddr);
+        }
+        segments.push(ElfSegment { paddr, data, memsz });
+    }
+
+    let entry =
+        entry_gpa.ok_or_else(|| rvm_err_type!(InvalidParam, "ELF entry not in any segment"))?;
+    Ok(ElfImage { entry, segments })
+}
+
+/// Check the header of a Linux RISC-V `Image` to be placed in RAM at
+/// `ram_base`. Returns the load address and the size it takes in memory.
+fn parse_linux_image(image: &[u8], ram_base: GuestPhysAddr) -> RvmResult<(GuestPhysAddr, usize)> {
+    if image.len() < LINUX_IMAGE_HEADER_SIZE
+        || &image[LINUX_IMAGE_MAGIC2_OFFSET..LINUX_IMAGE_MAGIC2_OFFSET + 4] != LINUX_IMAGE_MAGIC2
+    {
+        return rvm_err!(InvalidParam, "not a Linux RISC-V Image");
+    }
+    let text_offset = read_u64(image, 8)?;
+    // Images before Linux 4.x did not set `image_size`.
+    let image_size = read_u64(image, 16)?.max(image.len());
+
+    let load_gpa = ram_base
+        .checked_add(text_offset)
+        .filter(|gpa| gpa.checked_add(image_size).is_some())
+        .ok_or_else(|| rvm_err_type!(InvalidParam, "Linux Image text offset out of range"))?;
+    if load_gpa % LINUX_IMAGE_ALIGN != 0 {
+        return rvm_err!(InvalidParam, "Linux Image load address not 2M aligned");
+    }
+    Ok((load_gpa, image_size))
+}
+
+/// Load `image` into `gpm`, detecting its format. `load_gpa` is where a raw
+/// binary is loaded, or the start of guest RAM for a Linux `Image`; it is
+/// unused for ELF files. Returns the entry GPA.
+pub fn load_image(
+    gpm: &mut GuestPhysMemorySet,
+    image: &[u8],
+    load_gpa: GuestPhysAddr,
+) -> RvmResult<GuestPhysAddr> {
+    match ImageFormat::detect(image) {
+        ImageFormat::Raw => load_raw(gpm, image, load_gpa),
+        ImageFormat::Elf => load_elf(gpm, image),
+        ImageFormat::LinuxImage => load_linux_image(gpm, image, load_gpa),
+    }
+}
+
+/// Copy a raw flat binary to `load_gpa`, which is also the entry.
+pub fn load_raw(
+    gpm: &mut GuestPhysMemorySet,
+    image: &[u8],
+    load_gpa: GuestPhysAddr,
+) -> RvmResult<GuestPhysAddr> {
+    gpm.write_bytes(load_gpa, image)?;
+    info!(
+        "[RVM] loaded raw image at [{:#x}, {:#x})",
+        load_gpa,
+        load_gpa + image.len()
+    );
+    Ok(load_gpa)
+}
+
+/// Load the `PT_LOAD` segments of an ELF64 RISC-V executable at their
+/// physical addresses, and zero the rest of each segment in memory.
+///
+/// The entry is translated to a physical address through the segment that
+/// contains it, so kernels linked at a virtual address can start with paging
+/// off.
+pub fn load_elf(gpm: &mut GuestPhysMemorySet, image: &[u8]) -> RvmResult<GuestPhysAddr> {
+    let elf = parse_elf(image)?;
+    for seg in &elf.segments {
+        gpm.write_bytes(seg.paddr, seg.data)?;
+        if seg.memsz > seg.data.len() {
+            gpm.zero_bytes(seg.paddr + seg.data.len(), seg.memsz - seg.data.len())?;
+        }
+        debug!(
+            "[RVM] loaded ELF segment at [{:#x}, {:#x})",
+            seg.paddr,
+            seg.paddr + seg.memsz
+        );
+    }
+    info!("[RVM] loaded ELF image, entry {:#x}", elf.entry);
+    Ok(elf.entry)
+}
+
+/// Load a Linux RISC-V `Image` at `text_offset` from `ram_base`, which is
+/// also the entry. The memory up to `image_size` is zeroed.
+pub fn load_linux_image(
+    gpm: &mut GuestPhysMemorySet,
+    image: &[u8],
+    ram_base: GuestPhysAddr,
+) -> RvmResult<GuestPhysAddr> {
+    let (load_gpa, image_size) = parse_linux_image(image, ram_base)?;
+    gpm.write_bytes(load_gpa, image)?;
+    if image_size > image.len() {
+        gpm.zero_bytes(load_gpa + image.len(), image_size - image.len())?;
+    }
+    info!(
+        "[RVM] loaded Linux Image at [{:#x}, {:#x})",
+        load_gpa,
+        load_gpa + image_size
+    );
+    Ok(load_gpa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hv::RvmError;
+    use alloc::vec;
+
+    const PT_NOTE: u32 = 4;
+
+    /// A program header: type, file offset, vaddr, paddr, filesz, memsz.
+    type Phdr = (u32, u64, u64, u64, u64, u64);
+
+    /// Build an ELF64 RISC-V file with `phdrs` right after the ELF header,
+    /// followed by `payload`.
+    fn elf(entry: u64, phdrs: &[Phdr], payload: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; ELF64_EHDR_SIZE];
+        image[..4].copy_from_slice(ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        image[24..32].copy_from_slice(&entry.to_le_bytes());
+        image[32..40].copy_from_slice(&(ELF64_EHDR_SIZE as u64).to_le_bytes());
+        image[54..56].copy_from_slice(&(ELF64_PHDR_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for &(p_type, offset, vaddr, paddr, filesz, memsz) in phdrs {
+            let mut ph = vec![0; ELF64_PHDR_SIZE];
+            ph[0..4].copy_from_slice(&p_type.to_le_bytes());
+            ph[8..16].copy_from_slice(&offset.to_le_bytes());
+            ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            ph[24..32].copy_from_slice(&paddr.to_le_bytes());
+            ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+            ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+            image.extend_from_slice(&ph);
+        }
+        image.extend_from_slice(payload);
+        image
+    }
+
+    /// File offset of the payload of an `elf` with `n` program headers.
+    const fn payload_offset(n: usize) -> u64 {
+        (ELF64_EHDR_SIZE + n * ELF64_PHDR_SIZE) as u64
+    }
+
+    /// Build a Linux `Image` header with `text_offset` and `image_size`,
+    /// padded to `len` bytes.
+    fn linux_image(text_offset: u64, image_size: u64, len: usize) -> Vec<u8> {
+        let mut image = vec![0; len.max(LINUX_IMAGE_HEADER_SIZE)];
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[LINUX_IMAGE_MAGIC2_OFFSET..LINUX_IMAGE_MAGIC2_OFFSET + 4]
+            .copy_from_slice(LINUX_IMAGE_MAGIC2);
+        image
+    }
+
+    #[test]
+    fn detect_format() {
+        let cases: &[(&[u8], ImageFormat)] = &[
+            (&elf(0, &[], &[]), ImageFormat::Elf),
+            (&linux_image(0, 0, 0), ImageFormat::LinuxImage),
+            (&[0x13, 0, 0, 0], ImageFormat::Raw),
+            (&[], ImageFormat::Raw),
+            // The Linux magic must be at its offset, not at the start.
+            (LINUX_IMAGE_MAGIC2, ImageFormat::Raw),
+        ];
+        for (image, format) in cases {
+            assert_eq!(ImageFormat::detect(image), *format, "{:x?}", image);
+        }
+    }
+
+    #[test]
+    fn elf_segments() {
+        const VBASE: u64 = 0xffff_ffff_8000_0000;
+        const PBASE: u64 = 0x8020_0000;
+        let off = payload_offset(3);
+        let image = elf(
+            VBASE + 0x10,
+            &[
+                (PT_NOTE, off, 0, 0, 4, 4),
+                (PT_LOAD, off, VBASE, PBASE, 4, 0x1000),
+                (PT_LOAD, off + 4, VBASE + 0x1000, PBASE + 0x1000, 2, 2),
+            ],
+            &[1, 2, 3, 4, 5, 6],
+        );
+        let parsed = parse_elf(&image).unwrap();
+        assert_eq!(parsed.entry, PBASE as usize + 0x10);
+        assert_eq!(
+            parsed.segments,
+            [
+                ElfSegment {
+                    paddr: PBASE as usize,
+                    data: &[1, 2, 3, 4],
+                    memsz: 0x1000,
+                },
+                ElfSegment {
+                    paddr: PBASE as usize + 0x1000,
+                    data: &[5, 6],
+                    memsz: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn elf_invalid() {
+        let off = payload_offset(1);
+        let load =
+            |offset, vaddr, filesz, memsz| (PT_LOAD, offset, vaddr, 0x8000_0000, filesz, memsz);
+        let cases: &[(&str, Vec<u8>)] = &[
+            (
+                "truncated header",
+                elf(0, &[], &[])[..ELF64_EHDR_SIZE - 1].to_vec(),
+            ),
+            (
+                "truncated program headers",
+                elf(0, &[load(off, 0, 0, 0)], &[])[..off as usize - 1].to_vec(),
+            ),
+            ("filesz > memsz", elf(0, &[load(off, 0, 2, 1)], &[0; 2])),
+            (
+                "segment out of file",
+                elf(0, &[load(off, 0, 2, 2)], &[0; 1]),
+            ),
+            (
+                "offset + filesz overflows",
+                elf(0, &[load(u64::MAX, 0, 2, 2)], &[]),
+            ),
+            (
+                "vaddr + memsz overflows",
+                elf(0, &[load(off, u64::MAX, 0, 2)], &[]),
+            ),
+            (
+                "entry not in a segment",
+                elf(0x100, &[load(off, 0, 0, 0x100)], &[]),
+            ),
+            ("no PT_LOAD", elf(0, &[], &[])),
+        ];
+        for (what, image) in cases {
+            assert!(parse_elf(image).is_err(), "{}", what);
+        }
+
+        let mut image = elf(0, &[load(off, 0, 0, 1)], &[]);
+        image[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse_elf(&image).is_err(), "phoff + phentsize overflows");
+
+        let mut image = elf(0, &[load(off, 0, 0, 1)], &[]);
+        image[4] = 1;
+        assert!(
+            matches!(parse_elf(&image), Err(RvmError::Unsupported)),
+            "ELF32"
+        );
+
+        let mut image = elf(0, &[load(off, 0, 0, 1)], &[]);
+        image[18] = 62;
+        assert!(
+            matches!(parse_elf(&image), Err(RvmError::Unsupported)),
+            "x86-64"
+        );
+    }
+
+    #[test]
+    fn linux_image_header() {
+        const RAM_BASE: usize = 0x8000_0000;
+        let image = linux_image(0x20_0000, 0x10000, 0x100);
+        assert_eq!(
+            parse_linux_image(&image, RAM_BASE).unwrap(),
+            (RAM_BASE + 0x20_0000, 0x10000)
+        );
+        // An unset `image_size` is the file size.
+        let image = linux_image(0, 0, 0x100);
+        assert_eq!(
+            parse_linux_image(&image, RAM_BASE).unwrap(),
+            (RAM_BASE, 0x100)
+        );
+
+        let cases: &[(&str, Vec<u8>)] = &[
+            ("not 2M aligned", linux_image(0x1000, 0, 0)),
+            ("text_offset overflows", linux_image(u64::MAX, 0, 0)),
+            ("image_size overflows", linux_image(0, u64::MAX, 0)),
+            (
+                "truncated",
+                linux_image(0, 0, 0)[..LINUX_IMAGE_HEADER_SIZE - 1].to_vec(),
+            ),
+            ("no magic", vec![0; LINUX_IMAGE_HEADER_SIZE]),
+        ];
+        for (what, image) in cases {
+            assert!(parse_linux_image(image, RAM_BASE).is_err(), "{}", what);
+        }
+    }
+}
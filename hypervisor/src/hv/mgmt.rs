@@ -0,0 +1,82 @@
+//! The management channel, a virtio console port on which an agent in the
+//! guest sends commands to the hypervisor.
+//!
+//! Commands are lines of text, each answered with one line:
+//!
+//! - `ping`: answers `pong`.
+//! - `status`: answers the VM ID and its number of vCPUs.
+//! - `shutdown`, `reboot`: stop the VM as an SBI system reset would, and
+//!   answer `ok`.
+//!
+//! Anything else is answered with `error: unknown command`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::devices::virtio::ChannelPort;
+use super::vm::{RvmVm, VmState};
+
+/// Longest command, longer lines are discarded.
+const MAX_LINE_LEN: usize = 64;
+
+/// The hypervisor end of the management port of a VM.
+pub struct MgmtChannel {
+    port: Arc<ChannelPort>,
+    /// The command being received, up to its newline.
+    line: Vec<u8>,
+    /// The current line is too long and is skipped up to its newline.
+    overflow: bool,
+}
+
+impl MgmtChannel {
+    pub fn new(port: Arc<ChannelPort>) -> Self {
+        Self {
+            port,
+            line: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    /// Handle the commands the guest sent since the last poll.
+    pub fn poll(&mut self, vm: &RvmVm) {
+        for c in self.port.recv() {
+            match c {
+                b'\n' => {
+                    let reply = match self.overflow {
+                        true => String::from("error: command too long"),
+                        false => handle_command(vm, &self.line),
+                    };
+                    self.port.send(reply.as_bytes());
+                    self.port.send(b"\n");
+                    self.line.clear();
+                    self.overflow = false;
+                }
+                _ if self.line.len() < MAX_LINE_LEN => self.line.push(c),
+                _ => self.overflow = true,
+            }
+        }
+    }
+}
+
+fn handle_command(vm: &RvmVm, line: &[u8]) -> String {
+    let command = core::str::from_utf8(line).unwrap_or("").trim();
+    match command {
+        "ping" => String::from("pong"),
+        "status" => format!("vm {} vcpus {}", vm.id(), vm.vcpu_count()),
+        "shutdown" | "reboot" => {
+            info!(
+                "[RVM] VM {}: {} requested on the management channel",
+                vm.id(),
+                command
+            );
+            vm.stop(match command {
+                "shutdown" => VmState::Shutdown,
+                _ => VmState::Reset,
+            });
+            String::from("ok")
+        }
+        _ => String::from("error: unknown command"),
+    }
+}
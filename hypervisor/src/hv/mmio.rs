@@ -0,0 +1,102 @@
+//! Trap-and-emulate of guest loads, stores and AMOs to emulated devices.
+
+use super::vm::RvmVm;
+use super::{GprIndex, GuestAccessType, GuestPhysAddr, RvmResult, RvmVcpu};
+use crate::riscv64::decode::{self, AmoOp, MemInsn, MemOp};
+use crate::{rvm_err, rvm_err_type};
+
+/// Keep the low `width` bytes of `value`.
+fn truncate(value: u64, width: usize) -> u64 {
+    value & (u64::MAX >> (64 - width * 8))
+}
+
+/// Sign-extend the low `width` bytes of `value`.
+fn sign_extend(value: u64, width: usize) -> u64 {
+    let shift = 64 - width * 8;
+    ((value << shift) as i64 >> shift) as u64
+}
+
+/// The value stored by AMO `op` of `width` bytes.
+fn amo_result(op: AmoOp, old: u64, src: u64, width: usize) -> u64 {
+    let (old_s, src_s) = (
+        sign_extend(old, width) as i64,
+        sign_extend(src, width) as i64,
+    );
+    let (old_u, src_u) = (truncate(old, width), truncate(src, width));
+    let result = match op {
+        AmoOp::Swap => src,
+        AmoOp::Add => old.wrapping_add(src),
+        AmoOp::Xor => old ^ src,
+        AmoOp::And => old & src,
+        AmoOp::Or => old | src,
+        AmoOp::Min => old_s.min(src_s) as u64,
+        AmoOp::Max => old_s.max(src_s) as u64,
+        AmoOp::MinU => old_u.min(src_u),
+        AmoOp::MaxU => old_u.max(src_u),
+        AmoOp::LoadReserved | AmoOp::StoreConditional => unreachable!(),
+    };
+    truncate(result, width)
+}
+
+fn reg(raw: u32) -> GprIndex {
+    GprIndex::from_raw(raw).unwrap()
+}
+
+/// Decode the faulting instruction from the transformed one in `htinst`, or
+/// fetch it from the guest at `sepc` if the hardware did not provide one.
+fn fetch_insn(vcpu: &RvmVcpu, htinst: usize) -> RvmResult<MemInsn> {
+    let insn = match htinst {
+        0 => decode::decode(vcpu.fetch_guest_insn(vcpu.exit_info().sepc)?),
+        _ => decode::decode_transformed(htinst as u32),
+    };
+    insn.ok_or_else(|| rvm_err_type!(Unsupported, "MMIO instruction not decoded"))
+}
+
+/// Emulate the load, store or AMO of `vcpu` that faulted at `gpa`, with the
+/// device of `vm` that claims it, and skip the instruction.
+pub fn handle_mmio_fault(
+    vm: &RvmVm,
+    vcpu: &mut RvmVcpu,
+    access: GuestAccessType,
+    gpa: GuestPhysAddr,
+    htinst: usize,
+) -> RvmResult {
+    let bus = vm.mmio_bus();
+    if bus.find(gpa).is_none() {
+        return rvm_err!(InvalidParam, format_args!("no MMIO device at {:#x}", gpa));
+    }
+    let insn = fetch_insn(vcpu, htinst)?;
+    if insn.is_write() != (access == GuestAccessType::Store) {
+        return rvm_err!(InvalidParam, "MMIO instruction does not match the fault");
+    }
+
+    let width = insn.width;
+    match insn.op {
+        MemOp::Load { rd, signed } => {
+            let value = bus.read(gpa, width)?;
+            let value = match signed {
+                true => sign_extend(value, width),
+                false => truncate(value, width),
+            };
+            vcpu.regs_mut().set_reg(reg(rd), value as usize);
+        }
+        MemOp::Store { rs2 } => {
+            let value = vcpu.regs().reg(reg(rs2)) as u64;
+            bus.write(gpa, width, truncate(value, width))?;
+        }
+        MemOp::Amo {
+            op: AmoOp::LoadReserved | AmoOp::StoreConditional,
+            ..
+        } => return rvm_err!(Unsupported, "LR/SC on MMIO"),
+        MemOp::Amo { op, rd, rs2 } => {
+            // Not atomic with other vCPUs: devices see a read then a write.
+            let old = bus.read(gpa, width)?;
+            let src = vcpu.regs().reg(reg(rs2)) as u64;
+            bus.write(gpa, width, amo_result(op, old, src, width))?;
+            vcpu.regs_mut()
+                .set_reg(reg(rd), sign_extend(old, width) as usize);
+        }
+    }
+    vcpu.advance_pc(insn.len);
+    Ok(())
+}
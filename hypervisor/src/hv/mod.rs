@@ -1,12 +1,65 @@
+pub mod config;
+pub mod devices;
+pub mod dtb;
 pub mod error;
+pub mod host_console;
+pub mod loader;
+pub mod mgmt;
+pub mod mmio;
+pub mod sbi;
+pub mod vm;
 
-pub use error::{RvmError, RvmResult};
 pub use crate::mm::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr};
-pub use crate::riscv64::hext::{HextPerCpuState, has_hardware_support};
+pub use crate::riscv64::hext::{has_hardware_support, host_isa, HextPerCpuState, MachineISAFlags};
+pub use crate::riscv64::hext::{GStageMode, GStagePageTable, Sv39x4, Sv48x4};
+pub use crate::riscv64::hext::{
+    GeneralPurposeRegisters, GprIndex, GuestAccessType, GuestVsCsrs, HextExitInfo, HextVcpu,
+    RvmExitReason,
+};
+pub use error::{RvmError, RvmResult};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::{vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use self::config::{GuestDeviceConfig, GuestPlicConfig, VmConfig};
+use self::devices::virtio::{ChannelPort, HostConsolePort, VirtioConsole};
+use self::sbi::SbiAction;
+use self::vm::{RvmVm, VmState};
+use crate::config::MAX_CPUS;
+use crate::mm::{address::align_up, GuestMemoryRegion, GuestPhysMemorySet, MemFlags, PAGE_SIZE};
+use crate::riscv64::{instructions, timer};
+use crate::rvm_err;
+use crate::timer::{Duration, Instant};
+
+const GUEST_PHYS_MEMORY_BASE: GuestPhysAddr = 0x8000_0000;
+const GUEST_PHYS_MEMORY_SIZE: usize = 0x100_0000; // 16M
+/// Where a raw guest image is loaded, also the RAM base for a Linux `Image`.
+const GUEST_IMAGE_GPA: GuestPhysAddr = GUEST_PHYS_MEMORY_BASE;
+/// The guest device tree is placed in the last 2M of guest RAM.
+const GUEST_DTB_GPA: GuestPhysAddr = GUEST_PHYS_MEMORY_BASE + GUEST_PHYS_MEMORY_SIZE - 0x20_0000;
+const GUEST_PLIC_BASE: GuestPhysAddr = 0x0c00_0000;
+const GUEST_PLIC_NUM_SOURCES: u32 = 32;
+const GUEST_UART_BASE: GuestPhysAddr = 0x1000_0000;
+const GUEST_UART_IRQ: u32 = 10;
+const GUEST_VIRTIO_CONSOLE_BASE: GuestPhysAddr = 0x1000_1000;
+const GUEST_VIRTIO_CONSOLE_IRQ: u32 = 1;
+/// Name of the virtio console port for the hypervisor management channel.
+const MGMT_PORT_NAME: &str = "rvm.mgmt";
+/// How often vCPU 0 leaves the guest to poll the host console.
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const GUEST_VMID: usize = 1;
+
+/// The G-stage page table used by guests.
+pub type NestedPageTable = GStagePageTable<Sv39x4>;
 
 /// Host per-CPU states to run the guest.
 pub struct RvmPerCpu {
-    _cpu_id: usize,
+    cpu_id: usize,
     hext: HextPerCpuState,
 }
 
@@ -14,11 +67,16 @@ impl RvmPerCpu {
     /// Create an uninitialized instance.
     pub fn new(cpu_id: usize) -> Self {
         Self {
-            _cpu_id: cpu_id,
+            cpu_id,
             hext: HextPerCpuState::new(),
         }
     }
 
+    /// ID of the CPU that owns this instance.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
     /// Whether the current CPU has hardware virtualization enabled.
     pub fn is_enabled(&self) -> bool {
         self.hext.is_enabled()
@@ -29,10 +87,27 @@ impl RvmPerCpu {
         self.hext.hardware_enable()
     }
 
+    /// Number of VMID bits supported by the current CPU, valid once enabled.
+    pub fn vmid_bits(&self) -> usize {
+        self.hext.vmid_bits()
+    }
+
     /// Disable hardware virtualization on the current CPU.
     pub fn hardware_disable(&mut self) -> RvmResult {
         self.hext.hardware_disable()
     }
+
+    /// Create a [`RvmVcpu`] that starts at `entry`, with guest physical
+    /// memory translated by `hgatp`. It can only run on the current CPU.
+    pub fn create_vcpu(&self, entry: GuestPhysAddr, hgatp: u64) -> RvmResult<RvmVcpu> {
+        if !self.is_enabled() {
+            return rvm_err!(BadState, "virtualization is not enabled");
+        }
+        if self.cpu_id != crate::percpu::this_cpu_id() {
+            return rvm_err!(BadState, "RvmPerCpu used on another CPU");
+        }
+        RvmVcpu::new(entry, hgatp)
+    }
 }
 
 impl Drop for RvmPerCpu {
@@ -43,11 +118,390 @@ impl Drop for RvmPerCpu {
     }
 }
 
+/// A virtual CPU within a guest.
+pub struct RvmVcpu {
+    hext: HextVcpu,
+}
+
+impl RvmVcpu {
+    fn new(entry: GuestPhysAddr, hgatp: u64) -> RvmResult<Self> {
+        Ok(Self {
+            hext: HextVcpu::new(entry, hgatp),
+        })
+    }
+
+    /// Run the guest until it traps back to the hypervisor.
+    pub fn run(&mut self) -> RvmExitReason {
+        self.hext.run()
+    }
+
+    /// Trap CSRs of the last VM exit.
+    pub fn exit_info(&self) -> HextExitInfo {
+        self.hext.exit_info()
+    }
+
+    /// Guest general-purpose registers.
+    pub fn regs(&self) -> &GeneralPurposeRegisters {
+        self.hext.regs()
+    }
+
+    /// Mutable reference of guest general-purpose registers.
+    pub fn regs_mut(&mut self) -> &mut GeneralPurposeRegisters {
+        self.hext.regs_mut()
+    }
+
+    /// Guest VS-level CSRs.
+    pub fn vs_csrs(&self) -> &GuestVsCsrs {
+        self.hext.vs_csrs()
+    }
+
+    /// Mutable reference of guest VS-level CSRs.
+    pub fn vs_csrs_mut(&mut self) -> &mut GuestVsCsrs {
+        self.hext.vs_csrs_mut()
+    }
+
+    /// Advance guest `sepc` by `instr_len` bytes.
+    pub fn advance_pc(&mut self, instr_len: usize) {
+        self.hext.advance_pc(instr_len)
+    }
+
+    /// Make VS-level interrupt `irq` (`INTERRUPT_VS_*`) pending.
+    pub fn set_irq_pending(&mut self, irq: usize) -> RvmResult {
+        self.hext.set_irq_pending(irq)
+    }
+
+    /// Withdraw the pending VS-level interrupt `irq`.
+    pub fn clear_irq_pending(&mut self, irq: usize) -> RvmResult {
+        self.hext.clear_irq_pending(irq)
+    }
+
+    /// The current `time` as seen by the guest.
+    pub fn guest_time(&self) -> u64 {
+        self.hext.vtimer().guest_time()
+    }
+
+    /// Offset the guest `time` from the host one by `time_delta`.
+    pub fn set_time_delta(&mut self, time_delta: u64) {
+        self.hext.set_time_delta(time_delta)
+    }
+
+    /// Raise the virtual timer interrupt once the guest `time` reaches
+    /// `deadline`, and withdraw the pending one, like `sbi_set_timer`.
+    pub fn set_timer(&mut self, deadline: u64) {
+        self.hext.set_timer(deadline)
+    }
+
+    /// Read guest virtual memory at `gva` into `buf`.
+    pub fn read_guest_virt(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> RvmResult {
+        self.hext.read_guest_virt(gva, buf)
+    }
+
+    /// Write `data` to guest virtual memory at `gva`.
+    pub fn write_guest_virt(&self, gva: GuestVirtAddr, data: &[u8]) -> RvmResult {
+        self.hext.write_guest_virt(gva, data)
+    }
+
+    /// Fetch the guest instruction at `gva`.
+    pub fn fetch_guest_insn(&self, gva: GuestVirtAddr) -> RvmResult<u32> {
+        self.hext.fetch_guest_insn(gva)
+    }
+}
+
+// The test guest: print the digits 0 to 4 with the SBI debug console, then
+// shut down. It is only copied to guest memory, so it is kept with the
+// read-only data, between `test_guest` and `test_guest_end`.
+core::arch::global_asm!(
+    r#"
+.pushsection .rodata.test_guest, "a"
+.balign 4
+.globl test_guest, test_guest_end
+test_guest:
+    li      a7, 0x4442434e          // DBCN
+    li      a6, 2                   // sbi_debug_console_write_byte
+    li      t0, 0
+    li      t1, 5
+1:
+    addi    a0, t0, 0x30
+    ecall
+    addi    t0, t0, 1
+    blt     t0, t1, 1b
+    li      a0, 0x0a
+    ecall
+    li      a7, 0x53525354          // SRST
+    li      a6, 0                   // sbi_system_reset
+    li      a0, 0                   // shutdown
+    li      a1, 0
+    ecall
+    unimp
+test_guest_end:
+.popsection
+"#
+);
+
+/// Host CPUs that run the vCPUs of the VM, vCPU `i` runs on the `i`-th one.
+/// The boot CPU runs vCPU 0.
+fn host_cpus() -> Vec<usize> {
+    let platform = crate::platform::info();
+    let boot_hart_id = platform.boot_hart_id;
+    let others = platform
+        .hart_ids
+        .iter()
+        .copied()
+        .filter(|&id| id != boot_hart_id && id < MAX_CPUS);
+    core::iter::once(boot_hart_id).chain(others).collect()
+}
+
+fn guest_config() -> VmConfig {
+    let cpu_count = host_cpus().len();
+    VmConfig {
+        cpu_count,
+        isa: host_isa(),
+        timebase_frequency: crate::platform::info().timebase_frequency as u32,
+        ram: vec![(GUEST_PHYS_MEMORY_BASE, GUEST_PHYS_MEMORY_SIZE)],
+        bootargs: String::new(),
+        plic: Some(GuestPlicConfig {
+            base: GUEST_PLIC_BASE,
+            size: align_up(devices::plic_size(cpu_count)),
+            num_sources: GUEST_PLIC_NUM_SOURCES,
+        }),
+        uart: Some(GuestDeviceConfig {
+            base: GUEST_UART_BASE,
+            size: PAGE_SIZE,
+            irq: GUEST_UART_IRQ,
+        }),
+        virtio_mmio: vec![GuestDeviceConfig {
+            base: GUEST_VIRTIO_CONSOLE_BASE,
+            size: PAGE_SIZE,
+            irq: GUEST_VIRTIO_CONSOLE_IRQ,
+        }],
+    }
+}
+
+/// Set up guest memory and load the guest in it, returns the memory and the
+/// entry of the guest.
+fn setup_gpm(config: &VmConfig) -> RvmResult<(GuestPhysMemorySet, GuestPhysAddr)> {
+    let mut gpm = GuestPhysMemorySet::new()?;
+    for &(base, size) in &config.ram {
+        gpm.map_region(GuestMemoryRegion::new_ram(
+            base,
+            size,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+        ))?;
+    }
+
+    extern "C" {
+        fn test_guest();
+        fn test_guest_end();
+    }
+    // Load `test_guest` as a raw image, it only uses PC-relative branches.
+    let (start, end) = (test_guest as usize, test_guest_end as usize);
+    let code = unsafe { core::slice::from_raw_parts(start as *const u8, end - start) };
+    let entry = loader::load_image(&mut gpm, code, GUEST_IMAGE_GPA)?;
+    dtb::load_guest_dtb(&mut gpm, config, GUEST_DTB_GPA)?;
+    debug!("{:#x?}", gpm);
+    Ok((gpm, entry))
+}
+
+fn create_vm(vmid: usize) -> RvmResult<Arc<RvmVm>> {
+    static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(1);
+    let config = guest_config();
+    let (gpm, entry) = setup_gpm(&config)?;
+    let id = NEXT_VM_ID.fetch_add(1, Ordering::Relaxed);
+    // vCPU 0 boots with the device tree in `a1`.
+    let vm = RvmVm::new(id, vmid, config, gpm, entry, GUEST_DTB_GPA)?;
+    // The guest's `hvc0` is the host console, and its second port the
+    // management channel.
+    let mgmt_port = Arc::new(ChannelPort::new(MGMT_PORT_NAME));
+    let console = VirtioConsole::new(vec![Arc::new(HostConsolePort), mgmt_port.clone()]);
+    vm.add_virtio_device(&vm.config().virtio_mmio[0], Box::new(console))?;
+    vm.set_mgmt_port(mgmt_port);
+    Ok(vm)
+}
+
+/// Print where the guest of `vcpu` was running, for crash reports. The walk
+/// is done in software, so it also works if the guest page table is broken.
+fn print_guest_pc(vm: &RvmVm, vcpu: &RvmVcpu) {
+    let sepc = vcpu.exit_info().sepc;
+    match vm
+        .gpm()
+        .lock()
+        .translate_guest_virt(vcpu.vs_csrs().vsatp, sepc)
+    {
+        Ok((gpa, hpa)) => {
+            println!("Guest PC {:#x}: GPA {:#x}, HPA {:#x}", sepc, gpa, hpa);
+        }
+        Err(e) => {
+            println!("Guest PC {:#x}: not translated: {:?}", sepc, e);
+        }
+    }
+}
+
+/// Run vCPU `vcpu_id` of `vm` on the current CPU, each time it is started,
+/// until the VM stops.
+fn run_vcpu(percpu: &RvmPerCpu, vm: &RvmVm, vcpu_id: usize) {
+    let cpu_id = percpu.cpu_id();
+    // Drop translations left by an earlier VM with the same VMID.
+    instructions::hfence_gvma_vmid(vm.vmid());
+    instructions::hfence_vvma_all();
+
+    while let Some((entry, opaque)) = vm.wait_for_start(vcpu_id) {
+        let mut vcpu = match percpu.create_vcpu(entry, vm.hgatp()) {
+            Ok(vcpu) => vcpu,
+            Err(e) => {
+                println!("CPU {}: failed to create vCPU {}: {:?}", cpu_id, vcpu_id, e);
+                vm.stop(VmState::Crashed);
+                return;
+            }
+        };
+        vcpu.set_time_delta(vm.time_delta());
+        // SBI HSM entry state: `a0` is the hart ID, `a1` is opaque.
+        vcpu.regs_mut().set_reg(GprIndex::A0, vcpu_id);
+        vcpu.regs_mut().set_reg(GprIndex::A1, opaque);
+        debug!("CPU {}: vCPU {} started at {:#x}", cpu_id, vcpu_id, entry);
+
+        // vCPU 0 serves the host console to the emulated devices.
+        let poll_console = vcpu_id == 0 && vm.has_console_devices();
+        let mut next_poll = Instant::now();
+
+        loop {
+            if !vm.is_running() {
+                return;
+            }
+            if poll_console && Instant::now() >= next_poll {
+                vm.poll_console();
+                // Only to leave the guest when the next poll is due.
+                next_poll = Instant::now() + CONSOLE_POLL_INTERVAL;
+                timer::set_timer(next_poll.ticks(), |_| {});
+            }
+            // Interrupts stay off from the pending check to the guest entry,
+            // so none is left pending in the host while the guest runs. A
+            // host interrupt still makes the guest exit at once, and is taken
+            // when they are enabled again.
+            let exit_reason = instructions::with_irqs_disabled(|| {
+                vm.prepare_run(vcpu_id, &mut vcpu);
+                vcpu.run()
+            });
+            match exit_reason {
+                RvmExitReason::Ecall => match sbi::handle_ecall(vm, vcpu_id, &mut vcpu) {
+                    SbiAction::Continue => {}
+                    SbiAction::HartStop => break,
+                    SbiAction::VmStop => return,
+                },
+                RvmExitReason::GuestPageFault {
+                    access: access @ (GuestAccessType::Load | GuestAccessType::Store),
+                    gpa,
+                    htinst,
+                    ..
+                } => {
+                    if let Err(e) = mmio::handle_mmio_fault(vm, &mut vcpu, access, gpa, htinst) {
+                        println!(
+                            "CPU {}: vCPU {} MMIO access at {:#x} failed: {:?}, {:#x?}",
+                            cpu_id,
+                            vcpu_id,
+                            gpa,
+                            e,
+                            vcpu.exit_info()
+                        );
+                        print_guest_pc(vm, &vcpu);
+                        vm.stop(VmState::Crashed);
+                        return;
+                    }
+                }
+                RvmExitReason::HostInterrupt(_) => {
+                    // Already handled by the host trap handler once interrupts
+                    // were enabled again.
+                }
+                reason => {
+                    println!(
+                        "CPU {}: vCPU {} exit: {:?}, {:#x?}",
+                        cpu_id,
+                        vcpu_id,
+                        reason,
+                        vcpu.exit_info()
+                    );
+                    print_guest_pc(vm, &vcpu);
+                    vm.stop(VmState::Crashed);
+                    return;
+                }
+            }
+        }
+        debug!("CPU {}: vCPU {} stopped", cpu_id, vcpu_id);
+    }
+}
+
+/// The VM run by all CPUs, replaced when the guest reboots.
+static CURRENT_VM: Mutex<Option<Arc<RvmVm>>> = Mutex::new(None);
+/// Set when the boot CPU will not create VMs anymore.
+static NO_MORE_VMS: AtomicBool = AtomicBool::new(false);
+
+/// Wait for a VM other than the one with `last_id`.
+fn wait_for_vm(last_id: Option<usize>) -> Option<Arc<RvmVm>> {
+    loop {
+        if let Some(vm) = CURRENT_VM.lock().as_ref() {
+            if Some(vm.id()) != last_id {
+                return Some(vm.clone());
+            }
+        }
+        if NO_MORE_VMS.load(Ordering::Acquire) {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
 pub fn run() {
-    println!("Starting virtualization...");
-    println!("Hardware support: {:?}", has_hardware_support());
+    let cpu_id = crate::percpu::this_cpu_id();
+    let Some(vcpu_id) = host_cpus().iter().position(|&id| id == cpu_id) else {
+        return;
+    };
+    if vcpu_id == 0 {
+        println!("Starting virtualization...");
+        println!("Hardware support: {:?}", has_hardware_support());
+    }
 
-    let mut percpu = RvmPerCpu::new(0);
+    let mut percpu = RvmPerCpu::new(cpu_id);
     let res = percpu.hardware_enable();
-    println!("Hardware enable: {:?}", res);
+    println!("CPU {}: hardware enable: {:?}", cpu_id, res);
+    if res.is_err() {
+        if vcpu_id == 0 {
+            NO_MORE_VMS.store(true, Ordering::Release);
+        }
+        return;
+    }
+
+    if vcpu_id != 0 {
+        let mut last_id = None;
+        while let Some(vm) = wait_for_vm(last_id) {
+            last_id = Some(vm.id());
+            run_vcpu(&percpu, &vm, vcpu_id);
+        }
+        return;
+    }
+
+    // VMID 0 is the only one available if no VMID bits are implemented.
+    let vmid = if percpu.vmid_bits() > 0 {
+        GUEST_VMID
+    } else {
+        0
+    };
+    loop {
+        let vm = match create_vm(vmid) {
+            Ok(vm) => vm,
+            Err(e) => {
+                println!("Failed to create VM: {:?}", e);
+                break;
+            }
+        };
+        info!("Created VM: {:#x?}", vm);
+        *CURRENT_VM.lock() = Some(vm.clone());
+        run_vcpu(&percpu, &vm, 0);
+        *CURRENT_VM.lock() = None;
+
+        let state = vm.state();
+        println!("VM {} stopped: {:?}", vm.id(), state);
+        if state != VmState::Reset {
+            break;
+        }
+    }
+    NO_MORE_VMS.store(true, Ordering::Release);
 }
@@ -0,0 +1,329 @@
+//! The SBI implementation seen by guests, called with `ecall` from VS-mode.
+//!
+//! See the [RISC-V SBI specification](https://github.com/riscv-non-isa/riscv-sbi-doc).
+
+use alloc::vec::Vec;
+
+use super::host_console::{self, GuestConsole};
+use super::vm::{RvmVm, VcpuState, VmState};
+use super::{GprIndex, RvmVcpu};
+use crate::mm::PAGE_SIZE;
+use crate::riscv64::instructions;
+use crate::riscv64::sbi::*;
+
+/// SBI specification version 2.0.
+const SPEC_VERSION: usize = 2 << 24;
+/// Implementation ID reported by `sbi_get_sbi_impl_id`.
+const IMPL_ID: usize = 0x52_564D; // "RVM"
+const IMPL_VERSION: usize = 1;
+
+/// Range flushes larger than this flush the whole VS-stage TLB instead.
+const MAX_SFENCE_PAGES: usize = 64;
+
+const FID_BASE_GET_SPEC_VERSION: usize = 0;
+const FID_BASE_GET_IMPL_ID: usize = 1;
+const FID_BASE_GET_IMPL_VERSION: usize = 2;
+const FID_BASE_PROBE_EXTENSION: usize = 3;
+const FID_BASE_GET_MVENDORID: usize = 4;
+const FID_BASE_GET_MARCHID: usize = 5;
+const FID_BASE_GET_MIMPID: usize = 6;
+
+const FID_TIME_SET_TIMER: usize = 0;
+
+const FID_IPI_SEND_IPI: usize = 0;
+
+const FID_RFENCE_REMOTE_FENCE_I: usize = 0;
+const FID_RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+const FID_RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+const FID_HSM_HART_START: usize = 0;
+const FID_HSM_HART_STOP: usize = 1;
+const FID_HSM_HART_GET_STATUS: usize = 2;
+
+const FID_SRST_SYSTEM_RESET: usize = 0;
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_COLD_REBOOT: usize = 1;
+const RESET_TYPE_WARM_REBOOT: usize = 2;
+
+const FID_DBCN_CONSOLE_WRITE: usize = 0;
+const FID_DBCN_CONSOLE_READ: usize = 1;
+const FID_DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+/// What the vCPU should do after an SBI call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiAction {
+    /// Return to the guest.
+    Continue,
+    /// The vCPU stopped itself with `sbi_hart_stop`.
+    HartStop,
+    /// The VM is no longer running, see [`RvmVm::state`].
+    VmStop,
+}
+
+/// The guest hart IDs selected by `hart_mask` and `hart_mask_base`.
+fn selected_harts(
+    vm: &RvmVm,
+    hart_mask: usize,
+    hart_mask_base: usize,
+) -> Result<Vec<usize>, SbiRet> {
+    if hart_mask_base == usize::MAX {
+        return Ok((0..vm.vcpu_count()).collect());
+    }
+    (0..usize::BITS as usize)
+        .filter(|i| hart_mask & (1 << i) != 0)
+        .map(|i| match hart_mask_base.checked_add(i) {
+            Some(id) if id < vm.vcpu_count() => Ok(id),
+            _ => Err(SbiRet::error(SBI_ERR_INVALID_PARAM)),
+        })
+        .collect()
+}
+
+fn probe_extension(eid: usize) -> bool {
+    matches!(
+        eid,
+        EID_BASE
+            | EID_TIME
+            | EID_IPI
+            | EID_RFENCE
+            | EID_HSM
+            | EID_SRST
+            | EID_DBCN
+            | EID_LEGACY_CONSOLE_PUTCHAR
+            | EID_LEGACY_CONSOLE_GETCHAR
+    )
+}
+
+fn handle_base(fid: usize, args: &[usize; 6]) -> SbiRet {
+    match fid {
+        FID_BASE_GET_SPEC_VERSION => SbiRet::success(SPEC_VERSION),
+        FID_BASE_GET_IMPL_ID => SbiRet::success(IMPL_ID),
+        FID_BASE_GET_IMPL_VERSION => SbiRet::success(IMPL_VERSION),
+        FID_BASE_PROBE_EXTENSION => SbiRet::success(probe_extension(args[0]) as usize),
+        // Do not expose the host's machine IDs.
+        FID_BASE_GET_MVENDORID | FID_BASE_GET_MARCHID | FID_BASE_GET_MIMPID => SbiRet::success(0),
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_ipi(vm: &RvmVm, fid: usize, args: &[usize; 6]) -> SbiRet {
+    if fid != FID_IPI_SEND_IPI {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    match selected_harts(vm, args[0], args[1]) {
+        Ok(harts) => {
+            for vcpu_id in harts {
+                vm.send_ipi(vcpu_id).ok();
+            }
+            SbiRet::success(0)
+        }
+        Err(ret) => ret,
+    }
+}
+
+/// Flush the VS-stage TLB of the current hart for `[start, start + size)`
+/// in address space `asid`, or all address spaces if `asid` is `None`.
+fn local_sfence_vma(start: usize, size: usize, asid: Option<usize>) {
+    let full = size == usize::MAX || size / PAGE_SIZE > MAX_SFENCE_PAGES;
+    match (full, asid) {
+        (true, Some(asid)) => instructions::hfence_vvma_asid(asid),
+        (true, None) => instructions::hfence_vvma_all(),
+        (false, _) => {
+            let end = start.saturating_add(size);
+            for vaddr in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+                match asid {
+                    Some(asid) => instructions::hfence_vvma(vaddr, asid),
+                    None => instructions::hfence_vvma_addr(vaddr),
+                }
+            }
+        }
+    }
+}
+
+/// Remote fences run at once on the calling vCPU. The other vCPUs run them
+/// as soon as they leave the guest, with a full flush for VS-stage ones, and
+/// the call returns once they all have.
+fn handle_rfence(vm: &RvmVm, vcpu_id: usize, fid: usize, args: &[usize; 6]) -> SbiRet {
+    let harts = match selected_harts(vm, args[0], args[1]) {
+        Ok(harts) => harts,
+        Err(ret) => return ret,
+    };
+    let (start, size) = (args[2], args[3]);
+    let mut requests = Vec::new();
+    for target in harts {
+        let shared = vm.vcpu(target).unwrap();
+        match fid {
+            FID_RFENCE_REMOTE_FENCE_I if target == vcpu_id => instructions::fence_i(),
+            FID_RFENCE_REMOTE_FENCE_I => requests.push((target, shared.request_fence_i())),
+            FID_RFENCE_REMOTE_SFENCE_VMA if target == vcpu_id => {
+                local_sfence_vma(start, size, None)
+            }
+            FID_RFENCE_REMOTE_SFENCE_VMA_ASID if target == vcpu_id => {
+                local_sfence_vma(start, size, Some(args[4]))
+            }
+            FID_RFENCE_REMOTE_SFENCE_VMA | FID_RFENCE_REMOTE_SFENCE_VMA_ASID => {
+                requests.push((target, shared.request_vvma_flush()))
+            }
+            // The HFENCE functions are only for guests that run guests.
+            _ => return SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+    vm.wait_for_fences(vcpu_id, &requests);
+    SbiRet::success(0)
+}
+
+fn handle_hsm(vm: &RvmVm, vcpu_id: usize, fid: usize, args: &[usize; 6]) -> (SbiRet, SbiAction) {
+    let ret = match fid {
+        FID_HSM_HART_START => {
+            let (target, entry, opaque) = (args[0], args[1], args[2]);
+            match vm.vcpu(target).map(|v| v.state()) {
+                None => SbiRet::error(SBI_ERR_INVALID_PARAM),
+                Some(VcpuState::Stopped) => {
+                    if vm.gpm().lock().translate(entry).is_err() {
+                        SbiRet::error(SBI_ERR_INVALID_ADDRESS)
+                    } else if vm.start_vcpu(target, entry, opaque).is_err() {
+                        SbiRet::error(SBI_ERR_ALREADY_AVAILABLE)
+                    } else {
+                        SbiRet::success(0)
+                    }
+                }
+                Some(_) => SbiRet::error(SBI_ERR_ALREADY_AVAILABLE),
+            }
+        }
+        FID_HSM_HART_STOP => {
+            vm.stop_vcpu(vcpu_id);
+            return (SbiRet::success(0), SbiAction::HartStop);
+        }
+        FID_HSM_HART_GET_STATUS => match vm.vcpu(args[0]) {
+            Some(vcpu) => SbiRet::success(vcpu.state() as usize),
+            None => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        },
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    };
+    (ret, SbiAction::Continue)
+}
+
+fn handle_srst(vm: &RvmVm, fid: usize, args: &[usize; 6]) -> (SbiRet, SbiAction) {
+    if fid != FID_SRST_SYSTEM_RESET {
+        return (SbiRet::error(SBI_ERR_NOT_SUPPORTED), SbiAction::Continue);
+    }
+    let reason = match args[0] {
+        RESET_TYPE_SHUTDOWN => VmState::Shutdown,
+        RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => VmState::Reset,
+        _ => return (SbiRet::error(SBI_ERR_INVALID_PARAM), SbiAction::Continue),
+    };
+    info!(
+        "[RVM] guest system reset: {:?}, reason {:#x}",
+        reason, args[1]
+    );
+    vm.stop(reason);
+    (SbiRet::success(0), SbiAction::VmStop)
+}
+
+fn handle_dbcn(vm: &RvmVm, fid: usize, args: &[usize; 6]) -> SbiRet {
+    let (num_bytes, gpa) = (args[0], args[1]);
+    // On RV64, `base_addr_lo` holds the whole address and `base_addr_hi`
+    // must be zero.
+    let addr_ok = args[2] == 0;
+    let mut buf = [0u8; 64];
+    match fid {
+        FID_DBCN_CONSOLE_WRITE | FID_DBCN_CONSOLE_READ if !addr_ok => {
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        }
+        FID_DBCN_CONSOLE_WRITE => {
+            let gpm = vm.gpm().lock();
+            let mut written = 0;
+            while written < num_bytes {
+                let len = buf.len().min(num_bytes - written);
+                let Some(addr) = gpa.checked_add(written) else {
+                    return SbiRet::error(SBI_ERR_INVALID_PARAM);
+                };
+                if gpm.read_bytes(addr, &mut buf[..len]).is_err() {
+                    return SbiRet::error(SBI_ERR_INVALID_PARAM);
+                }
+                host_console::write(GuestConsole::Sbi, &buf[..len]);
+                written += len;
+            }
+            SbiRet::success(written)
+        }
+        FID_DBCN_CONSOLE_READ => {
+            let len = buf.len().min(num_bytes);
+            let read = host_console::read(GuestConsole::Sbi, &mut buf[..len]);
+            match vm.gpm().lock().write_bytes(gpa, &buf[..read]) {
+                Ok(()) => SbiRet::success(read),
+                Err(_) => SbiRet::error(SBI_ERR_INVALID_PARAM),
+            }
+        }
+        FID_DBCN_CONSOLE_WRITE_BYTE => {
+            host_console::write(GuestConsole::Sbi, &[args[0] as u8]);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Handle an `ecall` from vCPU `vcpu_id` of `vm`, whose arguments are in
+/// `a0`-`a7` of `vcpu`. The result is written back to `a0` and `a1`, and
+/// `sepc` is moved past the `ecall`.
+pub fn handle_ecall(vm: &RvmVm, vcpu_id: usize, vcpu: &mut RvmVcpu) -> SbiAction {
+    let regs = vcpu.regs();
+    let eid = regs.reg(GprIndex::A7);
+    let fid = regs.reg(GprIndex::A6);
+    let args = [
+        regs.reg(GprIndex::A0),
+        regs.reg(GprIndex::A1),
+        regs.reg(GprIndex::A2),
+        regs.reg(GprIndex::A3),
+        regs.reg(GprIndex::A4),
+        regs.reg(GprIndex::A5),
+    ];
+    trace!(
+        "[RVM] vCPU {} SBI call {:#x}:{} {:#x?}",
+        vcpu_id,
+        eid,
+        fid,
+        args
+    );
+
+    let mut action = SbiAction::Continue;
+    let ret = match eid {
+        EID_BASE => handle_base(fid, &args),
+        EID_TIME if fid == FID_TIME_SET_TIMER => {
+            vcpu.set_timer(args[0] as u64);
+            SbiRet::success(0)
+        }
+        EID_IPI => handle_ipi(vm, fid, &args),
+        EID_RFENCE => handle_rfence(vm, vcpu_id, fid, &args),
+        EID_HSM => {
+            let (ret, hsm_action) = handle_hsm(vm, vcpu_id, fid, &args);
+            action = hsm_action;
+            ret
+        }
+        EID_SRST => {
+            let (ret, srst_action) = handle_srst(vm, fid, &args);
+            action = srst_action;
+            ret
+        }
+        EID_DBCN => handle_dbcn(vm, fid, &args),
+        // Legacy extensions only return a value in `a0`.
+        EID_LEGACY_CONSOLE_PUTCHAR => {
+            host_console::write(GuestConsole::Sbi, &[args[0] as u8]);
+            vcpu.regs_mut().set_reg(GprIndex::A0, 0);
+            vcpu.advance_pc(4);
+            return action;
+        }
+        EID_LEGACY_CONSOLE_GETCHAR => {
+            let c = host_console::read_byte(GuestConsole::Sbi).map_or(usize::MAX, |c| c as usize);
+            vcpu.regs_mut().set_reg(GprIndex::A0, c);
+            vcpu.advance_pc(4);
+            return action;
+        }
+        _ => {
+            debug!("[RVM] unsupported SBI call {:#x}:{}", eid, fid);
+            SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+        }
+    };
+    vcpu.regs_mut().set_reg(GprIndex::A0, ret.error as usize);
+    vcpu.regs_mut().set_reg(GprIndex::A1, ret.value);
+    vcpu.advance_pc(4);
+    action
+}
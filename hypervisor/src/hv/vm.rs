@@ -0,0 +1,530 @@
+//! A virtual machine whose vCPUs may run on several physical CPUs at once.
+
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use spin::{Mutex, RwLock, RwLockReadGuard};
+
+use super::config::{GuestDeviceConfig, VmConfig};
+use super::devices::virtio::{ChannelPort, VirtioDevice, VirtioMmio};
+use super::devices::{IrqSink, MmioBus, MmioDevice, VirtPlic, VirtUart16550};
+use super::host_console::{self, GuestConsole};
+use super::mgmt::MgmtChannel;
+use super::{GuestPhysAddr, RvmResult, RvmVcpu};
+use crate::mm::{GuestMemoryRegion, GuestPhysMemorySet};
+use crate::riscv64::sbi::{
+    HART_STATE_STARTED, HART_STATE_START_PENDING, HART_STATE_STOPPED, HART_STATE_STOP_PENDING,
+};
+use crate::riscv64::trap::{INTERRUPT_VS_EXT, INTERRUPT_VS_SOFT, INTERRUPT_VS_TIMER};
+use crate::riscv64::{instructions, ipi};
+use crate::timer::Instant;
+use crate::{rvm_err, rvm_err_type};
+
+/// Why a VM is no longer running.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    Running = 0,
+    /// The guest asked for a shutdown.
+    Shutdown = 1,
+    /// The guest asked for a reboot.
+    Reset = 2,
+    /// The guest did something the hypervisor cannot handle.
+    Crashed = 3,
+}
+
+/// HSM state of a vCPU, with the values of `sbi_hart_get_status`.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuState {
+    Started = HART_STATE_STARTED,
+    Stopped = HART_STATE_STOPPED,
+    StartPending = HART_STATE_START_PENDING,
+    StopPending = HART_STATE_STOP_PENDING,
+}
+
+/// `hfence.vvma` of all guest addresses.
+const PENDING_VVMA_ALL: usize = 1 << 0;
+/// `fence.i`.
+const PENDING_FENCE_I: usize = 1 << 1;
+
+/// No host CPU runs the vCPU.
+const NO_HOST_CPU: usize = usize::MAX;
+
+/// The `1 << irq` bit of VS-level interrupt `irq`.
+fn vs_irq_bit(irq: usize) -> RvmResult<usize> {
+    match irq {
+        INTERRUPT_VS_SOFT | INTERRUPT_VS_TIMER | INTERRUPT_VS_EXT => Ok(1 << irq),
+        _ => rvm_err!(InvalidParam, "not a VS-level interrupt"),
+    }
+}
+
+/// The part of a vCPU that other CPUs can act on.
+///
+/// Requests from other vCPUs are recorded here and applied by the CPU that
+/// runs the vCPU before it enters the guest again. That CPU is sent an IPI so
+/// that it leaves the guest if it is running it.
+pub struct VcpuShared {
+    state: AtomicUsize,
+    /// Entry and `a1` given to `sbi_hart_start`.
+    start_args: Mutex<(GuestPhysAddr, usize)>,
+    /// ID of the host CPU that runs the vCPU, or `NO_HOST_CPU`.
+    host_cpu: AtomicUsize,
+    /// `INTERRUPT_VS_*` bits to make pending.
+    pending_irqs: AtomicUsize,
+    /// `INTERRUPT_VS_*` bits to withdraw, applied before `pending_irqs`.
+    cleared_irqs: AtomicUsize,
+    /// `PENDING_*` fences to execute.
+    pending_fences: AtomicUsize,
+    /// Generation of the last fence request, counted from 1.
+    fences_requested: AtomicUsize,
+    /// Generation of the last fence request executed.
+    fences_done: AtomicUsize,
+}
+
+impl VcpuShared {
+    fn new(state: VcpuState) -> Self {
+        Self {
+            state: AtomicUsize::new(state as usize),
+            start_args: Mutex::new((0, 0)),
+            host_cpu: AtomicUsize::new(NO_HOST_CPU),
+            pending_irqs: AtomicUsize::new(0),
+            cleared_irqs: AtomicUsize::new(0),
+            pending_fences: AtomicUsize::new(0),
+            fences_requested: AtomicUsize::new(0),
+            fences_done: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn state(&self) -> VcpuState {
+        match self.state.load(Ordering::Acquire) {
+            HART_STATE_STARTED => VcpuState::Started,
+            HART_STATE_START_PENDING => VcpuState::StartPending,
+            HART_STATE_STOP_PENDING => VcpuState::StopPending,
+            _ => VcpuState::Stopped,
+        }
+    }
+
+    fn set_state(&self, state: VcpuState) {
+        self.state.store(state as usize, Ordering::Release);
+    }
+
+    /// Make the host CPU running the vCPU, if it is not the current one,
+    /// exit the guest to apply the requests.
+    fn kick(&self) {
+        let cpu_id = self.host_cpu.load(Ordering::Acquire);
+        if cpu_id != NO_HOST_CPU && cpu_id != crate::percpu::this_cpu_id() {
+            ipi::send_ipi(cpu_id);
+        }
+    }
+
+    /// Assert VS-level interrupt `irq` (`INTERRUPT_VS_*`), from any CPU. It
+    /// stays pending until deasserted, or cleared by the guest for `VSSIP`.
+    pub fn assert_irq(&self, irq: usize) -> RvmResult {
+        let bit = vs_irq_bit(irq)?;
+        self.cleared_irqs.fetch_and(!bit, Ordering::AcqRel);
+        self.pending_irqs.fetch_or(bit, Ordering::AcqRel);
+        self.kick();
+        Ok(())
+    }
+
+    /// Deassert VS-level interrupt `irq` (`INTERRUPT_VS_*`), from any CPU.
+    pub fn deassert_irq(&self, irq: usize) -> RvmResult {
+        let bit = vs_irq_bit(irq)?;
+        self.pending_irqs.fetch_and(!bit, Ordering::AcqRel);
+        self.cleared_irqs.fetch_or(bit, Ordering::AcqRel);
+        self.kick();
+        Ok(())
+    }
+
+    /// Flush the whole VS-stage TLB on the next guest entry. Returns the
+    /// generation to wait for with [`RvmVm::wait_for_fences`].
+    pub fn request_vvma_flush(&self) -> usize {
+        self.request_fences(PENDING_VVMA_ALL)
+    }
+
+    /// Execute `fence.i` on the next guest entry. Returns the generation to
+    /// wait for with [`RvmVm::wait_for_fences`].
+    pub fn request_fence_i(&self) -> usize {
+        self.request_fences(PENDING_FENCE_I)
+    }
+
+    fn request_fences(&self, fences: usize) -> usize {
+        self.pending_fences.fetch_or(fences, Ordering::AcqRel);
+        let gen = self.fences_requested.fetch_add(1, Ordering::AcqRel) + 1;
+        self.kick();
+        gen
+    }
+
+    /// Whether the fences requested up to generation `gen` are executed, or
+    /// no host CPU runs the vCPU, which executes them before it runs again.
+    fn fences_done(&self, gen: usize) -> bool {
+        self.fences_done.load(Ordering::Acquire) >= gen
+            || self.host_cpu.load(Ordering::Acquire) == NO_HOST_CPU
+    }
+
+    /// Execute the fences requested by other vCPUs on the current CPU.
+    fn apply_fences(&self) {
+        // The requests of this generation and the ones before are pending by
+        // now, the bits are set before the generation is counted.
+        let gen = self.fences_requested.load(Ordering::Acquire);
+        let fences = self.pending_fences.swap(0, Ordering::AcqRel);
+        if fences & PENDING_VVMA_ALL != 0 {
+            instructions::hfence_vvma_all();
+        }
+        if fences & PENDING_FENCE_I != 0 {
+            instructions::fence_i();
+        }
+        self.fences_done.fetch_max(gen, Ordering::AcqRel);
+    }
+
+    /// Apply the requests of other vCPUs to `vcpu`, which is about to run.
+    fn apply_pending(&self, vcpu: &mut RvmVcpu) {
+        let cleared = self.cleared_irqs.swap(0, Ordering::AcqRel);
+        for irq in (0..usize::BITS as usize).filter(|i| cleared & (1 << i) != 0) {
+            vcpu.clear_irq_pending(irq).ok();
+        }
+        let irqs = self.pending_irqs.swap(0, Ordering::AcqRel);
+        for irq in (0..usize::BITS as usize).filter(|i| irqs & (1 << i) != 0) {
+            vcpu.set_irq_pending(irq).ok();
+        }
+        self.apply_fences();
+    }
+}
+
+/// A virtual machine, shared by the CPUs that run its vCPUs.
+pub struct RvmVm {
+    id: usize,
+    vmid: usize,
+    config: VmConfig,
+    /// Shared with the devices that access guest memory.
+    gpm: Arc<Mutex<GuestPhysMemorySet>>,
+    hgatp: u64,
+    vcpus: Vec<VcpuShared>,
+    state: AtomicU8,
+    /// Added to the host `time` to get the guest one, shared by all vCPUs.
+    time_delta: u64,
+    plic: Option<Arc<VirtPlic>>,
+    uart: Option<Arc<VirtUart16550>>,
+    virtio_devices: Mutex<Vec<Arc<VirtioMmio>>>,
+    mgmt: Mutex<Option<MgmtChannel>>,
+    mmio_bus: RwLock<MmioBus>,
+}
+
+impl RvmVm {
+    /// Create a VM of `config` with guest memory `gpm`, tagged with `vmid`.
+    /// vCPU 0 is started at `entry` with `a1 = boot_arg`, the others wait for
+    /// `sbi_hart_start`.
+    pub fn new(
+        id: usize,
+        vmid: usize,
+        config: VmConfig,
+        mut gpm: GuestPhysMemorySet,
+        entry: GuestPhysAddr,
+        boot_arg: usize,
+    ) -> RvmResult<Arc<Self>> {
+        if config.cpu_count == 0 {
+            return rvm_err!(InvalidParam, "VM without vCPU");
+        }
+        let vcpus: Vec<_> = (0..config.cpu_count)
+            .map(|vcpu_id| match vcpu_id {
+                0 => VcpuShared::new(VcpuState::StartPending),
+                _ => VcpuShared::new(VcpuState::Stopped),
+            })
+            .collect();
+        *vcpus[0].start_args.lock() = (entry, boot_arg);
+        gpm.npt_mut().set_vmid(vmid);
+        let vm = Arc::new_cyclic(|vm: &Weak<Self>| {
+            // The PLIC context of each vCPU drives its `VSEIP`.
+            let plic = config.plic.map(|plic| {
+                let vm = vm.clone();
+                Arc::new(VirtPlic::new(
+                    plic.num_sources as usize,
+                    config.cpu_count,
+                    move |context, level| {
+                        if let Some(vm) = vm.upgrade() {
+                            match level {
+                                true => vm.assert_irq(context, INTERRUPT_VS_EXT),
+                                false => vm.deassert_irq(context, INTERRUPT_VS_EXT),
+                            }
+                            .ok();
+                        }
+                    },
+                ))
+            });
+            let uart = config.uart.map(|uart| {
+                let irq_sink = plic.clone().map(|plic| plic as Arc<dyn IrqSink>);
+                Arc::new(VirtUart16550::new(uart.irq, irq_sink, |data| {
+                    host_console::write(GuestConsole::Uart, data)
+                }))
+            });
+            Self {
+                id,
+                vmid,
+                hgatp: gpm.npt().hgatp(vmid),
+                config,
+                gpm: Arc::new(Mutex::new(gpm)),
+                vcpus,
+                state: AtomicU8::new(VmState::Running as u8),
+                // The guest `time` starts from 0.
+                time_delta: 0u64.wrapping_sub(Instant::now().ticks()),
+                plic,
+                uart,
+                virtio_devices: Mutex::new(Vec::new()),
+                mgmt: Mutex::new(None),
+                mmio_bus: RwLock::new(MmioBus::new()),
+            }
+        });
+        if let (Some(config), Some(plic)) = (vm.config.plic, vm.plic.clone()) {
+            vm.register_mmio(config.base, config.size, plic)?;
+        }
+        if let (Some(config), Some(uart)) = (vm.config.uart, vm.uart.clone()) {
+            vm.register_mmio(config.base, config.size, uart)?;
+        }
+        Ok(vm)
+    }
+
+    /// A number that differs from the VMs created before.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn vmid(&self) -> usize {
+        self.vmid
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// The `hgatp` value that selects the G-stage page table of this VM.
+    pub fn hgatp(&self) -> u64 {
+        self.hgatp
+    }
+
+    pub fn gpm(&self) -> &Mutex<GuestPhysMemorySet> {
+        &self.gpm
+    }
+
+    /// The `htimedelta` of all vCPUs.
+    pub fn time_delta(&self) -> u64 {
+        self.time_delta
+    }
+
+    /// The emulated PLIC, the interrupt sink of emulated devices.
+    pub fn plic(&self) -> Option<&Arc<VirtPlic>> {
+        self.plic.as_ref()
+    }
+
+    /// Emulate `device` on guest accesses to `[base, base + size)`, which is
+    /// left unmapped in guest memory.
+    pub fn register_mmio(
+        &self,
+        base: GuestPhysAddr,
+        size: usize,
+        device: Arc<dyn MmioDevice>,
+    ) -> RvmResult {
+        let mut bus = self.mmio_bus.write();
+        let mut gpm = self.gpm.lock();
+        gpm.map_region(GuestMemoryRegion::new_mmio(base, size))?;
+        if let Err(e) = bus.register(base, size, device) {
+            gpm.unmap_region(base)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Expose virtio `device` to the guest through a virtio-mmio transport at
+    /// the place described by `config`.
+    pub fn add_virtio_device(
+        &self,
+        config: &GuestDeviceConfig,
+        device: Box<dyn VirtioDevice>,
+    ) -> RvmResult {
+        let irq_sink = self.plic.clone().map(|plic| plic as Arc<dyn IrqSink>);
+        let transport = Arc::new(VirtioMmio::new(
+            device,
+            self.gpm.clone(),
+            config.irq,
+            irq_sink,
+        ));
+        self.register_mmio(config.base, config.size, transport.clone())?;
+        self.virtio_devices.lock().push(transport);
+        Ok(())
+    }
+
+    /// Serve the management commands of the guest on `port`, a port of one
+    /// of its virtio consoles.
+    pub fn set_mgmt_port(&self, port: Arc<ChannelPort>) {
+        *self.mgmt.lock() = Some(MgmtChannel::new(port));
+    }
+
+    /// The emulated devices of this VM.
+    pub fn mmio_bus(&self) -> RwLockReadGuard<'_, MmioBus> {
+        self.mmio_bus.read()
+    }
+
+    pub fn vcpu_count(&self) -> usize {
+        self.vcpus.len()
+    }
+
+    pub fn vcpu(&self, vcpu_id: usize) -> Option<&VcpuShared> {
+        self.vcpus.get(vcpu_id)
+    }
+
+    pub fn state(&self) -> VmState {
+        match self.state.load(Ordering::Acquire) {
+            0 => VmState::Running,
+            1 => VmState::Shutdown,
+            2 => VmState::Reset,
+            _ => VmState::Crashed,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state() == VmState::Running
+    }
+
+    /// Stop all vCPUs, the first reason given is kept.
+    pub fn stop(&self, reason: VmState) {
+        self.state
+            .compare_exchange(
+                VmState::Running as u8,
+                reason as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .ok();
+        for vcpu in &self.vcpus {
+            vcpu.kick();
+        }
+    }
+
+    /// Ask vCPU `vcpu_id` to start at `entry` with `a1 = opaque`.
+    pub fn start_vcpu(&self, vcpu_id: usize, entry: GuestPhysAddr, opaque: usize) -> RvmResult {
+        let vcpu = self.vcpu_or_err(vcpu_id)?;
+        let mut start_args = vcpu.start_args.lock();
+        if vcpu.state() != VcpuState::Stopped {
+            return rvm_err!(ResourceBusy, "vCPU already started");
+        }
+        *start_args = (entry, opaque);
+        vcpu.set_state(VcpuState::StartPending);
+        Ok(())
+    }
+
+    /// Mark vCPU `vcpu_id` as stopped, it is run again after the next
+    /// [`start_vcpu`](Self::start_vcpu).
+    pub fn stop_vcpu(&self, vcpu_id: usize) {
+        if let Some(vcpu) = self.vcpu(vcpu_id) {
+            vcpu.host_cpu.store(NO_HOST_CPU, Ordering::Release);
+            vcpu.set_state(VcpuState::Stopped);
+        }
+    }
+
+    /// Wait until vCPU `vcpu_id` is asked to start, and return its entry and
+    /// `a1`. Returns `None` if the VM stops first.
+    pub fn wait_for_start(&self, vcpu_id: usize) -> Option<(GuestPhysAddr, usize)> {
+        let vcpu = self.vcpu(vcpu_id)?;
+        loop {
+            if !self.is_running() {
+                return None;
+            }
+            if vcpu.state() == VcpuState::StartPending {
+                let start_args = *vcpu.start_args.lock();
+                vcpu.host_cpu
+                    .store(crate::percpu::this_cpu_id(), Ordering::Release);
+                vcpu.set_state(VcpuState::Started);
+                return Some(start_args);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn vcpu_or_err(&self, vcpu_id: usize) -> RvmResult<&VcpuShared> {
+        self.vcpu(vcpu_id)
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "invalid vCPU ID"))
+    }
+
+    /// Assert VS-level interrupt `irq` on vCPU `vcpu_id`, which may run on
+    /// another CPU.
+    pub fn assert_irq(&self, vcpu_id: usize, irq: usize) -> RvmResult {
+        self.vcpu_or_err(vcpu_id)?.assert_irq(irq)
+    }
+
+    /// Deassert VS-level interrupt `irq` on vCPU `vcpu_id`, which may run on
+    /// another CPU.
+    pub fn deassert_irq(&self, vcpu_id: usize, irq: usize) -> RvmResult {
+        self.vcpu_or_err(vcpu_id)?.deassert_irq(irq)
+    }
+
+    /// Wait until the fences requested on other vCPUs are executed. Each of
+    /// `requests` is a vCPU ID and the generation its request returned.
+    ///
+    /// The waiting vCPU `vcpu_id` executes the fences requested on it
+    /// meanwhile, so that vCPUs waiting on each other make progress.
+    pub fn wait_for_fences(&self, vcpu_id: usize, requests: &[(usize, usize)]) {
+        let Some(current) = self.vcpu(vcpu_id) else {
+            return;
+        };
+        for &(target, gen) in requests {
+            let Some(target) = self.vcpu(target) else {
+                continue;
+            };
+            while !target.fences_done(gen) && self.is_running() {
+                current.apply_fences();
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Send a software interrupt to vCPU `vcpu_id`.
+    pub fn send_ipi(&self, vcpu_id: usize) -> RvmResult {
+        self.assert_irq(vcpu_id, INTERRUPT_VS_SOFT)
+    }
+
+    /// Prepare `vcpu`, whose ID is `vcpu_id`, to enter the guest.
+    pub fn prepare_run(&self, vcpu_id: usize, vcpu: &mut RvmVcpu) {
+        if let Some(shared) = self.vcpu(vcpu_id) {
+            shared.apply_pending(vcpu);
+        }
+    }
+
+    /// Whether the VM has emulated devices that need [`Self::poll_console`].
+    pub fn has_console_devices(&self) -> bool {
+        self.uart.is_some() || !self.virtio_devices.lock().is_empty()
+    }
+
+    /// Move host console input to the emulated UART and virtio devices,
+    /// flush the output of the UART, and answer management commands.
+    pub fn poll_console(&self) {
+        if let Some(uart) = &self.uart {
+            uart.flush();
+            while uart.can_receive() {
+                match host_console::read_byte(GuestConsole::Uart) {
+                    Some(c) => uart.receive(c),
+                    None => break,
+                };
+            }
+        }
+        // Before the devices, which then pass the replies to the guest.
+        if let Some(mgmt) = self.mgmt.lock().as_mut() {
+            mgmt.poll(self);
+        }
+        for device in self.virtio_devices.lock().iter() {
+            device.poll();
+        }
+    }
+}
+
+impl core::fmt::Debug for RvmVm {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("RvmVm")
+            .field("id", &self.id)
+            .field("vmid", &self.vmid)
+            .field("vcpu_count", &self.vcpus.len())
+            .field("state", &self.state())
+            .finish()
+    }
+}
@@ -42,6 +42,15 @@ pub fn print(args: fmt::Arguments) {
     Stdout.write_fmt(args).unwrap();
 }
 
+/// Write raw bytes to the console, without line ending conversion. Used for
+/// guest console output.
+pub fn write_bytes(bytes: &[u8]) {
+    let _locked = PRINT_LOCK.lock();
+    for &b in bytes {
+        uart::console_putchar(b);
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($fmt: literal $(, $($arg: tt)+)?) => {
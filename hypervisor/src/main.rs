@@ -6,13 +6,18 @@
 #[macro_use]
 extern crate log;
 
+extern crate alloc;
+
 #[macro_use]
 mod logging;
 
-mod riscv64;
 mod config;
+mod fdt;
 mod hv;
 mod mm;
+mod percpu;
+mod platform;
+mod riscv64;
 mod timer;
 
 #[cfg(not(test))]
@@ -47,8 +52,11 @@ pub fn init_ok() -> bool {
 }
 
 #[no_mangle]
-pub extern "C" fn main() -> ! {
+pub extern "C" fn main(hart_id: usize, dtb: usize) -> ! {
     clear_bss();
+    percpu::init(hart_id);
+    mm::init_heap_early();
+    platform::init(hart_id, dtb);
     riscv64::init_early();
     println!("{}", LOGO);
     println!(
@@ -62,15 +70,17 @@ pub extern "C" fn main() -> ! {
         option_env!("LOG").unwrap_or(""),
     );
 
-    mm::init_heap_early();
     logging::init();
     info!("Logging is enabled.");
+    platform::print_info();
 
     riscv64::init();
     mm::init();
     INIT_OK.store(true, Ordering::SeqCst);
     println!("Initialization completed.\n");
 
+    riscv64::start_secondary_cpus(&platform::info().hart_ids);
+
     hv::run();
     println!("Run OK!");
 
@@ -79,3 +89,22 @@ pub extern "C" fn main() -> ! {
         riscv64::instructions::wait_for_ints();
     }
 }
+
+#[no_mangle]
+pub extern "C" fn main_secondary(hart_id: usize) -> ! {
+    percpu::init(hart_id);
+    mm::init_secondary();
+    riscv64::init();
+    info!(
+        "Secondary CPU {} started, {} CPUs online.",
+        hart_id,
+        percpu::online_cpus()
+    );
+
+    hv::run();
+
+    riscv64::instructions::enable_irqs();
+    loop {
+        riscv64::instructions::wait_for_ints();
+    }
+}
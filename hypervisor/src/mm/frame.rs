@@ -1,12 +1,17 @@
+use core::ops::Range;
+
 use bitmap_allocator::BitAlloc;
 use spin::Mutex;
 
-use super::address::{align_down, align_up, virt_to_phys, PhysAddr};
+use super::address::{align_down, align_up, PhysAddr};
 use super::PAGE_SIZE;
-use crate::config::PHYS_MEMORY_END;
 
-// Support max 1M * 4096 = 1GB memory.
-type FrameAlloc = bitmap_allocator::BitAlloc1M;
+// Support max 16M * 4096 = 64GB of memory, counted from the base.
+type FrameAlloc = bitmap_allocator::BitAlloc16M;
+const MAX_FRAMES: usize = 1 << 24;
+/// The bitmap base is aligned to 1G, so that `alloc_contiguous` can return
+/// blocks for 1G mappings.
+const BASE_ALIGN: usize = 1 << 30;
 
 static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
 
@@ -23,10 +28,31 @@ impl FrameAllocator {
         }
     }
 
-    fn init(&mut self, base: PhysAddr, size: usize) {
-        self.base = align_up(base);
-        let page_count = align_up(size) / PAGE_SIZE;
-        self.inner.insert(0..page_count);
+    /// Bitmap indices are counted from `base`, so `base` should be aligned to
+    /// the largest alignment requested by `alloc_contiguous`.
+    fn set_base(&mut self, base: PhysAddr) {
+        self.base = base;
+    }
+
+    /// The frames of `[start, start + size)` that the bitmap can track.
+    fn frame_range(&self, start: PhysAddr, size: usize) -> Range<usize> {
+        let limit = self.base + MAX_FRAMES * PAGE_SIZE;
+        let first = align_up(start).clamp(self.base, limit);
+        let last = align_down(start + size).clamp(first, limit);
+        (first - self.base) / PAGE_SIZE..(last - self.base) / PAGE_SIZE
+    }
+
+    /// Add the frames in `[start, start + size)` as free memory.
+    fn add_region(&mut self, start: PhysAddr, size: usize) {
+        let range = self.frame_range(start, size);
+        self.inner.insert(range);
+    }
+
+    /// Mark the frames overlapping `[start, start + size)` as used.
+    fn reserve(&mut self, start: PhysAddr, size: usize) {
+        let aligned_start = align_down(start);
+        let range = self.frame_range(aligned_start, align_up(start + size) - aligned_start);
+        self.inner.remove(range);
     }
 
     unsafe fn alloc(&mut self) -> Option<PhysAddr> {
@@ -39,6 +65,21 @@ impl FrameAllocator {
         trace!("Deallocate frame: {:x}", target);
         self.inner.dealloc((target - self.base) / PAGE_SIZE)
     }
+
+    unsafe fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<PhysAddr> {
+        let ret = self
+            .inner
+            .alloc_contiguous(count, align_log2)
+            .map(|idx| idx * PAGE_SIZE + self.base);
+        trace!("Allocate {} frames: {:x?}", count, ret);
+        ret
+    }
+
+    unsafe fn dealloc_contiguous(&mut self, target: PhysAddr, count: usize) {
+        trace!("Deallocate {} frames: {:x}", count, target);
+        let start_idx = (target - self.base) / PAGE_SIZE;
+        self.inner.insert(start_idx..start_idx + count);
+    }
 }
 
 pub unsafe fn alloc_page() -> Option<PhysAddr> {
@@ -49,17 +90,40 @@ pub unsafe fn dealloc_page(paddr: PhysAddr) {
     FRAME_ALLOCATOR.lock().dealloc(paddr)
 }
 
-pub(super) fn init() {
-    extern "C" {
-        fn ekernel();
-    }
+/// Allocate `count` contiguous frames, the start address is aligned to
+/// `PAGE_SIZE << align_log2`.
+pub unsafe fn alloc_contiguous(count: usize, align_log2: usize) -> Option<PhysAddr> {
+    FRAME_ALLOCATOR.lock().alloc_contiguous(count, align_log2)
+}
 
-    let mem_pool_start = align_up(virt_to_phys(ekernel as usize));
-    let mem_pool_end = align_down(PHYS_MEMORY_END);
-    let mem_pool_size = mem_pool_end - mem_pool_start;
-    println!(
-        "Initializing frame allocator at: [{:#x?}, {:#x?})",
-        mem_pool_start, mem_pool_end
-    );
-    FRAME_ALLOCATOR.lock().init(mem_pool_start, mem_pool_size);
+pub unsafe fn dealloc_contiguous(paddr: PhysAddr, count: usize) {
+    FRAME_ALLOCATOR.lock().dealloc_contiguous(paddr, count)
+}
+
+/// Initialize the allocator with free memory regions given as [start, end),
+/// excluding `reserved` regions given as (base, size).
+pub(super) fn init(
+    regions: impl Iterator<Item = (PhysAddr, PhysAddr)> + Clone,
+    reserved: &[(PhysAddr, usize)],
+) {
+    let Some(lowest) = regions.clone().map(|(start, _)| start).min() else {
+        panic!("no free memory found");
+    };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.set_base(lowest & !(BASE_ALIGN - 1));
+    let limit = allocator.base + MAX_FRAMES * PAGE_SIZE;
+    for (start, end) in regions {
+        if end > limit {
+            warn!("Memory above {:#x} is not managed", limit);
+        }
+        println!(
+            "Initializing frame allocator at: [{:#x?}, {:#x?})",
+            start, end
+        );
+        allocator.add_region(start, end - start);
+    }
+    for &(base, size) in reserved {
+        println!("Reserved memory: [{:#x?}, {:#x?})", base, base + size);
+        allocator.reserve(base, size);
+    }
 }
@@ -0,0 +1,316 @@
+use alloc::collections::BTreeMap;
+use core::fmt::{Debug, Formatter, Result};
+
+use super::address::{align_up, phys_to_virt};
+use super::page_table::PageSize;
+use super::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, MemFlags, PAGE_SIZE};
+use crate::hv::{NestedPageTable, RvmResult};
+use crate::riscv64::hext::PhysFrameRange;
+use crate::{rvm_err, rvm_err_type};
+
+/// How a guest physical memory region is backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestMemoryKind {
+    /// Guest RAM, backed by frames allocated when the region is mapped.
+    Ram,
+    /// A hole left unmapped in the G-stage table, so that guest accesses trap
+    /// for emulation.
+    Mmio,
+}
+
+impl GuestMemoryKind {
+    /// Whether backing frames are allocated and owned by the memory set.
+    const fn is_allocated(self) -> bool {
+        matches!(self, Self::Ram)
+    }
+}
+
+/// A contiguous range of guest physical memory.
+#[derive(Clone, Copy)]
+pub struct GuestMemoryRegion {
+    pub gpa: GuestPhysAddr,
+    /// Start of the backing host memory, set when the region is mapped.
+    pub hpa: HostPhysAddr,
+    pub size: usize,
+    pub flags: MemFlags,
+    pub kind: GuestMemoryKind,
+}
+
+impl GuestMemoryRegion {
+    pub const fn new_ram(gpa: GuestPhysAddr, size: usize, flags: MemFlags) -> Self {
+        Self {
+            gpa,
+            hpa: 0,
+            size,
+            flags,
+            kind: GuestMemoryKind::Ram,
+        }
+    }
+
+    pub const fn new_mmio(gpa: GuestPhysAddr, size: usize) -> Self {
+        Self {
+            gpa,
+            hpa: 0,
+            size,
+            flags: MemFlags::from_bits_truncate(
+                MemFlags::READ.bits() | MemFlags::WRITE.bits() | MemFlags::DEVICE.bits(),
+            ),
+            kind: GuestMemoryKind::Mmio,
+        }
+    }
+
+    pub const fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        self.gpa <= gpa && gpa < self.gpa + self.size
+    }
+
+    const fn is_overlap_with(&self, other: &Self) -> bool {
+        self.gpa < other.gpa + other.size && other.gpa < self.gpa + self.size
+    }
+
+    /// Allocate zeroed backing frames, and point the region at them.
+    fn alloc_backing(&mut self) -> RvmResult<PhysFrameRange> {
+        let count = self.size / PAGE_SIZE;
+        // Prefer 2M alignment so that large regions can use huge mappings,
+        // and fall back to 4K alignment.
+        let frames = match self.size >= PageSize::Size2M as usize {
+            true => PhysFrameRange::alloc_zero(count, 9)
+                .or_else(|_| PhysFrameRange::alloc_zero(count, 0)),
+            false => PhysFrameRange::alloc_zero(count, 0),
+        }?;
+        debug!(
+            "[RVM] allocated {:#x} bytes of guest memory at {:#x}",
+            self.size,
+            frames.start_paddr()
+        );
+        self.hpa = frames.start_paddr();
+        Ok(frames)
+    }
+
+    /// Map the region in `npt`. On failure, the part already mapped is
+    /// unmapped again.
+    fn map_to(&self, npt: &mut NestedPageTable) -> RvmResult {
+        if self.kind == GuestMemoryKind::Mmio {
+            return Ok(());
+        }
+        if let Err(e) = npt.map_region(self.gpa, self.hpa, self.size, self.flags, true) {
+            let mut offset = 0;
+            while offset < self.size {
+                let gpa = self.gpa + offset;
+                offset += match npt.query(gpa) {
+                    Ok((hpa, _, _)) if hpa == self.hpa + offset => {
+                        npt.unmap(gpa).map_or(PAGE_SIZE, |(_, size)| size as usize)
+                    }
+                    Ok((_, _, size)) => size as usize - (gpa & (size as usize - 1)),
+                    Err(_) => PAGE_SIZE,
+                };
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn unmap_from(&self, npt: &mut NestedPageTable) -> RvmResult {
+        if self.kind != GuestMemoryKind::Mmio {
+            npt.unmap_region(self.gpa, self.size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for GuestMemoryRegion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("GuestMemoryRegion")
+            .field("gpa_range", &(self.gpa..self.gpa + self.size))
+            .field("hpa_range", &(self.hpa..self.hpa + self.size))
+            .field("flags", &self.flags)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+/// The guest physical address space of a VM: a set of non-overlapping
+/// regions and the G-stage page table that maps them.
+///
+/// Backing frames of RAM and ROM regions are freed when the region is
+/// removed or the set is dropped.
+pub struct GuestPhysMemorySet {
+    regions: BTreeMap<GuestPhysAddr, GuestMemoryRegion>,
+    /// Backing frames of RAM and ROM regions, keyed by the region start.
+    backing: BTreeMap<GuestPhysAddr, PhysFrameRange>,
+    npt: NestedPageTable,
+}
+
+impl GuestPhysMemorySet {
+    pub fn new() -> RvmResult<Self> {
+        Ok(Self {
+            regions: BTreeMap::new(),
+            backing: BTreeMap::new(),
+            npt: NestedPageTable::new()?,
+        })
+    }
+
+    pub fn npt(&self) -> &NestedPageTable {
+        &self.npt
+    }
+
+    pub fn npt_mut(&mut self) -> &mut NestedPageTable {
+        &mut self.npt
+    }
+
+    /// Add `region` to the set, allocating its backing memory if needed, and
+    /// populate the G-stage table.
+    pub fn map_region(&mut self, mut region: GuestMemoryRegion) -> RvmResult {
+        region.size = align_up(region.size);
+        if region.size == 0 || region.gpa % PAGE_SIZE != 0 || region.hpa % PAGE_SIZE != 0 {
+            return rvm_err!(InvalidParam, "guest memory region not aligned");
+        }
+        if self.regions.values().any(|r| r.is_overlap_with(&region)) {
+            return rvm_err!(InvalidParam, "guest memory region overlapped");
+        }
+        let backing = match region.kind.is_allocated() {
+            true => Some(region.alloc_backing()?),
+            false => None,
+        };
+        region.map_to(&mut self.npt)?;
+        if let Some(backing) = backing {
+            self.backing.insert(region.gpa, backing);
+        }
+        self.regions.insert(region.gpa, region);
+        Ok(())
+    }
+
+    /// Remove the region that starts at `gpa`.
+    pub fn unmap_region(&mut self, gpa: GuestPhysAddr) -> RvmResult {
+        let region = self
+            .regions
+            .remove(&gpa)
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "guest memory region not found"))?;
+        region.unmap_from(&mut self.npt)?;
+        self.backing.remove(&gpa);
+        Ok(())
+    }
+
+    /// Find the region that contains `gpa`.
+    pub fn find_region(&self, gpa: GuestPhysAddr) -> Option<&GuestMemoryRegion> {
+        self.regions
+            .range(..=gpa)
+            .next_back()
+            .map(|(_, r)| r)
+            .filter(|r| r.contains(gpa))
+    }
+
+    /// Translate `gpa` to the host physical address that backs it.
+    pub fn translate(&self, gpa: GuestPhysAddr) -> RvmResult<HostPhysAddr> {
+        Ok(self.npt.query(gpa)?.0)
+    }
+
+    /// Translate guest virtual address `gva` in software, first through the
+    /// guest page table of `vsatp`, then through the G-stage table. Returns
+    /// the guest physical address and the host one that backs it.
+    ///
+    /// Only validity is checked, not access permissions or A/D bits.
+    pub fn translate_guest_virt(
+        &self,
+        vsatp: usize,
+        gva: GuestVirtAddr,
+    ) -> RvmResult<(GuestPhysAddr, HostPhysAddr)> {
+        const PTE_V: u64 = 1 << 0;
+        const PTE_R: u64 = 1 << 1;
+        const PTE_W: u64 = 1 << 2;
+        const PTE_X: u64 = 1 << 3;
+        const PPN_MASK: u64 = (1 << 44) - 1;
+
+        let levels = match vsatp >> 60 {
+            0 => return Ok((gva, self.translate(gva)?)),
+            8 => 3,  // Sv39
+            9 => 4,  // Sv48
+            10 => 5, // Sv57
+            _ => return rvm_err!(Unsupported, "unknown vsatp mode"),
+        };
+        let va_bits = 12 + 9 * levels;
+        if ((gva as isize) << (64 - va_bits) >> (64 - va_bits)) as usize != gva {
+            return rvm_err!(InvalidParam, "guest virtual address not canonical");
+        }
+
+        let mut table = (vsatp & PPN_MASK as usize) * PAGE_SIZE;
+        for level in (0..levels).rev() {
+            let shift = 12 + 9 * level;
+            let index = (gva >> shift) & 0x1ff;
+            let mut buf = [0; 8];
+            self.read_bytes(table + index * 8, &mut buf)?;
+            let pte = u64::from_le_bytes(buf);
+            if pte & PTE_V == 0 || (pte & PTE_W != 0 && pte & PTE_R == 0) {
+                return rvm_err!(InvalidParam, "invalid guest page table entry");
+            }
+            let base = ((pte >> 10) & PPN_MASK) as usize * PAGE_SIZE;
+            if pte & (PTE_R | PTE_X) != 0 {
+                let offset_mask = (1 << shift) - 1;
+                if base & offset_mask != 0 {
+                    return rvm_err!(InvalidParam, "misaligned guest superpage");
+                }
+                let gpa = base | (gva & offset_mask);
+                return Ok((gpa, self.translate(gpa)?));
+            }
+            table = base;
+        }
+        rvm_err!(InvalidParam, "guest page table has no leaf entry")
+    }
+
+    /// Get the host virtual address of `[gpa, gpa + size)`, which must lie
+    /// within a single RAM or ROM region.
+    fn hva_range(&self, gpa: GuestPhysAddr, size: usize) -> RvmResult<*mut u8> {
+        let region = self
+            .find_region(gpa)
+            .filter(|r| r.kind.is_allocated())
+            .ok_or_else(|| rvm_err_type!(InvalidParam, "guest address not in RAM or ROM"))?;
+        if size > region.gpa + region.size - gpa {
+            return rvm_err!(InvalidParam, "guest memory range crosses region boundary");
+        }
+        Ok(phys_to_virt(region.hpa + (gpa - region.gpa)) as *mut u8)
+    }
+
+    /// Copy `data` into guest memory at `gpa`. ROM regions can be written
+    /// too, as the hypervisor is the one that fills them.
+    pub fn write_bytes(&mut self, gpa: GuestPhysAddr, data: &[u8]) -> RvmResult {
+        let dst = self.hva_range(gpa, data.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    /// Copy guest memory at `gpa` into `buf`.
+    pub fn read_bytes(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> RvmResult {
+        let src = self.hva_range(gpa, buf.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Fill `size` bytes of guest memory at `gpa` with zeros.
+    pub fn zero_bytes(&mut self, gpa: GuestPhysAddr, size: usize) -> RvmResult {
+        let dst = self.hva_range(gpa, size)?;
+        unsafe { core::ptr::write_bytes(dst, 0, size) };
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        for region in self.regions.values_mut() {
+            region.unmap_from(&mut self.npt).unwrap();
+        }
+        self.regions.clear();
+        self.backing.clear();
+    }
+}
+
+impl Drop for GuestPhysMemorySet {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl Debug for GuestPhysMemorySet {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("GuestPhysMemorySet")
+            .field("npt_root", &self.npt.root_paddr())
+            .field("regions", &self.regions)
+            .finish()
+    }
+}
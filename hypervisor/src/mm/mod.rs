@@ -1,7 +1,12 @@
 mod heap;
+mod paging;
 
 pub mod address;
 pub mod frame;
+pub mod gpm;
+pub mod page_table;
+
+pub use gpm::{GuestMemoryKind, GuestMemoryRegion, GuestPhysMemorySet};
 
 pub const PAGE_SIZE: usize = 0x1000;
 
@@ -10,10 +15,50 @@ pub type GuestPhysAddr = usize;
 pub type HostVirtAddr = usize;
 pub type HostPhysAddr = usize;
 
+bitflags::bitflags! {
+    /// Permission and type of a memory mapping.
+    pub struct MemFlags: u64 {
+        const READ          = 1 << 0;
+        const WRITE         = 1 << 1;
+        const EXECUTE       = 1 << 2;
+        const USER          = 1 << 3;
+        const DEVICE        = 1 << 4;
+    }
+}
+
 pub fn init_heap_early() {
     heap::init();
 }
 
+/// Physical memory left to the hypervisor, as [start, end): all memory
+/// banks except the kernel and the part of its bank below it, which holds
+/// the SBI firmware.
+fn usable_memory() -> impl Iterator<Item = (HostPhysAddr, HostPhysAddr)> + Clone {
+    extern "C" {
+        fn ekernel();
+    }
+
+    let kernel_end = address::align_up(address::virt_to_phys(ekernel as usize));
+    crate::platform::info()
+        .memory
+        .iter()
+        .filter_map(move |&(base, size)| {
+            let end = base + size;
+            let start = if (base..end).contains(&kernel_end) {
+                kernel_end
+            } else {
+                base
+            };
+            Some((start, end)).filter(|_| start < end)
+        })
+}
+
 pub fn init() {
-    frame::init();
+    frame::init(usable_memory(), &crate::platform::info().reserved);
+    paging::init();
+}
+
+/// Switch a secondary CPU to the kernel page table built by [`init`].
+pub fn init_secondary() {
+    paging::init_secondary();
 }
@@ -0,0 +1,393 @@
+//! Generic multi-level page tables, shared by the host and G-stage tables.
+
+use alloc::vec::Vec;
+use core::{fmt::Debug, marker::PhantomData};
+
+use super::address::{phys_to_virt, PhysAddr, VirtAddr};
+use super::{frame, MemFlags, PAGE_SIZE};
+use crate::hv::RvmError;
+use crate::riscv64::hext::PhysFrameRange;
+use crate::rvm_err_type;
+
+const ENTRY_COUNT: usize = 512;
+
+#[derive(Debug)]
+pub enum PagingError {
+    NoMemory,
+    NotAligned,
+    NotMapped,
+    AlreadyMapped,
+    MappedToHugePage,
+}
+
+pub type PagingResult<T = ()> = Result<T, PagingError>;
+
+impl From<PagingError> for RvmError {
+    fn from(err: PagingError) -> Self {
+        match err {
+            PagingError::NoMemory => rvm_err_type!(OutOfMemory),
+            _ => rvm_err_type!(InvalidParam, format_args!("{:?}", err)),
+        }
+    }
+}
+
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PageSize {
+    Size4K = 0x1000,
+    Size2M = 0x20_0000,
+    Size1G = 0x4000_0000,
+}
+
+impl PageSize {
+    pub const fn is_huge(self) -> bool {
+        matches!(self, Self::Size1G | Self::Size2M)
+    }
+
+    pub const fn is_aligned(self, addr: usize) -> bool {
+        addr & (self as usize - 1) == 0
+    }
+
+    /// Size of a leaf that is `levels_below` levels above the last level.
+    const fn from_levels_below(levels_below: usize) -> Option<Self> {
+        match levels_below {
+            0 => Some(Self::Size4K),
+            1 => Some(Self::Size2M),
+            2 => Some(Self::Size1G),
+            _ => None,
+        }
+    }
+
+    const fn levels_below(self) -> usize {
+        match self {
+            Self::Size4K => 0,
+            Self::Size2M => 1,
+            Self::Size1G => 2,
+        }
+    }
+}
+
+/// Paging mode parameters of a page table.
+pub trait PagingMetaData: Sync + Send + Sized {
+    /// Number of levels of the page table.
+    const LEVELS: usize;
+    /// Width of the input (virtual or guest physical) address.
+    const VA_MAX_BITS: usize;
+    /// Number of contiguous pages of the root table.
+    const ROOT_PAGES: usize = 1;
+
+    /// Number of entries in the root table.
+    const ROOT_ENTRY_COUNT: usize = 1 << (Self::VA_MAX_BITS - 12 - 9 * (Self::LEVELS - 1));
+
+    /// Whether `vaddr` is in the input address space. By default the bits
+    /// above `VA_MAX_BITS` must be zero, as for guest physical addresses.
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        vaddr >> Self::VA_MAX_BITS == 0
+    }
+}
+
+/// A page table entry, its layout is decided by the architecture.
+pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
+    /// Create a leaf entry that maps to `paddr`.
+    fn new_page(paddr: PhysAddr, flags: MemFlags, is_huge: bool) -> Self;
+    /// Create an entry that points to the next level table at `paddr`.
+    fn new_table(paddr: PhysAddr) -> Self;
+
+    fn paddr(&self) -> PhysAddr;
+    fn flags(&self) -> MemFlags;
+    fn set_paddr(&mut self, paddr: PhysAddr);
+    fn set_flags(&mut self, flags: MemFlags, is_huge: bool);
+    fn is_unused(&self) -> bool;
+    fn is_present(&self) -> bool;
+    /// Whether the entry is a leaf above the last level.
+    fn is_huge(&self) -> bool;
+    fn clear(&mut self);
+}
+
+/// A generic page table whose input addresses are translated in
+/// `M::LEVELS` levels of 512-entry tables, except the root.
+pub struct PageTable64<M: PagingMetaData, PTE: GenericPTE> {
+    root: PhysFrameRange,
+    intrm_tables: Vec<PhysAddr>,
+    _phantom: PhantomData<(M, PTE)>,
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE> PageTable64<M, PTE> {
+    /// Create an empty page table, the root is aligned to its own size.
+    pub fn new() -> PagingResult<Self> {
+        let pages = M::ROOT_PAGES;
+        let root = PhysFrameRange::alloc_zero(pages, pages.trailing_zeros() as usize)
+            .map_err(|_| PagingError::NoMemory)?;
+        Ok(Self {
+            root,
+            intrm_tables: Vec::new(),
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn root_paddr(&self) -> PhysAddr {
+        self.root.start_paddr()
+    }
+
+    pub fn map(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        page_size: PageSize,
+        flags: MemFlags,
+    ) -> PagingResult {
+        if !page_size.is_aligned(vaddr) || !page_size.is_aligned(paddr) {
+            return Err(PagingError::NotAligned);
+        }
+        let entry = self.get_entry_mut_or_create(vaddr, page_size)?;
+        if !entry.is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *entry = GenericPTE::new_page(paddr, flags, page_size.is_huge());
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, PageSize)> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if !entry.is_present() {
+            return Err(PagingError::NotMapped);
+        }
+        let paddr = entry.paddr();
+        entry.clear();
+        Ok((paddr, size))
+    }
+
+    /// Change the flags of the mapping that contains `vaddr`.
+    pub fn protect(&mut self, vaddr: VirtAddr, flags: MemFlags) -> PagingResult<PageSize> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if !entry.is_present() {
+            return Err(PagingError::NotMapped);
+        }
+        entry.set_flags(flags, size.is_huge());
+        Ok(size)
+    }
+
+    /// Returns the physical address `vaddr` is mapped to, together with the
+    /// flags and size of the mapping.
+    pub fn query(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
+        let (entry, size) = self.get_entry(vaddr)?;
+        if !entry.is_present() {
+            return Err(PagingError::NotMapped);
+        }
+        let off = vaddr & (size as usize - 1);
+        Ok((entry.paddr() + off, entry.flags(), size))
+    }
+
+    /// Map `[vaddr, vaddr + size)` to `[paddr, paddr + size)`, using 2M or 1G
+    /// leaves where both addresses are suitably aligned if `allow_huge`.
+    pub fn map_region(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: usize,
+        flags: MemFlags,
+        allow_huge: bool,
+    ) -> PagingResult {
+        if !PageSize::Size4K.is_aligned(vaddr)
+            || !PageSize::Size4K.is_aligned(paddr)
+            || !PageSize::Size4K.is_aligned(size)
+        {
+            return Err(PagingError::NotAligned);
+        }
+        trace!(
+            "map_region({:#x}): [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
+            self.root_paddr(),
+            vaddr,
+            vaddr + size,
+            paddr,
+            paddr + size,
+            flags,
+        );
+        let mut vaddr = vaddr;
+        let mut paddr = paddr;
+        let mut size = size;
+        while size > 0 {
+            let page_size = if allow_huge {
+                [PageSize::Size1G, PageSize::Size2M]
+                    .into_iter()
+                    .find(|&ps| ps.is_aligned(vaddr) && ps.is_aligned(paddr) && size >= ps as usize)
+                    .unwrap_or(PageSize::Size4K)
+            } else {
+                PageSize::Size4K
+            };
+            self.map(vaddr, paddr, page_size, flags)?;
+            vaddr += page_size as usize;
+            paddr += page_size as usize;
+            size -= page_size as usize;
+        }
+        Ok(())
+    }
+
+    pub fn unmap_region(&mut self, vaddr: VirtAddr, size: usize) -> PagingResult {
+        trace!(
+            "unmap_region({:#x}) [{:#x}, {:#x})",
+            self.root_paddr(),
+            vaddr,
+            vaddr + size,
+        );
+        let mut vaddr = vaddr;
+        let mut size = size;
+        while size > 0 {
+            let (_, page_size) = self.unmap(vaddr)?;
+            assert!(page_size.is_aligned(vaddr));
+            assert!(page_size as usize <= size);
+            vaddr += page_size as usize;
+            size -= page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Print all present leaf entries.
+    pub fn dump(&self) {
+        self.dump_table(self.root_paddr(), 0, 0);
+    }
+}
+
+// Private implementation.
+impl<M: PagingMetaData, PTE: GenericPTE> PageTable64<M, PTE> {
+    fn alloc_table() -> PagingResult<PhysAddr> {
+        let paddr = unsafe { frame::alloc_page() }.ok_or(PagingError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, PAGE_SIZE) };
+        Ok(paddr)
+    }
+
+    fn p_index(vaddr: VirtAddr, level: usize) -> usize {
+        let shift = 12 + 9 * (M::LEVELS - 1 - level);
+        (vaddr >> shift) & (Self::table_len(level) - 1)
+    }
+
+    /// Addresses outside the input address space would alias lower ones
+    /// once masked into table indices.
+    fn check_vaddr(vaddr: VirtAddr) -> PagingResult {
+        match M::vaddr_is_valid(vaddr) {
+            true => Ok(()),
+            false => Err(PagingError::NotMapped),
+        }
+    }
+
+    const fn table_len(level: usize) -> usize {
+        if level == 0 {
+            M::ROOT_ENTRY_COUNT
+        } else {
+            ENTRY_COUNT
+        }
+    }
+
+    fn table_of<'a>(&self, paddr: PhysAddr, level: usize) -> &'a [PTE] {
+        let count = Self::table_len(level);
+        unsafe { core::slice::from_raw_parts(phys_to_virt(paddr) as *const PTE, count) }
+    }
+
+    fn table_of_mut<'a>(&mut self, paddr: PhysAddr, level: usize) -> &'a mut [PTE] {
+        let count = Self::table_len(level);
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(paddr) as *mut PTE, count) }
+    }
+
+    /// The address of the next level table that `entry` points to.
+    fn next_table_paddr(entry: &PTE) -> PagingResult<PhysAddr> {
+        if !entry.is_present() {
+            Err(PagingError::NotMapped)
+        } else if entry.is_huge() {
+            Err(PagingError::MappedToHugePage)
+        } else {
+            Ok(entry.paddr())
+        }
+    }
+
+    fn next_table_mut_or_create<'a>(
+        &mut self,
+        entry: &mut PTE,
+        level: usize,
+    ) -> PagingResult<&'a mut [PTE]> {
+        if entry.is_unused() {
+            let paddr = Self::alloc_table()?;
+            self.intrm_tables.push(paddr);
+            *entry = GenericPTE::new_table(paddr);
+            Ok(self.table_of_mut(paddr, level + 1))
+        } else {
+            let paddr = Self::next_table_paddr(entry)?;
+            Ok(self.table_of_mut(paddr, level + 1))
+        }
+    }
+
+    fn get_entry(&self, vaddr: VirtAddr) -> PagingResult<(&PTE, PageSize)> {
+        Self::check_vaddr(vaddr)?;
+        let mut table = self.table_of(self.root_paddr(), 0);
+        for level in 0..M::LEVELS {
+            let entry = &table[Self::p_index(vaddr, level)];
+            let levels_below = M::LEVELS - 1 - level;
+            if levels_below == 0 || entry.is_huge() {
+                let size = PageSize::from_levels_below(levels_below)
+                    .ok_or(PagingError::MappedToHugePage)?;
+                return Ok((entry, size));
+            }
+            table = self.table_of(Self::next_table_paddr(entry)?, level + 1);
+        }
+        unreachable!()
+    }
+
+    fn get_entry_mut(&mut self, vaddr: VirtAddr) -> PagingResult<(&mut PTE, PageSize)> {
+        Self::check_vaddr(vaddr)?;
+        let mut table = self.table_of_mut(self.root_paddr(), 0);
+        for level in 0..M::LEVELS {
+            let entry = &mut table[Self::p_index(vaddr, level)];
+            let levels_below = M::LEVELS - 1 - level;
+            if levels_below == 0 || entry.is_huge() {
+                let size = PageSize::from_levels_below(levels_below)
+                    .ok_or(PagingError::MappedToHugePage)?;
+                return Ok((entry, size));
+            }
+            table = self.table_of_mut(Self::next_table_paddr(entry)?, level + 1);
+        }
+        unreachable!()
+    }
+
+    fn get_entry_mut_or_create(
+        &mut self,
+        vaddr: VirtAddr,
+        page_size: PageSize,
+    ) -> PagingResult<&mut PTE> {
+        Self::check_vaddr(vaddr)?;
+        let leaf_level = M::LEVELS - 1 - page_size.levels_below();
+        let mut table = self.table_of_mut(self.root_paddr(), 0);
+        for level in 0..leaf_level {
+            let entry = &mut table[Self::p_index(vaddr, level)];
+            table = self.next_table_mut_or_create(entry, level)?;
+        }
+        Ok(&mut table[Self::p_index(vaddr, leaf_level)])
+    }
+
+    fn dump_table(&self, table_paddr: PhysAddr, level: usize, start_vaddr: VirtAddr) {
+        let shift = 12 + 9 * (M::LEVELS - 1 - level);
+        for (i, entry) in self.table_of(table_paddr, level).iter().enumerate() {
+            if !entry.is_present() {
+                continue;
+            }
+            let vaddr = start_vaddr | (i << shift);
+            if level == M::LEVELS - 1 || entry.is_huge() {
+                println!(
+                    "[PT] {:#x} -> {:#x} ({:#x}) {:?}",
+                    vaddr,
+                    entry.paddr(),
+                    1usize << shift,
+                    entry.flags()
+                );
+            } else {
+                self.dump_table(entry.paddr(), level + 1, vaddr);
+            }
+        }
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE> Drop for PageTable64<M, PTE> {
+    fn drop(&mut self) {
+        for &paddr in &self.intrm_tables {
+            unsafe { frame::dealloc_page(paddr) };
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! The address space of the hypervisor itself.
+
+use spin::Once;
+
+use super::address::{phys_to_virt, virt_to_phys};
+use super::page_table::PagingResult;
+use super::MemFlags;
+use crate::riscv64::instructions;
+use crate::riscv64::page_table::HostPageTable;
+
+static KERNEL_PAGE_TABLE: Once<HostPageTable> = Once::new();
+
+fn map_kernel_memory(pt: &mut HostPageTable) -> PagingResult {
+    extern "C" {
+        fn stext();
+        fn etext();
+        fn srodata();
+        fn erodata();
+        fn sdata();
+        fn edata();
+        fn boot_stack();
+        fn ebss();
+    }
+
+    let sections = [
+        (
+            ".text",
+            stext as usize,
+            etext as usize,
+            MemFlags::READ | MemFlags::EXECUTE,
+        ),
+        (
+            ".rodata",
+            srodata as usize,
+            erodata as usize,
+            MemFlags::READ,
+        ),
+        (
+            ".data",
+            sdata as usize,
+            edata as usize,
+            MemFlags::READ | MemFlags::WRITE,
+        ),
+        (
+            ".bss",
+            boot_stack as usize,
+            ebss as usize,
+            MemFlags::READ | MemFlags::WRITE,
+        ),
+    ];
+    for (name, start, end, flags) in sections {
+        println!("Mapping {}: [{:#x}, {:#x}) {:?}", name, start, end, flags);
+        pt.map_region(start, virt_to_phys(start), end - start, flags, false)?;
+    }
+
+    // The rest of physical memory, used by the frame allocator.
+    for (start, end) in super::usable_memory() {
+        println!(
+            "Mapping physical memory: [{:#x}, {:#x})",
+            phys_to_virt(start),
+            phys_to_virt(end)
+        );
+        pt.map_region(
+            phys_to_virt(start),
+            start,
+            end - start,
+            MemFlags::READ | MemFlags::WRITE,
+            true,
+        )?;
+    }
+
+    let platform = crate::platform::info();
+    let mmio_regions = platform
+        .uart
+        .iter()
+        .chain(platform.plic.iter())
+        .chain(platform.virtio_mmio.iter());
+    for &(base, size) in mmio_regions {
+        println!(
+            "Mapping MMIO: [{:#x}, {:#x})",
+            phys_to_virt(base),
+            phys_to_virt(base + size)
+        );
+        pt.map_region(
+            phys_to_virt(base),
+            base,
+            size,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::DEVICE,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// Build the kernel page table and switch to it, dropping the boot page
+/// table that maps the whole low 4G with RWX permissions.
+pub(super) fn init() {
+    let pt = KERNEL_PAGE_TABLE.call_once(|| {
+        let mut pt = HostPageTable::new().unwrap();
+        map_kernel_memory(&mut pt).unwrap();
+        pt
+    });
+    instructions::activate_sv39(pt.root_paddr());
+    info!("Switched to kernel page table at {:#x}", pt.root_paddr());
+}
+
+pub(super) fn init_secondary() {
+    let pt = KERNEL_PAGE_TABLE
+        .get()
+        .expect("kernel page table not built");
+    instructions::activate_sv39(pt.root_paddr());
+}
@@ -0,0 +1,65 @@
+//! Per-CPU data of the hypervisor.
+//!
+//! Each hart has a [`PerCpu`] area, indexed by its hart ID. Its address is
+//! kept in `tp` and `sscratch` while the hart runs the hypervisor, the world
+//! switch saves and restores both around a guest run.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::MAX_CPUS;
+use crate::riscv64::instructions;
+use crate::riscv64::timer::TimerQueue;
+
+static PER_CPU: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// Number of CPUs that have called [`init`].
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Data owned by one CPU.
+pub struct PerCpu {
+    cpu_id: AtomicUsize,
+    /// Timer events to run on this CPU.
+    pub timer_queue: TimerQueue,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            cpu_id: AtomicUsize::new(0),
+            timer_queue: TimerQueue::new(),
+        }
+    }
+
+    /// ID of this CPU, the same as its hart ID.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id.load(Ordering::Relaxed)
+    }
+}
+
+/// Set up the per-CPU area of the current hart, whose hart ID is `cpu_id`.
+///
+/// Harts with an ID of `MAX_CPUS` or more have no per-CPU area, the boot
+/// code parks them before they get here.
+pub fn init(cpu_id: usize) {
+    let percpu = PER_CPU.get(cpu_id).expect("hart ID beyond MAX_CPUS");
+    percpu.cpu_id.store(cpu_id, Ordering::Relaxed);
+    let ptr = percpu as *const PerCpu as usize;
+    instructions::write_tp(ptr);
+    instructions::write_sscratch(ptr);
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// The per-CPU area of the current hart, available after [`init`].
+pub fn current() -> &'static PerCpu {
+    unsafe { &*(instructions::read_tp() as *const PerCpu) }
+}
+
+/// ID of the current CPU.
+pub fn this_cpu_id() -> usize {
+    current().cpu_id()
+}
+
+/// Number of CPUs that have set up their per-CPU area.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
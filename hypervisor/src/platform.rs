@@ -0,0 +1,133 @@
+//! Description of the host machine, discovered from the device tree passed
+//! by the SBI firmware.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::fdt::{Fdt, FdtNode};
+use crate::mm::address::phys_to_virt;
+use crate::mm::HostPhysAddr;
+
+/// Used if the device tree gives no valid `timebase-frequency`, it is the
+/// value of QEMU virt.
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+const UART_COMPATIBLE: &[&str] = &["ns16550a", "ns16550"];
+const PLIC_COMPATIBLE: &[&str] = &["sifive,plic-1.0.0", "riscv,plic0"];
+const VIRTIO_MMIO_COMPATIBLE: &[&str] = &["virtio,mmio"];
+
+static PLATFORM_INFO: Once<PlatformInfo> = Once::new();
+
+/// Host machine description, as found in the device tree.
+#[derive(Debug, Default)]
+pub struct PlatformInfo {
+    /// Physical address and size of the device tree blob.
+    pub dtb: (HostPhysAddr, usize),
+    /// ID of the hart that runs `main`.
+    pub boot_hart_id: usize,
+    /// IDs of all available harts.
+    pub hart_ids: Vec<usize>,
+    /// `riscv,isa` of the boot hart, like `rv64imafdch_zicsr`, or empty if
+    /// the device tree does not give it.
+    pub boot_hart_isa: String,
+    /// Frequency of the `time` CSR, in Hz.
+    pub timebase_frequency: u64,
+    /// RAM banks, as (base, size).
+    pub memory: Vec<(HostPhysAddr, usize)>,
+    /// Memory the hypervisor must not touch, like the SBI firmware, as
+    /// (base, size).
+    pub reserved: Vec<(HostPhysAddr, usize)>,
+    pub uart: Option<(HostPhysAddr, usize)>,
+    pub plic: Option<(HostPhysAddr, usize)>,
+    pub virtio_mmio: Vec<(HostPhysAddr, usize)>,
+}
+
+impl PlatformInfo {
+    fn parse(fdt: &Fdt, boot_hart_id: usize) -> Self {
+        let mut info = Self {
+            boot_hart_id,
+            timebase_frequency: DEFAULT_TIMEBASE_FREQUENCY,
+            ..Default::default()
+        };
+        for (address, size) in fdt.mem_reservations() {
+            info.reserved.push((address as usize, size as usize));
+        }
+        if let Some(cpus) = fdt.find_node("/cpus") {
+            let freq = cpus
+                .property("timebase-frequency")
+                .and_then(|freq| freq.cells_at(0, freq.value.len() / 4));
+            match freq {
+                Some(0) => warn!("zero timebase-frequency, using the default"),
+                Some(freq) => info.timebase_frequency = freq,
+                None => {}
+            }
+        }
+        if let Some(root) = fdt.root() {
+            info.parse_children(&root);
+        }
+        info
+    }
+
+    fn parse_children(&mut self, parent: &FdtNode) {
+        for node in parent.children() {
+            if !node.is_available() {
+                continue;
+            }
+            let first_reg = || node.reg(parent).next();
+            let device_type = node.property("device_type").and_then(|p| p.as_str());
+            if device_type == Some("memory") {
+                self.memory.extend(node.reg(parent));
+            } else if device_type == Some("cpu") {
+                if let Some((hart_id, _)) = first_reg() {
+                    self.hart_ids.push(hart_id);
+                    if hart_id == self.boot_hart_id {
+                        let isa = node.property("riscv,isa").and_then(|p| p.as_str());
+                        self.boot_hart_isa = isa.unwrap_or_default().into();
+                    }
+                }
+            } else if parent.name() == "reserved-memory" {
+                self.reserved.extend(node.reg(parent));
+            } else if node.is_compatible(UART_COMPATIBLE) {
+                self.uart = self.uart.or(first_reg());
+            } else if node.is_compatible(PLIC_COMPATIBLE) {
+                self.plic = self.plic.or(first_reg());
+            } else if node.is_compatible(VIRTIO_MMIO_COMPATIBLE) {
+                self.virtio_mmio.extend(first_reg());
+            }
+            self.parse_children(&node);
+        }
+    }
+}
+
+/// Parse the device tree at physical address `dtb`. It must run before the
+/// boot page table, which maps it, is replaced.
+pub fn init(boot_hart_id: usize, dtb: HostPhysAddr) {
+    let fdt =
+        unsafe { Fdt::from_ptr(phys_to_virt(dtb) as *const u8) }.expect("invalid device tree blob");
+    PLATFORM_INFO.call_once(|| {
+        let mut info = PlatformInfo::parse(&fdt, boot_hart_id);
+        info.dtb = (dtb, fdt.total_size());
+        info.reserved.push(info.dtb);
+        info
+    });
+}
+
+/// Print the host machine description.
+pub fn print_info() {
+    let info = info();
+    println!(
+        "Platform: boot hart {} ({}), harts {:?}, timebase {} Hz",
+        info.boot_hart_id, info.boot_hart_isa, info.hart_ids, info.timebase_frequency
+    );
+    for &(base, size) in &info.memory {
+        println!("Memory: [{:#x}, {:#x})", base, base + size);
+    }
+    println!("Device tree: {:#x?}", info.dtb);
+    println!("UART: {:#x?}, PLIC: {:#x?}", info.uart, info.plic);
+}
+
+/// The host machine description, available after [`init`].
+pub fn info() -> &'static PlatformInfo {
+    PLATFORM_INFO.get().expect("platform info not initialized")
+}
@@ -1,20 +1,192 @@
 // RISC-V boot code
-// For QEMU, the bootloader sets:
+// The SBI firmware (nanosbi or OpenSBI) enters the kernel in HS-mode, on one
+// hart or on all harts at once, with:
 // - a0 = hart_id
 // - a1 = device_tree_blob
-// - pc = 0x80000000 (default load address)
+// - pc = 0x80200000 (default load address)
+//
+// The kernel is linked at `PHYS_VIRT_OFFSET + load address`, but entered
+// with paging off. `_start` only uses PC-relative addressing until it has
+// enabled a boot page table that maps the kernel at both addresses.
 
 use core::arch::global_asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::config::{BOOT_KERNEL_STACK_SIZE, MAX_CPUS, PHYS_VIRT_OFFSET};
+use crate::mm::address::virt_to_phys;
+use crate::riscv64::{instructions, sbi};
+use crate::timer::{delay, Duration, Instant};
+
+/// How long the boot hart waits for the secondary harts to come online.
+const SECONDARY_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// One boot stack per hart, indexed by hart ID.
+#[link_section = ".bss.stack"]
+static mut BOOT_STACK: [u8; BOOT_KERNEL_STACK_SIZE * MAX_CPUS] =
+    [0; BOOT_KERNEL_STACK_SIZE * MAX_CPUS];
+
+#[link_section = ".data.boot_page_table"]
+static mut BOOT_PT_SV39: [u64; 512] = [0; 512];
+
+/// Taken by the first hart that enters `_start`. Kept out of `.bss`, which
+/// the winner clears while the other harts may be reading it.
+#[link_section = ".data.boot"]
+static BOOT_LOTTERY: AtomicU32 = AtomicU32::new(0);
+
+/// Set by the boot hart to release the harts that lost the boot lottery.
+#[link_section = ".data.boot"]
+static SECONDARY_RELEASE: AtomicU32 = AtomicU32::new(0);
+
+/// Map the first 4G with 1G blocks, both identically and at
+/// `PHYS_VIRT_OFFSET`.
+unsafe extern "C" fn init_boot_page_table() {
+    const FLAGS_VRWXAD: u64 = 0xcf;
+    let pt = &mut *core::ptr::addr_of_mut!(BOOT_PT_SV39);
+    for i in 0..4 {
+        let pte = ((i << 30) >> 2) | FLAGS_VRWXAD;
+        pt[i as usize] = pte;
+        pt[((PHYS_VIRT_OFFSET >> 30) & 0x1ff) + i as usize] = pte;
+    }
+}
+
+unsafe extern "C" fn init_mmu() {
+    let root_paddr = core::ptr::addr_of!(BOOT_PT_SV39) as usize;
+    instructions::activate_sv39(root_paddr);
+}
+
+/// Start the harts in `hart_ids` other than the current one, which enter
+/// `main_secondary` with their hart ID.
+///
+/// Harts that the firmware started at `_start` together with the boot hart
+/// are waiting there, having lost the boot lottery, and are released. With
+/// the SBI HSM extension, the harts still stopped are started here at
+/// `_start_secondary`.
+pub fn start_secondary_cpus(hart_ids: &[usize]) {
+    extern "C" {
+        fn _start_secondary();
+    }
+
+    SECONDARY_RELEASE.store(1, Ordering::Release);
+    let boot_hart_id = crate::percpu::this_cpu_id();
+    if sbi::probe_extension(sbi::EID_HSM) {
+        let start_paddr = virt_to_phys(_start_secondary as usize);
+        for &hart_id in hart_ids.iter().filter(|&&id| id != boot_hart_id) {
+            if hart_id >= MAX_CPUS {
+                warn!("No boot stack for hart {}, not started", hart_id);
+                continue;
+            }
+            let status = sbi::hart_get_status(hart_id);
+            if status.is_ok() && status.value != sbi::HART_STATE_STOPPED {
+                continue;
+            }
+            let ret = sbi::hart_start(hart_id, start_paddr, 0);
+            if !ret.is_ok() {
+                warn!("Failed to start hart {}: {:?}", hart_id, ret);
+            }
+        }
+    }
+
+    // Wait for the started harts to set up their per-CPU area before the
+    // boot hart goes on to create the VM.
+    let expected = hart_ids.iter().filter(|&&id| id < MAX_CPUS).count();
+    let start = Instant::now();
+    while crate::percpu::online_cpus() < expected {
+        if start.elapsed() >= SECONDARY_START_TIMEOUT {
+            warn!(
+                "Only {} of {} CPUs online after {:?}",
+                crate::percpu::online_cpus(),
+                expected,
+                SECONDARY_START_TIMEOUT
+            );
+            break;
+        }
+        delay(Duration::from_millis(1));
+    }
+}
 
 global_asm!(
     r#"
+// Set `sp` to the top of the boot stack of hart `\hart_id`, or park the hart
+// if it has no stack.
+.macro SET_BOOT_STACK hart_id
+    li      t0, {max_cpus}
+    bgeu    \hart_id, t0, park
+    la      sp, {boot_stack}
+    addi    t0, \hart_id, 1
+    li      t1, {boot_stack_size}
+    mul     t0, t0, t1
+    add     sp, sp, t0
+.endm
+
 .section .text.boot
 .globl _start
 _start:
-    // Load the kernel stack pointer
-    la sp, boot_stack_top
+    mv      s0, a0                  // hart id
+    mv      s1, a1                  // device tree blob
+
+    // If the firmware starts all harts here, only the first one boots the
+    // system. The others wait to be released as secondary harts.
+    la      t0, {boot_lottery}
+    li      t1, 1
+    amoswap.w t1, t1, (t0)
+    bnez    t1, wait_for_release
+
+    // Load the boot stack pointer (physical address)
+    SET_BOOT_STACK s0
+
+    call    {init_boot_page_table}
+    call    {init_mmu}
+
+    // Move to the higher half
+    li      s2, {phys_virt_offset}
+    add     sp, sp, s2
 
     // Jump to main
-    j main
-"#
+    mv      a0, s0
+    mv      a1, s1
+    la      a2, {main}
+    add     a2, a2, s2
+    jalr    a2
+    j       .
+
+wait_for_release:
+    la      t0, {secondary_release}
+1:
+    lw      t1, (t0)
+    beqz    t1, 1b
+    fence   r, rw
+    mv      a0, s0
+
+// Entry of secondary harts, started with `a0 = hart id` by SBI HSM.
+.globl _start_secondary
+_start_secondary:
+    mv      s0, a0
+    SET_BOOT_STACK s0
+
+    // The boot page table is already set up by the boot hart
+    call    {init_mmu}
+
+    li      s2, {phys_virt_offset}
+    add     sp, sp, s2
+
+    mv      a0, s0
+    la      a1, {main_secondary}
+    add     a1, a1, s2
+    jalr    a1
+    j       .
+
+park:
+    wfi
+    j       park
+"#,
+    max_cpus = const MAX_CPUS,
+    boot_stack = sym BOOT_STACK,
+    boot_stack_size = const BOOT_KERNEL_STACK_SIZE,
+    boot_lottery = sym BOOT_LOTTERY,
+    secondary_release = sym SECONDARY_RELEASE,
+    init_boot_page_table = sym init_boot_page_table,
+    init_mmu = sym init_mmu,
+    phys_virt_offset = const PHYS_VIRT_OFFSET,
+    main = sym crate::main,
+    main_secondary = sym crate::main_secondary,
 );
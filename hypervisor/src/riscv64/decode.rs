@@ -0,0 +1,313 @@
+//! Decoding of guest loads, stores and AMOs, to emulate accesses to MMIO
+//! regions.
+//!
+//! Only plain integer arithmetic on instruction bits is done here, so that it
+//! does not depend on the machine it runs on.
+
+/// An atomic memory operation of the A extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmoOp {
+    LoadReserved,
+    StoreConditional,
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    MinU,
+    MaxU,
+}
+
+/// What a memory instruction does with its registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemOp {
+    /// Load into `rd`, sign-extended if `signed`.
+    Load { rd: u32, signed: bool },
+    /// Store the low bytes of `rs2`.
+    Store { rs2: u32 },
+    /// Load the old value into `rd` (sign-extended), and store the result of
+    /// `op` on it and `rs2`.
+    Amo { op: AmoOp, rd: u32, rs2: u32 },
+}
+
+/// A decoded integer load, store or AMO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInsn {
+    pub op: MemOp,
+    /// Access width in bytes: 1, 2, 4 or 8.
+    pub width: usize,
+    /// Instruction length in bytes: 2 for compressed instructions, else 4.
+    pub len: usize,
+}
+
+impl MemInsn {
+    /// Whether the instruction writes memory, and so faults as a store.
+    pub fn is_write(&self) -> bool {
+        !matches!(self.op, MemOp::Load { .. })
+    }
+}
+
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_STORE: u32 = 0b010_0011;
+const OPCODE_AMO: u32 = 0b010_1111;
+
+/// Length of the instruction whose low 16 bits are `low`. Instructions longer
+/// than 32 bits are not supported.
+pub fn insn_len(low: u16) -> usize {
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Decode `insn`, a 32-bit instruction or a compressed one in the low 16
+/// bits. Returns `None` if it is not an integer load, store or AMO.
+pub fn decode(insn: u32) -> Option<MemInsn> {
+    match insn_len(insn as u16) {
+        2 => decode_compressed(insn as u16),
+        _ => decode_32(insn, 4),
+    }
+}
+
+/// Decode the transformed instruction that the hardware wrote to `htinst`
+/// on a guest-page fault. It has the encoding of the 32-bit instruction, and
+/// bit 1 clear if the trapping instruction was compressed.
+pub fn decode_transformed(htinst: u32) -> Option<MemInsn> {
+    let len = if htinst & 0b10 != 0 { 4 } else { 2 };
+    decode_32(htinst | 0b10, len)
+}
+
+fn decode_32(insn: u32, len: usize) -> Option<MemInsn> {
+    let rd = (insn >> 7) & 0x1f;
+    let funct3 = (insn >> 12) & 0b111;
+    let rs2 = (insn >> 20) & 0x1f;
+    let (op, width) = match insn & 0x7f {
+        // LB, LH, LW, LD, LBU, LHU, LWU
+        OPCODE_LOAD if funct3 != 0b111 => {
+            let signed = funct3 & 0b100 == 0;
+            (MemOp::Load { rd, signed }, 1 << (funct3 & 0b11))
+        }
+        // SB, SH, SW, SD
+        OPCODE_STORE if funct3 <= 0b011 => (MemOp::Store { rs2 }, 1 << funct3),
+        // *.W and *.D
+        OPCODE_AMO if funct3 == 0b010 || funct3 == 0b011 => {
+            let op = match insn >> 27 {
+                0b00010 if rs2 == 0 => AmoOp::LoadReserved,
+                0b00011 => AmoOp::StoreConditional,
+                0b00001 => AmoOp::Swap,
+                0b00000 => AmoOp::Add,
+                0b00100 => AmoOp::Xor,
+                0b01100 => AmoOp::And,
+                0b01000 => AmoOp::Or,
+                0b10000 => AmoOp::Min,
+                0b10100 => AmoOp::Max,
+                0b11000 => AmoOp::MinU,
+                0b11100 => AmoOp::MaxU,
+                _ => return None,
+            };
+            (MemOp::Amo { op, rd, rs2 }, 1 << funct3)
+        }
+        _ => return None,
+    };
+    Some(MemInsn { op, width, len })
+}
+
+/// Decode the RV64C loads and stores of integer registers.
+fn decode_compressed(insn: u16) -> Option<MemInsn> {
+    let insn = insn as u32;
+    let funct3 = insn >> 13;
+    // Registers x8..x15 encoded in 3 bits.
+    let rd_prime = ((insn >> 2) & 0b111) + 8;
+    let rd = (insn >> 7) & 0x1f;
+    let rs2 = (insn >> 2) & 0x1f;
+    let (op, width) = match (insn & 0b11, funct3) {
+        // C.LW, C.LD
+        (0b00, 0b010) => (
+            MemOp::Load {
+                rd: rd_prime,
+                signed: true,
+            },
+            4,
+        ),
+        (0b00, 0b011) => (
+            MemOp::Load {
+                rd: rd_prime,
+                signed: true,
+            },
+            8,
+        ),
+        // C.SW, C.SD
+        (0b00, 0b110) => (MemOp::Store { rs2: rd_prime }, 4),
+        (0b00, 0b111) => (MemOp::Store { rs2: rd_prime }, 8),
+        // C.LWSP, C.LDSP, with `rd = 0` reserved
+        (0b10, 0b010) if rd != 0 => (MemOp::Load { rd, signed: true }, 4),
+        (0b10, 0b011) if rd != 0 => (MemOp::Load { rd, signed: true }, 8),
+        // C.SWSP, C.SDSP
+        (0b10, 0b110) => (MemOp::Store { rs2 }, 4),
+        (0b10, 0b111) => (MemOp::Store { rs2 }, 8),
+        _ => return None,
+    };
+    Some(MemInsn { op, width, len: 2 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn load(rd: u32, signed: bool, width: usize, len: usize) -> MemInsn {
+        MemInsn {
+            op: MemOp::Load { rd, signed },
+            width,
+            len,
+        }
+    }
+
+    const fn store(rs2: u32, width: usize, len: usize) -> MemInsn {
+        MemInsn {
+            op: MemOp::Store { rs2 },
+            width,
+            len,
+        }
+    }
+
+    const fn amo(op: AmoOp, rd: u32, rs2: u32, width: usize) -> MemInsn {
+        MemInsn {
+            op: MemOp::Amo { op, rd, rs2 },
+            width,
+            len: 4,
+        }
+    }
+
+    fn check(decode: fn(u32) -> Option<MemInsn>, cases: &[(u32, Option<MemInsn>)]) {
+        for &(insn, expected) in cases {
+            assert_eq!(decode(insn), expected, "instruction {:#010x}", insn);
+        }
+    }
+
+    #[test]
+    fn insn_length() {
+        assert_eq!(insn_len(0x4188), 2);
+        assert_eq!(insn_len(0xb503), 4);
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let cases = [
+            (0x01058503, Some(load(10, true, 1, 4))),  // lb a0, 16(a1)
+            (0x01059503, Some(load(10, true, 2, 4))),  // lh a0, 16(a1)
+            (0x0105a503, Some(load(10, true, 4, 4))),  // lw a0, 16(a1)
+            (0x0105b503, Some(load(10, true, 8, 4))),  // ld a0, 16(a1)
+            (0x0105c503, Some(load(10, false, 1, 4))), // lbu a0, 16(a1)
+            (0x0105d503, Some(load(10, false, 2, 4))), // lhu a0, 16(a1)
+            (0x0105e503, Some(load(10, false, 4, 4))), // lwu a0, 16(a1)
+            (0xfec58c23, Some(store(12, 1, 4))),       // sb a2, -8(a1)
+            (0xfec59c23, Some(store(12, 2, 4))),       // sh a2, -8(a1)
+            (0xfec5ac23, Some(store(12, 4, 4))),       // sw a2, -8(a1)
+            (0xfec5bc23, Some(store(12, 8, 4))),       // sd a2, -8(a1)
+        ];
+        check(decode, &cases);
+    }
+
+    #[test]
+    fn compressed() {
+        let cases = [
+            (0x4188, Some(load(10, true, 4, 2))), // c.lw a0, 0(a1)
+            (0x6188, Some(load(10, true, 8, 2))), // c.ld a0, 0(a1)
+            (0xc188, Some(store(10, 4, 2))),      // c.sw a0, 0(a1)
+            (0xe188, Some(store(10, 8, 2))),      // c.sd a0, 0(a1)
+            (0x4502, Some(load(10, true, 4, 2))), // c.lwsp a0, 0(sp)
+            (0x6502, Some(load(10, true, 8, 2))), // c.ldsp a0, 0(sp)
+            (0xc02a, Some(store(10, 4, 2))),      // c.swsp a0, 0(sp)
+            (0xe02a, Some(store(10, 8, 2))),      // c.sdsp a0, 0(sp)
+        ];
+        check(decode, &cases);
+    }
+
+    #[test]
+    fn amos() {
+        let cases = [
+            (0x0cc5a52f, Some(amo(AmoOp::Swap, 10, 12, 4))), // amoswap.w.aq a0, a2, (a1)
+            (0x04c5a52f, Some(amo(AmoOp::Add, 10, 12, 4))),  // amoadd.w.aq a0, a2, (a1)
+            (0x24c5a52f, Some(amo(AmoOp::Xor, 10, 12, 4))),  // amoxor.w.aq a0, a2, (a1)
+            (0x64c5a52f, Some(amo(AmoOp::And, 10, 12, 4))),  // amoand.w.aq a0, a2, (a1)
+            (0x44c5a52f, Some(amo(AmoOp::Or, 10, 12, 4))),   // amoor.w.aq a0, a2, (a1)
+            (0x84c5a52f, Some(amo(AmoOp::Min, 10, 12, 4))),  // amomin.w.aq a0, a2, (a1)
+            (0xa4c5a52f, Some(amo(AmoOp::Max, 10, 12, 4))),  // amomax.w.aq a0, a2, (a1)
+            (0xc4c5a52f, Some(amo(AmoOp::MinU, 10, 12, 4))), // amominu.w.aq a0, a2, (a1)
+            (0xe4c5a52f, Some(amo(AmoOp::MaxU, 10, 12, 4))), // amomaxu.w.aq a0, a2, (a1)
+            (0x1cc5a52f, Some(amo(AmoOp::StoreConditional, 10, 12, 4))), // sc.w.aq a0, a2, (a1)
+            (0x1005a52f, Some(amo(AmoOp::LoadReserved, 10, 0, 4))), // lr.w a0, (a1)
+            (0x0cc5b52f, Some(amo(AmoOp::Swap, 10, 12, 8))), // amoswap.d.aq a0, a2, (a1)
+            (0x04c5b52f, Some(amo(AmoOp::Add, 10, 12, 8))),  // amoadd.d.aq a0, a2, (a1)
+            (0x24c5b52f, Some(amo(AmoOp::Xor, 10, 12, 8))),  // amoxor.d.aq a0, a2, (a1)
+            (0x64c5b52f, Some(amo(AmoOp::And, 10, 12, 8))),  // amoand.d.aq a0, a2, (a1)
+            (0x44c5b52f, Some(amo(AmoOp::Or, 10, 12, 8))),   // amoor.d.aq a0, a2, (a1)
+            (0x84c5b52f, Some(amo(AmoOp::Min, 10, 12, 8))),  // amomin.d.aq a0, a2, (a1)
+            (0xa4c5b52f, Some(amo(AmoOp::Max, 10, 12, 8))),  // amomax.d.aq a0, a2, (a1)
+            (0xc4c5b52f, Some(amo(AmoOp::MinU, 10, 12, 8))), // amominu.d.aq a0, a2, (a1)
+            (0xe4c5b52f, Some(amo(AmoOp::MaxU, 10, 12, 8))), // amomaxu.d.aq a0, a2, (a1)
+            (0x1cc5b52f, Some(amo(AmoOp::StoreConditional, 10, 12, 8))), // sc.d.aq a0, a2, (a1)
+            (0x1005b52f, Some(amo(AmoOp::LoadReserved, 10, 0, 8))), // lr.d a0, (a1)
+        ];
+        check(decode, &cases);
+    }
+
+    /// LR/SC decode as AMOs, for MMIO emulation to reject them.
+    #[test]
+    fn lr_sc() {
+        let lr = decode(0x1005a52f).unwrap(); // lr.w a0, (a1)
+        assert!(matches!(
+            lr.op,
+            MemOp::Amo {
+                op: AmoOp::LoadReserved,
+                ..
+            }
+        ));
+        assert!(lr.is_write());
+        let sc = decode(0x1cc5b52f).unwrap(); // sc.d.aq a0, a2, (a1)
+        assert!(matches!(
+            sc.op,
+            MemOp::Amo {
+                op: AmoOp::StoreConditional,
+                ..
+            }
+        ));
+    }
+
+    /// `htinst` has the 32-bit encoding with the address offset cleared, and
+    /// bit 1 clear for compressed instructions.
+    #[test]
+    fn transformed() {
+        let cases = [
+            (0x00002503, Some(load(10, true, 4, 4))),      // lw a0
+            (0x00002401, Some(load(8, true, 4, 2))),       // c.lw s0
+            (0x00c03023, Some(store(12, 8, 4))),           // sd a2
+            (0x00803021, Some(store(8, 8, 2))),            // c.sd s0
+            (0x40c0352f, Some(amo(AmoOp::Or, 10, 12, 8))), // amoor.d a0, a2
+            (0x00000013, None),                            // not a memory access
+        ];
+        check(decode_transformed, &cases);
+    }
+
+    #[test]
+    fn not_decoded() {
+        let cases = [
+            (0x0005f503, None), // load with funct3 = 0b111
+            (0x00c5c023, None), // store with funct3 = 0b100
+            (0x00c5852f, None), // AMO with funct3 = 0b000
+            (0x28c5a52f, None), // AMO with an unknown funct5
+            (0x10c5a52f, None), // lr.w with rs2 != 0
+            (0x0005a507, None), // flw a0, 0(a1)
+            (0x00150513, None), // addi a0, a0, 1
+            (0x00000073, None), // ecall
+            (0x00000000, None), // illegal (c.addi4spn with imm = 0)
+            (0x4002, None),     // c.lwsp with rd = 0, reserved
+            (0x2188, None),     // c.fld fa0, 0(a1)
+            (0x0505, None),     // c.addi a0, 1
+        ];
+        check(decode, &cases);
+    }
+}
@@ -1,42 +1,108 @@
 use core::arch::asm;
 
-/// RISC-V control and status registers.
-#[repr(u32)]
-#[derive(Debug, Copy, Clone)]
-#[allow(non_camel_case_types)]
-pub enum Csr {
-    MISA = 0x301,
-}
+macro_rules! define_csrs {
+    ($($(#[$attr:meta])* $name:ident = $num:literal => $asm_name:literal,)+) => {
+        /// RISC-V control and status registers.
+        #[repr(u32)]
+        #[derive(Debug, Copy, Clone)]
+        #[allow(non_camel_case_types)]
+        pub enum Csr {
+            $($(#[$attr])* $name = $num,)+
+        }
 
-impl Csr {
-    /// Read 64 bits csr register.
-    #[inline(always)]
-    pub unsafe fn read(self) -> u64 {
-        let value: u64;
-        match self {
-            Csr::MISA => {
-                unsafe {
-                    asm!("csrr {}, misa", out(reg) value);
+        impl Csr {
+            /// Read 64 bits csr register.
+            #[inline(always)]
+            pub unsafe fn read(self) -> u64 {
+                let value: u64;
+                match self {
+                    $(Csr::$name => {
+                        asm!(concat!("csrr {}, ", $asm_name), out(reg) value);
+                    })+
+                }
+                value
+            }
+
+            /// Write 64 bits to csr register.
+            ///
+            /// # Safety
+            ///
+            /// The caller must ensure that this write operation has no unsafe side
+            /// effects.
+            #[inline(always)]
+            pub unsafe fn write(self, value: u64) {
+                match self {
+                    $(Csr::$name => {
+                        asm!(concat!("csrw ", $asm_name, ", {}"), in(reg) value);
+                    })+
+                }
+            }
+
+            /// Set the bits of `mask` in csr register, leaving the others
+            /// unchanged.
+            ///
+            /// # Safety
+            ///
+            /// The caller must ensure that this write operation has no unsafe side
+            /// effects.
+            #[inline(always)]
+            pub unsafe fn set_bits(self, mask: u64) {
+                match self {
+                    $(Csr::$name => {
+                        asm!(concat!("csrs ", $asm_name, ", {}"), in(reg) mask);
+                    })+
                 }
             }
-        }
-        value
-    }
 
-    /// Write 64 bits to csr register.
-    ///
-    /// # Safety
-    ///
-    /// The caller must ensure that this write operation has no unsafe side
-    /// effects.
-    #[inline(always)]
-    pub unsafe fn write(self, value: u64) {
-        match self {
-            Csr::MISA => {
-                asm!("csrw misa, {}", in(reg) value);
+            /// Clear the bits of `mask` in csr register, leaving the others
+            /// unchanged.
+            ///
+            /// # Safety
+            ///
+            /// The caller must ensure that this write operation has no unsafe side
+            /// effects.
+            #[inline(always)]
+            pub unsafe fn clear_bits(self, mask: u64) {
+                match self {
+                    $(Csr::$name => {
+                        asm!(concat!("csrc ", $asm_name, ", {}"), in(reg) mask);
+                    })+
+                }
             }
         }
-    }
+    };
+}
+
+define_csrs! {
+    MISA = 0x301 => "misa",
+
+    // Hypervisor CSRs
+    HSTATUS = 0x600 => "hstatus",
+    HEDELEG = 0x602 => "hedeleg",
+    HIDELEG = 0x603 => "hideleg",
+    HIE = 0x604 => "hie",
+    HTIMEDELTA = 0x605 => "htimedelta",
+    HCOUNTEREN = 0x606 => "hcounteren",
+    HGEIE = 0x607 => "hgeie",
+    HENVCFG = 0x60a => "henvcfg",
+    HTVAL = 0x643 => "htval",
+    HIP = 0x644 => "hip",
+    HVIP = 0x645 => "hvip",
+    HTINST = 0x64a => "htinst",
+    HGATP = 0x680 => "hgatp",
+    HGEIP = 0xe12 => "hgeip",
+
+    // Virtual supervisor CSRs
+    VSSTATUS = 0x200 => "vsstatus",
+    VSIE = 0x204 => "vsie",
+    VSTVEC = 0x205 => "vstvec",
+    VSSCRATCH = 0x240 => "vsscratch",
+    VSEPC = 0x241 => "vsepc",
+    VSCAUSE = 0x242 => "vscause",
+    VSTVAL = 0x243 => "vstval",
+    VSIP = 0x244 => "vsip",
+    VSTIMECMP = 0x24d => "vstimecmp",
+    VSATP = 0x280 => "vsatp",
 }
 
 pub(super) trait CsrReadWrite {
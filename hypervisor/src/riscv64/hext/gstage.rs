@@ -0,0 +1,162 @@
+//! G-stage (guest physical to host physical) page tables.
+
+use super::csr::Csr;
+use crate::hv::{GuestPhysAddr, HostPhysAddr};
+use crate::mm::page_table::{PageSize, PageTable64, PagingMetaData, PagingResult};
+use crate::mm::MemFlags;
+use crate::riscv64::instructions;
+use crate::riscv64::page_table::Rv64PTE;
+
+/// Paging modes usable for G-stage translation.
+pub trait GStageMode: PagingMetaData {
+    /// Value of the `hgatp.MODE` field.
+    const HGATP_MODE: u64;
+}
+
+/// Sv39x4: 41-bit guest physical addresses, 3 levels.
+pub struct Sv39x4;
+
+impl PagingMetaData for Sv39x4 {
+    const LEVELS: usize = 3;
+    const VA_MAX_BITS: usize = 41;
+    const ROOT_PAGES: usize = 4;
+}
+
+impl GStageMode for Sv39x4 {
+    const HGATP_MODE: u64 = 8;
+}
+
+/// Sv48x4: 50-bit guest physical addresses, 4 levels.
+pub struct Sv48x4;
+
+impl PagingMetaData for Sv48x4 {
+    const LEVELS: usize = 4;
+    const VA_MAX_BITS: usize = 50;
+    const ROOT_PAGES: usize = 4;
+}
+
+impl GStageMode for Sv48x4 {
+    const HGATP_MODE: u64 = 9;
+}
+
+const HGATP_VMID_SHIFT: u64 = 44;
+const HGATP_VMID_MASK: u64 = (1 << 14) - 1;
+const HGATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+/// A G-stage page table. The root is 16 KiB in size and alignment, as
+/// required by the "x4" modes.
+///
+/// Changes to mappings are followed by the matching `hfence.gvma` on the
+/// current hart, limited to the VMID set by [`set_vmid`](Self::set_vmid) or
+/// [`install`](Self::install) if any. The page table frames, including the
+/// intermediate tables, are freed on drop, after the translations of the
+/// VMID are flushed.
+pub struct GStagePageTable<M: GStageMode> {
+    inner: PageTable64<M, Rv64PTE>,
+    vmid: Option<usize>,
+}
+
+impl<M: GStageMode> GStagePageTable<M> {
+    pub fn new() -> PagingResult<Self> {
+        Ok(Self {
+            inner: PageTable64::new()?,
+            vmid: None,
+        })
+    }
+
+    pub fn root_paddr(&self) -> HostPhysAddr {
+        self.inner.root_paddr()
+    }
+
+    /// The `hgatp` value that selects this table for the VM with `vmid`.
+    pub fn hgatp(&self, vmid: usize) -> u64 {
+        (M::HGATP_MODE << 60)
+            | ((vmid as u64 & HGATP_VMID_MASK) << HGATP_VMID_SHIFT)
+            | ((self.root_paddr() as u64 >> 12) & HGATP_PPN_MASK)
+    }
+
+    /// Tag this table with `vmid`, so that fences are limited to it.
+    pub fn set_vmid(&mut self, vmid: usize) {
+        self.vmid = Some(vmid);
+    }
+
+    /// Install this table into `hgatp` on the current hart, tagged with
+    /// `vmid`, and drop any stale translations of that VMID.
+    pub fn install(&mut self, vmid: usize) {
+        unsafe { Csr::HGATP.write(self.hgatp(vmid)) };
+        instructions::hfence_gvma_vmid(vmid);
+        self.vmid = Some(vmid);
+    }
+
+    pub fn map(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        page_size: PageSize,
+        flags: MemFlags,
+    ) -> PagingResult {
+        self.inner
+            .map(gpa, hpa, page_size, flags | MemFlags::USER)?;
+        self.flush(Some(gpa));
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, gpa: GuestPhysAddr) -> PagingResult<(HostPhysAddr, PageSize)> {
+        let ret = self.inner.unmap(gpa)?;
+        self.flush(Some(gpa));
+        Ok(ret)
+    }
+
+    pub fn protect(&mut self, gpa: GuestPhysAddr, flags: MemFlags) -> PagingResult<PageSize> {
+        let ret = self.inner.protect(gpa, flags | MemFlags::USER)?;
+        self.flush(Some(gpa));
+        Ok(ret)
+    }
+
+    pub fn query(&self, gpa: GuestPhysAddr) -> PagingResult<(HostPhysAddr, MemFlags, PageSize)> {
+        let (hpa, flags, size) = self.inner.query(gpa)?;
+        Ok((hpa, flags - MemFlags::USER, size))
+    }
+
+    pub fn map_region(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        size: usize,
+        flags: MemFlags,
+        allow_huge: bool,
+    ) -> PagingResult {
+        let ret = self
+            .inner
+            .map_region(gpa, hpa, size, flags | MemFlags::USER, allow_huge);
+        // Also after a partial mapping.
+        self.flush(None);
+        ret
+    }
+
+    pub fn unmap_region(&mut self, gpa: GuestPhysAddr, size: usize) -> PagingResult {
+        self.inner.unmap_region(gpa, size)?;
+        self.flush(None);
+        Ok(())
+    }
+
+    pub fn dump(&self) {
+        self.inner.dump()
+    }
+
+    fn flush(&self, gpa: Option<GuestPhysAddr>) {
+        match (gpa, self.vmid) {
+            (Some(gpa), Some(vmid)) => instructions::hfence_gvma(gpa, vmid),
+            (Some(gpa), None) => instructions::hfence_gvma_addr(gpa),
+            (None, Some(vmid)) => instructions::hfence_gvma_vmid(vmid),
+            (None, None) => instructions::hfence_gvma_all(),
+        }
+    }
+}
+
+impl<M: GStageMode> Drop for GStagePageTable<M> {
+    fn drop(&mut self) {
+        // No cached translation may point into the tables freed by `inner`.
+        self.flush(None);
+    }
+}
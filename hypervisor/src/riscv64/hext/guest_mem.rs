@@ -0,0 +1,119 @@
+//! Access to guest virtual memory from HS-mode.
+//!
+//! `hlv`, `hsv` and `hlvx` access memory as the guest does, through `vsatp`
+//! and `hgatp`, with the privilege in `hstatus.SPVP`. A fault they raise is
+//! caught by pointing `stvec` at a fixup for the duration of the access, with
+//! interrupts disabled, and returned as the `scause` value.
+
+use core::arch::asm;
+
+use crate::riscv64::instructions;
+
+/// Define a function that runs one hypervisor load or store at `gva`.
+macro_rules! guest_access {
+    ($(#[$attr:meta])* fn $name:ident($insn:literal) -> $ty:ty) => {
+        $(#[$attr])*
+        fn $name(gva: usize) -> Result<$ty, usize> {
+            let (value, scause) = instructions::with_irqs_disabled(|| unsafe {
+                let (value, scause): (usize, usize);
+                asm!(
+                    ".option push",
+                    ".option arch, +h",
+                    "la     {stvec}, 1f",
+                    "csrrw  {stvec}, stvec, {stvec}",
+                    "li     {scause}, 0",
+                    concat!($insn, " {value}, ({gva})"),
+                    "j      2f",
+                    ".align 2",
+                    "1:",
+                    "csrr   {scause}, scause",
+                    "2:",
+                    "csrw   stvec, {stvec}",
+                    ".option pop",
+                    gva = in(reg) gva,
+                    value = out(reg) value,
+                    stvec = out(reg) _,
+                    scause = out(reg) scause,
+                );
+                (value, scause)
+            });
+            match scause {
+                0 => Ok(value as $ty),
+                _ => Err(scause),
+            }
+        }
+    };
+}
+
+guest_access!(
+    /// Load a byte with `hlv.bu`.
+    fn hlv_bu("hlv.bu") -> u8
+);
+guest_access!(
+    /// Load an instruction halfword with `hlvx.hu`, which needs execute
+    /// rather than read permission.
+    fn hlvx_hu("hlvx.hu") -> u16
+);
+
+/// Store a byte with `hsv.b`.
+fn hsv_b(gva: usize, value: u8) -> Result<(), usize> {
+    let scause = instructions::with_irqs_disabled(|| unsafe {
+        let scause: usize;
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "la     {stvec}, 1f",
+            "csrrw  {stvec}, stvec, {stvec}",
+            "li     {scause}, 0",
+            "hsv.b  {value}, ({gva})",
+            "j      2f",
+            ".align 2",
+            "1:",
+            "csrr   {scause}, scause",
+            "2:",
+            "csrw   stvec, {stvec}",
+            ".option pop",
+            gva = in(reg) gva,
+            value = in(reg) value,
+            stvec = out(reg) _,
+            scause = out(reg) scause,
+        );
+        scause
+    });
+    match scause {
+        0 => Ok(()),
+        _ => Err(scause),
+    }
+}
+
+/// Copy guest memory at `gva` into `buf`. On a fault, returns the faulting
+/// address and `scause`.
+pub(super) fn read_bytes(gva: usize, buf: &mut [u8]) -> Result<(), (usize, usize)> {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let addr = gva.wrapping_add(i);
+        *byte = hlv_bu(addr).map_err(|scause| (addr, scause))?;
+    }
+    Ok(())
+}
+
+/// Copy `data` into guest memory at `gva`. On a fault, returns the faulting
+/// address and `scause`, the bytes before it are written.
+pub(super) fn write_bytes(gva: usize, data: &[u8]) -> Result<(), (usize, usize)> {
+    for (i, &byte) in data.iter().enumerate() {
+        let addr = gva.wrapping_add(i);
+        hsv_b(addr, byte).map_err(|scause| (addr, scause))?;
+    }
+    Ok(())
+}
+
+/// Fetch the instruction at `gva`, a compressed one in the low 16 bits. On a
+/// fault, returns the faulting address and `scause`.
+pub(super) fn fetch_insn(gva: usize) -> Result<u32, (usize, usize)> {
+    let low = hlvx_hu(gva).map_err(|scause| (gva, scause))?;
+    if crate::riscv64::decode::insn_len(low) == 2 {
+        return Ok(low as u32);
+    }
+    let addr = gva.wrapping_add(2);
+    let high = hlvx_hu(addr).map_err(|scause| (addr, scause))?;
+    Ok((high as u32) << 16 | low as u32)
+}
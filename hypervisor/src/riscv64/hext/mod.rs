@@ -1,41 +1,153 @@
 mod csr;
+mod gstage;
+mod guest_mem;
 mod structs;
+mod vcpu;
+mod vmexit;
+mod vtimer;
 
-pub use structs::{HextRegion, MachineISA, MachineISAFlags, PhysFrame};
+pub use gstage::{GStageMode, GStagePageTable, Sv39x4, Sv48x4};
+pub use structs::{MachineISA, MachineISAFlags, PhysFrame, PhysFrameRange};
+pub use vcpu::{GeneralPurposeRegisters, GprIndex, GuestVsCsrs, HextVcpu};
+pub use vmexit::{GuestAccessType, HextExitInfo, RvmExitReason};
+pub use vtimer::{has_sstc, VirtualTimer};
 
+use core::arch::asm;
+
+use self::structs::{
+    Hcounteren, HcounterenFlags, Hedeleg, HedelegFlags, Hgatp, HgatpFlags, Hideleg, HidelegFlags,
+    Hvip, HvipFlags,
+};
 use crate::hv::RvmResult;
+use crate::riscv64::instructions;
 use crate::rvm_err;
 
+/// Check whether the H extension is implemented.
+///
+/// `misa` is only accessible from M-mode, so probe for it by reading
+/// `hstatus` with `stvec` temporarily pointing just past the read: an
+/// illegal-instruction trap then skips the line that reports success.
 pub fn has_hardware_support() -> bool {
-    MachineISA::read().contains(MachineISAFlags::H)
+    let irqs_disabled = instructions::irqs_disabled();
+    instructions::disable_irqs();
+    let supported: usize;
+    unsafe {
+        asm!(
+            "la     {stvec}, 1f",
+            "csrrw  {stvec}, stvec, {stvec}",
+            "li     {supported}, 0",
+            "csrr   {tmp}, hstatus",
+            "li     {supported}, 1",
+            ".align 2",
+            "1:",
+            "csrw   stvec, {stvec}",
+            stvec = out(reg) _,
+            supported = out(reg) supported,
+            tmp = out(reg) _,
+        );
+    }
+    if !irqs_disabled {
+        instructions::enable_irqs();
+    }
+    supported != 0
+}
+
+/// ISA extensions of the host that can be exposed to guests.
+///
+/// `misa` cannot be read from HS-mode, so they come from the `riscv,isa` of
+/// the boot hart in the host device tree. The H extension is left out, as
+/// guests cannot run guests of their own.
+pub fn host_isa() -> MachineISAFlags {
+    let isa = &crate::platform::info().boot_hart_isa;
+    let flags = MachineISAFlags::from_isa_string(isa);
+    if !flags.contains(MachineISAFlags::I) {
+        warn!("unrecognized host ISA {:?}", isa);
+    }
+    (flags - MachineISAFlags::H) | MachineISAFlags::I | MachineISAFlags::S | MachineISAFlags::U
 }
 
+/// Exceptions that VS-mode handles itself. Guest-page faults, virtual
+/// instructions and VS-mode `ecall`s always trap to HS-mode.
+const DEFAULT_HEDELEG: HedelegFlags = HedelegFlags::from_bits_truncate(
+    HedelegFlags::INST_ADDR_MISALIGNED.bits()
+        | HedelegFlags::BREAKPOINT.bits()
+        | HedelegFlags::ECALL_FROM_U.bits()
+        | HedelegFlags::INST_PAGE_FAULT.bits()
+        | HedelegFlags::LOAD_PAGE_FAULT.bits()
+        | HedelegFlags::STORE_PAGE_FAULT.bits(),
+);
+
 pub struct HextPerCpuState {
-    hext_region: HextRegion,
+    enabled: bool,
+    vmid_bits: usize,
 }
 
 impl HextPerCpuState {
     pub const fn new() -> Self {
         Self {
-            hext_region: unsafe { HextRegion::uninit() },
+            enabled: false,
+            vmid_bits: 0,
         }
     }
 
     pub fn is_enabled(&self) -> bool {
-        false
+        self.enabled
+    }
+
+    /// Number of implemented VMID bits in `hgatp`, valid once enabled.
+    pub fn vmid_bits(&self) -> usize {
+        self.vmid_bits
     }
 
     pub fn hardware_enable(&mut self) -> RvmResult {
         if !has_hardware_support() {
             return rvm_err!(Unsupported, "CPU does not support feature H-Ext");
         }
+        if self.is_enabled() {
+            return rvm_err!(ResourceBusy, "H-Ext is already turned on");
+        }
+
+        // Delegate guest-handled traps to VS-mode, with no virtual
+        // interrupts pending.
+        Hedeleg::write(DEFAULT_HEDELEG);
+        Hideleg::write(HidelegFlags::VSSI | HidelegFlags::VSTI | HidelegFlags::VSEI);
+        Hvip::write(HvipFlags::empty());
+        Hcounteren::write(HcounterenFlags::CY | HcounterenFlags::TM | HcounterenFlags::IR);
+
+        // The VMID field is WARL, writing all ones leaves only the
+        // implemented bits set.
+        Hgatp::write(HgatpFlags::VMID);
+        let vmid_mask = (Hgatp::read() & HgatpFlags::VMID).bits();
+        Hgatp::write(HgatpFlags::empty());
+        instructions::hfence_gvma_all();
 
-        info!("[RVM] successed to turn on H-Ext.");
+        let sstc = vtimer::enable_sstc();
+
+        self.vmid_bits = vmid_mask.count_ones() as usize;
+        self.enabled = true;
+        info!(
+            "[RVM] successed to turn on H-Ext, {} VMID bits, Sstc {}.",
+            self.vmid_bits, sstc
+        );
 
         Ok(())
     }
 
     pub fn hardware_disable(&mut self) -> RvmResult {
+        if !self.is_enabled() {
+            return rvm_err!(BadState, "H-Ext is not enabled");
+        }
+
+        Hgatp::write(HgatpFlags::empty());
+        instructions::hfence_gvma_all();
+        Hedeleg::write(HedelegFlags::empty());
+        Hideleg::write(HidelegFlags::empty());
+        Hvip::write(HvipFlags::empty());
+        Hcounteren::write(HcounterenFlags::empty());
+        vtimer::disable_sstc();
+
+        self.vmid_bits = 0;
+        self.enabled = false;
         info!("[RVM] successed to turn off H-Ext.");
 
         Ok(())
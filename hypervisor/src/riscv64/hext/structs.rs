@@ -1,19 +1,22 @@
+use alloc::string::String;
 use bit_field::BitField;
 use bitflags::bitflags;
 
-use crate::riscv64::hext::csr::{Csr, CsrReadWrite};
-use crate::hv::RvmResult;
 use crate::hv::HostPhysAddr;
+use crate::hv::RvmResult;
 use crate::mm::frame;
-use crate::rvm_err_type;
+use crate::riscv64::hext::csr::{Csr, CsrReadWrite};
+use crate::{rvm_err, rvm_err_type};
 
 /// A 4K-sized contiguous physical memory page, it will deallocate the page
 /// automatically on drop.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct PhysFrame {
     start_paddr: HostPhysAddr,
 }
 
+#[allow(dead_code)]
 impl PhysFrame {
     pub fn alloc() -> RvmResult<Self> {
         let start_paddr = unsafe { frame::alloc_page() }
@@ -55,44 +58,146 @@ impl Drop for PhysFrame {
     }
 }
 
+/// `count` contiguous physical pages, whose start address is aligned to
+/// `PAGE_SIZE << align_log2`. They will be deallocated automatically on drop.
 #[derive(Debug)]
-pub struct HextRegion {
-    frame: PhysFrame,
+pub struct PhysFrameRange {
+    start_paddr: HostPhysAddr,
+    count: usize,
 }
 
-impl HextRegion {
-    pub const unsafe fn uninit() -> Self {
-        Self {
-            frame: PhysFrame::uninit(),
+impl PhysFrameRange {
+    pub fn alloc(count: usize, align_log2: usize) -> RvmResult<Self> {
+        if count == 0 {
+            return rvm_err!(InvalidParam, "allocate zero physical frames");
         }
+        let start_paddr = unsafe { frame::alloc_contiguous(count, align_log2) }
+            .ok_or_else(|| rvm_err_type!(OutOfMemory, "allocate physical frames failed"))?;
+        assert_ne!(start_paddr, 0);
+        debug!(
+            "[RVM] allocated PhysFrameRange({:#x}, {} pages)",
+            start_paddr, count
+        );
+        Ok(Self { start_paddr, count })
+    }
+
+    pub fn alloc_zero(count: usize, align_log2: usize) -> RvmResult<Self> {
+        let mut f = Self::alloc(count, align_log2)?;
+        f.fill(0);
+        Ok(f)
+    }
+
+    pub fn start_paddr(&self) -> HostPhysAddr {
+        self.start_paddr
+    }
+
+    /// Size of the range in bytes.
+    pub fn size(&self) -> usize {
+        self.count * crate::mm::PAGE_SIZE
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        crate::mm::address::phys_to_virt(self.start_paddr) as *mut u8
     }
 
-    pub fn new() -> RvmResult<Self> {
-        let frame = PhysFrame::alloc_zero()?;
-        Ok(Self { frame })
+    pub fn fill(&mut self, byte: u8) {
+        unsafe { core::ptr::write_bytes(self.as_mut_ptr(), byte, self.size()) }
     }
+}
 
-    pub fn phys_addr(&self) -> HostPhysAddr {
-        self.frame.start_paddr()
+impl Drop for PhysFrameRange {
+    fn drop(&mut self) {
+        unsafe { frame::dealloc_contiguous(self.start_paddr, self.count) };
+        debug!(
+            "[RVM] deallocated PhysFrameRange({:#x}, {} pages)",
+            self.start_paddr, self.count
+        );
     }
 }
 
 bitflags! {
-    /// RISC-V Machine ISA flags
-    /// We only care about H-extension for now
+    /// RISC-V Machine ISA flags, one bit per single-letter extension
     pub struct MachineISAFlags: u64 {
+        /// Atomic extension
+        const A = 1 << 0;
+        /// Compressed extension
+        const C = 1 << 2;
+        /// Double-precision floating-point extension
+        const D = 1 << 3;
+        /// Single-precision floating-point extension
+        const F = 1 << 5;
         /// Hypervisor extension
         const H = 1 << 7;
+        /// RV32I/64I/128I base ISA
+        const I = 1 << 8;
+        /// Integer multiply/divide extension
+        const M = 1 << 12;
+        /// Supervisor mode implemented
+        const S = 1 << 18;
+        /// User mode implemented
+        const U = 1 << 20;
+        /// Vector extension
+        const V = 1 << 21;
+    }
+}
+
+impl MachineISAFlags {
+    /// The ISA string used by the `riscv,isa` device tree property, like
+    /// `rv64imafdc`. S and U are privilege modes, not ISA extensions, so they
+    /// are left out.
+    pub fn isa_string(&self) -> String {
+        const CANONICAL_ORDER: &[(MachineISAFlags, char)] = &[
+            (MachineISAFlags::I, 'i'),
+            (MachineISAFlags::M, 'm'),
+            (MachineISAFlags::A, 'a'),
+            (MachineISAFlags::F, 'f'),
+            (MachineISAFlags::D, 'd'),
+            (MachineISAFlags::C, 'c'),
+            (MachineISAFlags::V, 'v'),
+            (MachineISAFlags::H, 'h'),
+        ];
+        let mut isa = String::from("rv64");
+        for &(flag, letter) in CANONICAL_ORDER {
+            if self.contains(flag) {
+                isa.push(letter);
+            }
+        }
+        isa
+    }
+
+    /// Parse the single-letter extensions of a `riscv,isa` string, like
+    /// `rv64imafdch_zicsr_zifencei`. Multi-letter extensions and unknown
+    /// letters are ignored.
+    pub fn from_isa_string(isa: &str) -> Self {
+        let isa = isa.to_ascii_lowercase();
+        let Some(base) = isa
+            .strip_prefix("rv64")
+            .or_else(|| isa.strip_prefix("rv32"))
+        else {
+            return Self::empty();
+        };
+        let mut flags = Self::empty();
+        for letter in base.split('_').next().unwrap_or_default().bytes() {
+            flags |= match letter {
+                // G is IMAFD with Zicsr and Zifencei.
+                b'g' => Self::I | Self::M | Self::A | Self::F | Self::D,
+                b'a'..=b'z' => Self::from_bits_truncate(1 << (letter - b'a')),
+                _ => Self::empty(),
+            };
+        }
+        flags
     }
 }
 
 /// Machine ISA in RISC-V Processor
+#[allow(dead_code)]
 pub struct MachineISA;
 
 impl CsrReadWrite for MachineISA {
     const CSR: Csr = Csr::MISA;
 }
 
+#[allow(dead_code)]
 impl MachineISA {
     pub fn read() -> MachineISAFlags {
         MachineISAFlags::from_bits_truncate(unsafe { Self::read_raw() })
@@ -107,3 +212,376 @@ impl MachineISA {
         }
     }
 }
+
+/// Define a typed accessor for a CSR whose fields are described by a
+/// bitflags type, in the same way as [`MachineISA`]. Bits not covered by the
+/// flags are preserved on write.
+macro_rules! csr_with_flags {
+    ($(#[$attr:meta])* $name:ident: $flags:ident = $csr:ident) => {
+        $(#[$attr])*
+        pub struct $name;
+
+        impl CsrReadWrite for $name {
+            const CSR: Csr = Csr::$csr;
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            /// Read the register. Bits not covered by the flags are kept, so
+            /// that WARL fields survive a read-modify-write.
+            pub fn read() -> $flags {
+                // `bitflags` 1.x has no `from_bits_retain`.
+                unsafe { $flags::from_bits_unchecked(Self::read_raw()) }
+            }
+
+            pub fn write(flags: $flags) {
+                let old_value = unsafe { Self::read_raw() };
+                let reserved = old_value & !($flags::all().bits());
+                let new_value = reserved | flags.bits();
+                unsafe {
+                    Self::write_raw(new_value);
+                }
+            }
+
+            /// Set `flags`, leaving the other bits unchanged.
+            pub fn set(flags: $flags) {
+                unsafe { Self::CSR.set_bits(flags.bits()) }
+            }
+
+            /// Clear `flags`, leaving the other bits unchanged.
+            pub fn clear(flags: $flags) {
+                unsafe { Self::CSR.clear_bits(flags.bits()) }
+            }
+        }
+    };
+}
+
+/// Define a typed accessor for a CSR that holds a plain value, like an
+/// address or a counter.
+macro_rules! csr_with_value {
+    ($(#[$attr:meta])* $name:ident = $csr:ident) => {
+        csr_with_value!($(#[$attr])* $name = $csr, read_only);
+
+        #[allow(dead_code)]
+        impl $name {
+            pub fn write(value: u64) {
+                unsafe { Self::write_raw(value) }
+            }
+        }
+    };
+    ($(#[$attr:meta])* $name:ident = $csr:ident, read_only) => {
+        $(#[$attr])*
+        pub struct $name;
+
+        impl CsrReadWrite for $name {
+            const CSR: Csr = Csr::$csr;
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            pub fn read() -> u64 {
+                unsafe { Self::read_raw() }
+            }
+        }
+    };
+}
+
+bitflags! {
+    /// Hypervisor status register (`hstatus`) fields.
+    pub struct HstatusFlags: u64 {
+        /// Endianness of explicit VS-mode memory accesses is big-endian.
+        const VSBE = 1 << 5;
+        /// `stval` holds a guest virtual address on trap.
+        const GVA = 1 << 6;
+        /// The trap was taken from virtualized mode, `sret` returns to V=1.
+        const SPV = 1 << 7;
+        /// Privilege of HLV/HSV accesses, set for VS-mode and clear for VU-mode.
+        const SPVP = 1 << 8;
+        /// HLV/HSV/HFENCE are allowed in U-mode.
+        const HU = 1 << 9;
+        /// Guest external interrupt number routed to VS-level external
+        /// interrupts.
+        const VGEIN = 0x3f << 12;
+        /// `sfence.vma` and `satp` accesses in VS-mode raise
+        /// virtual-instruction exceptions.
+        const VTVM = 1 << 20;
+        /// `wfi` in VS-mode raises a virtual-instruction exception.
+        const VTW = 1 << 21;
+        /// `sret` in VS-mode raises a virtual-instruction exception.
+        const VTSR = 1 << 22;
+        /// Effective XLEN of VS-mode.
+        const VSXL = 0b11 << 32;
+    }
+}
+
+bitflags! {
+    /// Hypervisor exception delegation register (`hedeleg`) fields. Each bit
+    /// delegates the corresponding exception to VS-mode.
+    pub struct HedelegFlags: u64 {
+        const INST_ADDR_MISALIGNED = 1 << 0;
+        const INST_ACCESS_FAULT = 1 << 1;
+        const ILLEGAL_INST = 1 << 2;
+        const BREAKPOINT = 1 << 3;
+        const LOAD_ADDR_MISALIGNED = 1 << 4;
+        const LOAD_ACCESS_FAULT = 1 << 5;
+        const STORE_ADDR_MISALIGNED = 1 << 6;
+        const STORE_ACCESS_FAULT = 1 << 7;
+        const ECALL_FROM_U = 1 << 8;
+        const INST_PAGE_FAULT = 1 << 12;
+        const LOAD_PAGE_FAULT = 1 << 13;
+        const STORE_PAGE_FAULT = 1 << 15;
+    }
+}
+
+bitflags! {
+    /// Hypervisor interrupt delegation register (`hideleg`) fields. Each bit
+    /// delegates the corresponding VS-level interrupt to VS-mode.
+    pub struct HidelegFlags: u64 {
+        const VSSI = 1 << 2;
+        const VSTI = 1 << 6;
+        const VSEI = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// Hypervisor virtual interrupt pending register (`hvip`) fields, used to
+    /// inject VS-level interrupts.
+    pub struct HvipFlags: u64 {
+        const VSSIP = 1 << 2;
+        const VSTIP = 1 << 6;
+        const VSEIP = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// Hypervisor interrupt pending register (`hip`) fields.
+    pub struct HipFlags: u64 {
+        const VSSIP = 1 << 2;
+        const VSTIP = 1 << 6;
+        const VSEIP = 1 << 10;
+        const SGEIP = 1 << 12;
+    }
+}
+
+bitflags! {
+    /// Hypervisor interrupt enable register (`hie`) fields.
+    pub struct HieFlags: u64 {
+        const VSSIE = 1 << 2;
+        const VSTIE = 1 << 6;
+        const VSEIE = 1 << 10;
+        const SGEIE = 1 << 12;
+    }
+}
+
+bitflags! {
+    /// Hypervisor counter-enable register (`hcounteren`) fields, which
+    /// counters VS-mode may read.
+    pub struct HcounterenFlags: u64 {
+        const CY = 1 << 0;
+        const TM = 1 << 1;
+        const IR = 1 << 2;
+        const HPM = 0xffff_fff8;
+    }
+}
+
+bitflags! {
+    /// Hypervisor environment configuration register (`henvcfg`) fields.
+    pub struct HenvcfgFlags: u64 {
+        /// Fence of I/O implies memory.
+        const FIOM = 1 << 0;
+        /// Cache block invalidate instruction enable.
+        const CBIE = 0b11 << 4;
+        /// Cache block clean and flush instruction enable.
+        const CBCFE = 1 << 6;
+        /// Cache block zero instruction enable.
+        const CBZE = 1 << 7;
+        /// Hardware A/D bit updating for VS-stage page tables.
+        const ADUE = 1 << 61;
+        /// Page-based memory types for VS-stage page tables.
+        const PBMTE = 1 << 62;
+        /// `vstimecmp` is enabled (Sstc).
+        const STCE = 1 << 63;
+    }
+}
+
+bitflags! {
+    /// Hypervisor guest address translation and protection register
+    /// (`hgatp`) fields.
+    pub struct HgatpFlags: u64 {
+        /// Physical page number of the root G-stage page table.
+        const PPN = (1 << 44) - 1;
+        /// Virtual machine identifier.
+        const VMID = 0x3fff << 44;
+        /// Translation mode: 0 for Bare, 8 for Sv39x4, 9 for Sv48x4.
+        const MODE = 0xf << 60;
+    }
+}
+
+bitflags! {
+    /// Virtual supervisor status register (`vsstatus`) fields.
+    pub struct VsstatusFlags: u64 {
+        const SIE = 1 << 1;
+        const SPIE = 1 << 5;
+        const UBE = 1 << 6;
+        const SPP = 1 << 8;
+        const VS = 0b11 << 9;
+        const FS = 0b11 << 13;
+        const XS = 0b11 << 15;
+        const SUM = 1 << 18;
+        const MXR = 1 << 19;
+        const UXL = 0b11 << 32;
+        const SD = 1 << 63;
+    }
+}
+
+bitflags! {
+    /// Virtual supervisor interrupt pending register (`vsip`) fields, with
+    /// VS-level interrupts shown at their S-level positions.
+    pub struct VsipFlags: u64 {
+        const SSIP = 1 << 1;
+        const STIP = 1 << 5;
+        const SEIP = 1 << 9;
+    }
+}
+
+bitflags! {
+    /// Virtual supervisor interrupt enable register (`vsie`) fields, with
+    /// VS-level interrupts shown at their S-level positions.
+    pub struct VsieFlags: u64 {
+        const SSIE = 1 << 1;
+        const STIE = 1 << 5;
+        const SEIE = 1 << 9;
+    }
+}
+
+bitflags! {
+    /// Virtual supervisor trap vector register (`vstvec`) fields.
+    pub struct VstvecFlags: u64 {
+        /// Vectored mode, otherwise all traps go to `BASE`.
+        const MODE_VECTORED = 1 << 0;
+        /// Trap vector base address, 4-byte aligned.
+        const BASE = !0b11;
+    }
+}
+
+bitflags! {
+    /// Virtual supervisor cause register (`vscause`) fields.
+    pub struct VscauseFlags: u64 {
+        /// The trap was caused by an interrupt.
+        const INTERRUPT = 1 << 63;
+        /// Exception code.
+        const CODE = !(1 << 63);
+    }
+}
+
+bitflags! {
+    /// Virtual supervisor address translation and protection register
+    /// (`vsatp`) fields.
+    pub struct VsatpFlags: u64 {
+        /// Physical page number of the root VS-stage page table, as a guest
+        /// physical address.
+        const PPN = (1 << 44) - 1;
+        /// Address space identifier.
+        const ASID = 0xffff << 44;
+        /// Translation mode: 0 for Bare, 8 for Sv39, 9 for Sv48.
+        const MODE = 0xf << 60;
+    }
+}
+
+csr_with_flags!(
+    /// Hypervisor status register.
+    Hstatus: HstatusFlags = HSTATUS
+);
+csr_with_flags!(
+    /// Hypervisor exception delegation register.
+    Hedeleg: HedelegFlags = HEDELEG
+);
+csr_with_flags!(
+    /// Hypervisor interrupt delegation register.
+    Hideleg: HidelegFlags = HIDELEG
+);
+csr_with_flags!(
+    /// Hypervisor virtual interrupt pending register.
+    Hvip: HvipFlags = HVIP
+);
+csr_with_flags!(
+    /// Hypervisor interrupt pending register.
+    Hip: HipFlags = HIP
+);
+csr_with_flags!(
+    /// Hypervisor interrupt enable register.
+    Hie: HieFlags = HIE
+);
+csr_with_flags!(
+    /// Hypervisor counter-enable register.
+    Hcounteren: HcounterenFlags = HCOUNTEREN
+);
+csr_with_flags!(
+    /// Hypervisor environment configuration register.
+    Henvcfg: HenvcfgFlags = HENVCFG
+);
+csr_with_flags!(
+    /// Hypervisor guest address translation and protection register.
+    Hgatp: HgatpFlags = HGATP
+);
+csr_with_value!(
+    /// Hypervisor guest external interrupt pending register, one bit per
+    /// guest external interrupt number.
+    Hgeip = HGEIP, read_only
+);
+csr_with_value!(
+    /// Hypervisor guest external interrupt enable register, one bit per
+    /// guest external interrupt number.
+    Hgeie = HGEIE
+);
+csr_with_value!(
+    /// Hypervisor time delta register, added to `time` in VS/VU-mode.
+    Htimedelta = HTIMEDELTA
+);
+csr_with_value!(
+    /// Hypervisor trap value register, the guest physical address of a
+    /// guest-page fault shifted right by 2.
+    Htval = HTVAL
+);
+csr_with_value!(
+    /// Hypervisor trap instruction register, the transformed trapping
+    /// instruction.
+    Htinst = HTINST
+);
+
+csr_with_flags!(
+    /// Virtual supervisor status register.
+    Vsstatus: VsstatusFlags = VSSTATUS
+);
+csr_with_flags!(
+    /// Virtual supervisor interrupt pending register.
+    Vsip: VsipFlags = VSIP
+);
+csr_with_flags!(
+    /// Virtual supervisor interrupt enable register.
+    Vsie: VsieFlags = VSIE
+);
+csr_with_flags!(
+    /// Virtual supervisor trap vector register.
+    Vstvec: VstvecFlags = VSTVEC
+);
+csr_with_flags!(
+    /// Virtual supervisor cause register.
+    Vscause: VscauseFlags = VSCAUSE
+);
+csr_with_flags!(
+    /// Virtual supervisor address translation and protection register.
+    Vsatp: VsatpFlags = VSATP
+);
+csr_with_value!(
+    /// Virtual supervisor scratch register.
+    Vsscratch = VSSCRATCH
+);
+csr_with_value!(
+    /// Virtual supervisor exception program counter.
+    Vsepc = VSEPC
+);
+csr_with_value!(
+    /// Virtual supervisor trap value register.
+    Vstval = VSTVAL
+);
@@ -0,0 +1,545 @@
+//! Guest vCPU state and the world switch between HS-mode and VS-mode.
+
+use core::arch::global_asm;
+use core::mem::offset_of;
+
+use super::csr::Csr;
+use super::guest_mem;
+use super::structs::{Hstatus, HstatusFlags, Hvip, HvipFlags};
+use super::vmexit::{HextExitInfo, RvmExitReason};
+use super::vtimer::VirtualTimer;
+use crate::hv::{GuestPhysAddr, GuestVirtAddr, RvmResult};
+use crate::riscv64::trap::{INTERRUPT_VS_EXT, INTERRUPT_VS_SOFT, INTERRUPT_VS_TIMER};
+use crate::rvm_err;
+
+/// `sstatus.SPIE`: interrupt enable before the trap.
+const SSTATUS_SPIE: usize = 1 << 5;
+/// `sstatus.SPP`: return to S-mode (VS-mode when `hstatus.SPV` is set).
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Index of a general-purpose register in [`GeneralPurposeRegisters`].
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum GprIndex {
+    Zero = 0,
+    RA,
+    SP,
+    GP,
+    TP,
+    T0,
+    T1,
+    T2,
+    S0,
+    S1,
+    A0,
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    A6,
+    A7,
+    S2,
+    S3,
+    S4,
+    S5,
+    S6,
+    S7,
+    S8,
+    S9,
+    S10,
+    S11,
+    T3,
+    T4,
+    T5,
+    T6,
+}
+
+impl GprIndex {
+    /// The index of register `x{raw}`, as encoded in instructions.
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        if raw < 32 {
+            // SAFETY: `GprIndex` has one variant for each value in 0..32.
+            Some(unsafe { core::mem::transmute::<usize, GprIndex>(raw as usize) })
+        } else {
+            None
+        }
+    }
+}
+
+/// General-purpose registers `x0`..`x31`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GeneralPurposeRegisters([usize; 32]);
+
+impl GeneralPurposeRegisters {
+    pub fn reg(&self, index: GprIndex) -> usize {
+        self.0[index as usize]
+    }
+
+    /// Write a register, writes to `x0` are ignored.
+    pub fn set_reg(&mut self, index: GprIndex, val: usize) {
+        if index != GprIndex::Zero {
+            self.0[index as usize] = val;
+        }
+    }
+}
+
+/// Hypervisor state saved on guest entry and restored on guest exit.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct HypervisorCpuState {
+    gprs: GeneralPurposeRegisters,
+    sstatus: usize,
+    hstatus: usize,
+    scounteren: usize,
+    stvec: usize,
+    sscratch: usize,
+}
+
+/// Guest state swapped in and out by the world switch itself.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct GuestCpuState {
+    gprs: GeneralPurposeRegisters,
+    sstatus: usize,
+    hstatus: usize,
+    scounteren: usize,
+    sepc: usize,
+}
+
+/// VS-level CSRs of the guest. They are not touched by HS-mode traps, so they
+/// are only loaded before entering the guest and saved after leaving it.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GuestVsCsrs {
+    pub vsstatus: usize,
+    pub vsie: usize,
+    pub vstvec: usize,
+    pub vsscratch: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+    pub vsatp: usize,
+}
+
+impl GuestVsCsrs {
+    unsafe fn load(&self) {
+        Csr::VSSTATUS.write(self.vsstatus as u64);
+        Csr::VSIE.write(self.vsie as u64);
+        Csr::VSTVEC.write(self.vstvec as u64);
+        Csr::VSSCRATCH.write(self.vsscratch as u64);
+        Csr::VSEPC.write(self.vsepc as u64);
+        Csr::VSCAUSE.write(self.vscause as u64);
+        Csr::VSTVAL.write(self.vstval as u64);
+        Csr::VSATP.write(self.vsatp as u64);
+    }
+
+    unsafe fn save(&mut self) {
+        self.vsstatus = Csr::VSSTATUS.read() as usize;
+        self.vsie = Csr::VSIE.read() as usize;
+        self.vstvec = Csr::VSTVEC.read() as usize;
+        self.vsscratch = Csr::VSSCRATCH.read() as usize;
+        self.vsepc = Csr::VSEPC.read() as usize;
+        self.vscause = Csr::VSCAUSE.read() as usize;
+        self.vstval = Csr::VSTVAL.read() as usize;
+        self.vsatp = Csr::VSATP.read() as usize;
+    }
+}
+
+/// Trap CSRs recorded by the world switch on guest exit.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct VmCpuTrapState {
+    scause: usize,
+    stval: usize,
+    htval: usize,
+    htinst: usize,
+}
+
+/// Everything the world switch reads or writes, `a0` points to it.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct VmCpuRegisters {
+    hyp_regs: HypervisorCpuState,
+    guest_regs: GuestCpuState,
+    vs_csrs: GuestVsCsrs,
+    trap_csrs: VmCpuTrapState,
+}
+
+const fn hyp_field(offset: usize) -> usize {
+    offset_of!(VmCpuRegisters, hyp_regs) + offset
+}
+
+const fn guest_field(offset: usize) -> usize {
+    offset_of!(VmCpuRegisters, guest_regs) + offset
+}
+
+const fn trap_field(offset: usize) -> usize {
+    offset_of!(VmCpuRegisters, trap_csrs) + offset
+}
+
+extern "C" {
+    fn _run_guest(regs: *mut VmCpuRegisters);
+}
+
+global_asm!(
+    r#"
+.section .text
+.globl _run_guest
+_run_guest:
+    // Save hypervisor callee-saved registers
+    sd   ra,  ({hyp_gprs}+1*8)(a0)
+    sd   sp,  ({hyp_gprs}+2*8)(a0)
+    sd   gp,  ({hyp_gprs}+3*8)(a0)
+    sd   tp,  ({hyp_gprs}+4*8)(a0)
+    sd   s0,  ({hyp_gprs}+8*8)(a0)
+    sd   s1,  ({hyp_gprs}+9*8)(a0)
+    sd   s2,  ({hyp_gprs}+18*8)(a0)
+    sd   s3,  ({hyp_gprs}+19*8)(a0)
+    sd   s4,  ({hyp_gprs}+20*8)(a0)
+    sd   s5,  ({hyp_gprs}+21*8)(a0)
+    sd   s6,  ({hyp_gprs}+22*8)(a0)
+    sd   s7,  ({hyp_gprs}+23*8)(a0)
+    sd   s8,  ({hyp_gprs}+24*8)(a0)
+    sd   s9,  ({hyp_gprs}+25*8)(a0)
+    sd   s10, ({hyp_gprs}+26*8)(a0)
+    sd   s11, ({hyp_gprs}+27*8)(a0)
+
+    // Swap in guest CSRs. `sstatus` goes first so that SIE is cleared
+    // before `stvec` points to the guest exit path.
+    ld    t1, {guest_sstatus}(a0)
+    csrrw t1, sstatus, t1
+    sd    t1, {hyp_sstatus}(a0)
+
+    ld    t1, {guest_hstatus}(a0)
+    csrrw t1, hstatus, t1
+    sd    t1, {hyp_hstatus}(a0)
+
+    ld    t1, {guest_scounteren}(a0)
+    csrrw t1, scounteren, t1
+    sd    t1, {hyp_scounteren}(a0)
+
+    ld    t1, {guest_sepc}(a0)
+    csrw  sepc, t1
+
+    la    t1, _guest_exit
+    csrrw t1, stvec, t1
+    sd    t1, {hyp_stvec}(a0)
+
+    csrrw t1, sscratch, a0
+    sd    t1, {hyp_sscratch}(a0)
+
+    // Restore guest general-purpose registers, a0 goes last
+    ld   ra,  ({guest_gprs}+1*8)(a0)
+    ld   sp,  ({guest_gprs}+2*8)(a0)
+    ld   gp,  ({guest_gprs}+3*8)(a0)
+    ld   tp,  ({guest_gprs}+4*8)(a0)
+    ld   t0,  ({guest_gprs}+5*8)(a0)
+    ld   t1,  ({guest_gprs}+6*8)(a0)
+    ld   t2,  ({guest_gprs}+7*8)(a0)
+    ld   s0,  ({guest_gprs}+8*8)(a0)
+    ld   s1,  ({guest_gprs}+9*8)(a0)
+    ld   a1,  ({guest_gprs}+11*8)(a0)
+    ld   a2,  ({guest_gprs}+12*8)(a0)
+    ld   a3,  ({guest_gprs}+13*8)(a0)
+    ld   a4,  ({guest_gprs}+14*8)(a0)
+    ld   a5,  ({guest_gprs}+15*8)(a0)
+    ld   a6,  ({guest_gprs}+16*8)(a0)
+    ld   a7,  ({guest_gprs}+17*8)(a0)
+    ld   s2,  ({guest_gprs}+18*8)(a0)
+    ld   s3,  ({guest_gprs}+19*8)(a0)
+    ld   s4,  ({guest_gprs}+20*8)(a0)
+    ld   s5,  ({guest_gprs}+21*8)(a0)
+    ld   s6,  ({guest_gprs}+22*8)(a0)
+    ld   s7,  ({guest_gprs}+23*8)(a0)
+    ld   s8,  ({guest_gprs}+24*8)(a0)
+    ld   s9,  ({guest_gprs}+25*8)(a0)
+    ld   s10, ({guest_gprs}+26*8)(a0)
+    ld   s11, ({guest_gprs}+27*8)(a0)
+    ld   t3,  ({guest_gprs}+28*8)(a0)
+    ld   t4,  ({guest_gprs}+29*8)(a0)
+    ld   t5,  ({guest_gprs}+30*8)(a0)
+    ld   t6,  ({guest_gprs}+31*8)(a0)
+    ld   a0,  ({guest_gprs}+10*8)(a0)
+
+    // Enter the guest
+    sret
+
+.align 2
+_guest_exit:
+    // sscratch holds the pointer to VmCpuRegisters while in the guest
+    csrrw a0, sscratch, a0
+
+    // Save guest general-purpose registers
+    sd   ra,  ({guest_gprs}+1*8)(a0)
+    sd   sp,  ({guest_gprs}+2*8)(a0)
+    sd   gp,  ({guest_gprs}+3*8)(a0)
+    sd   tp,  ({guest_gprs}+4*8)(a0)
+    sd   t0,  ({guest_gprs}+5*8)(a0)
+    sd   t1,  ({guest_gprs}+6*8)(a0)
+    sd   t2,  ({guest_gprs}+7*8)(a0)
+    sd   s0,  ({guest_gprs}+8*8)(a0)
+    sd   s1,  ({guest_gprs}+9*8)(a0)
+    sd   a1,  ({guest_gprs}+11*8)(a0)
+    sd   a2,  ({guest_gprs}+12*8)(a0)
+    sd   a3,  ({guest_gprs}+13*8)(a0)
+    sd   a4,  ({guest_gprs}+14*8)(a0)
+    sd   a5,  ({guest_gprs}+15*8)(a0)
+    sd   a6,  ({guest_gprs}+16*8)(a0)
+    sd   a7,  ({guest_gprs}+17*8)(a0)
+    sd   s2,  ({guest_gprs}+18*8)(a0)
+    sd   s3,  ({guest_gprs}+19*8)(a0)
+    sd   s4,  ({guest_gprs}+20*8)(a0)
+    sd   s5,  ({guest_gprs}+21*8)(a0)
+    sd   s6,  ({guest_gprs}+22*8)(a0)
+    sd   s7,  ({guest_gprs}+23*8)(a0)
+    sd   s8,  ({guest_gprs}+24*8)(a0)
+    sd   s9,  ({guest_gprs}+25*8)(a0)
+    sd   s10, ({guest_gprs}+26*8)(a0)
+    sd   s11, ({guest_gprs}+27*8)(a0)
+    sd   t3,  ({guest_gprs}+28*8)(a0)
+    sd   t4,  ({guest_gprs}+29*8)(a0)
+    sd   t5,  ({guest_gprs}+30*8)(a0)
+    sd   t6,  ({guest_gprs}+31*8)(a0)
+    csrr t1, sscratch
+    sd   t1,  ({guest_gprs}+10*8)(a0)
+
+    // Record the trap CSRs and guest PC before anything can clobber them
+    csrr t1, scause
+    sd   t1, {trap_scause}(a0)
+    csrr t1, stval
+    sd   t1, {trap_stval}(a0)
+    csrr t1, htval
+    sd   t1, {trap_htval}(a0)
+    csrr t1, htinst
+    sd   t1, {trap_htinst}(a0)
+    csrr t1, sepc
+    sd   t1, {guest_sepc}(a0)
+
+    // Swap back hypervisor CSRs. `sstatus` goes last as it may re-enable
+    // interrupts.
+    ld    t1, {hyp_sscratch}(a0)
+    csrw  sscratch, t1
+
+    ld    t1, {hyp_stvec}(a0)
+    csrw  stvec, t1
+
+    ld    t1, {hyp_scounteren}(a0)
+    csrrw t1, scounteren, t1
+    sd    t1, {guest_scounteren}(a0)
+
+    ld    t1, {hyp_hstatus}(a0)
+    csrrw t1, hstatus, t1
+    sd    t1, {guest_hstatus}(a0)
+
+    ld    t1, {hyp_sstatus}(a0)
+    csrrw t1, sstatus, t1
+    sd    t1, {guest_sstatus}(a0)
+
+    // Restore hypervisor callee-saved registers
+    ld   ra,  ({hyp_gprs}+1*8)(a0)
+    ld   sp,  ({hyp_gprs}+2*8)(a0)
+    ld   gp,  ({hyp_gprs}+3*8)(a0)
+    ld   tp,  ({hyp_gprs}+4*8)(a0)
+    ld   s0,  ({hyp_gprs}+8*8)(a0)
+    ld   s1,  ({hyp_gprs}+9*8)(a0)
+    ld   s2,  ({hyp_gprs}+18*8)(a0)
+    ld   s3,  ({hyp_gprs}+19*8)(a0)
+    ld   s4,  ({hyp_gprs}+20*8)(a0)
+    ld   s5,  ({hyp_gprs}+21*8)(a0)
+    ld   s6,  ({hyp_gprs}+22*8)(a0)
+    ld   s7,  ({hyp_gprs}+23*8)(a0)
+    ld   s8,  ({hyp_gprs}+24*8)(a0)
+    ld   s9,  ({hyp_gprs}+25*8)(a0)
+    ld   s10, ({hyp_gprs}+26*8)(a0)
+    ld   s11, ({hyp_gprs}+27*8)(a0)
+
+    ret
+"#,
+    hyp_gprs = const hyp_field(offset_of!(HypervisorCpuState, gprs)),
+    hyp_sstatus = const hyp_field(offset_of!(HypervisorCpuState, sstatus)),
+    hyp_hstatus = const hyp_field(offset_of!(HypervisorCpuState, hstatus)),
+    hyp_scounteren = const hyp_field(offset_of!(HypervisorCpuState, scounteren)),
+    hyp_stvec = const hyp_field(offset_of!(HypervisorCpuState, stvec)),
+    hyp_sscratch = const hyp_field(offset_of!(HypervisorCpuState, sscratch)),
+    guest_gprs = const guest_field(offset_of!(GuestCpuState, gprs)),
+    guest_sstatus = const guest_field(offset_of!(GuestCpuState, sstatus)),
+    guest_hstatus = const guest_field(offset_of!(GuestCpuState, hstatus)),
+    guest_scounteren = const guest_field(offset_of!(GuestCpuState, scounteren)),
+    guest_sepc = const guest_field(offset_of!(GuestCpuState, sepc)),
+    trap_scause = const trap_field(offset_of!(VmCpuTrapState, scause)),
+    trap_stval = const trap_field(offset_of!(VmCpuTrapState, stval)),
+    trap_htval = const trap_field(offset_of!(VmCpuTrapState, htval)),
+    trap_htinst = const trap_field(offset_of!(VmCpuTrapState, htinst)),
+);
+
+/// A virtual CPU running in VS-mode.
+pub struct HextVcpu {
+    regs: VmCpuRegisters,
+    hgatp: u64,
+    /// Virtual interrupts pending for this vCPU. `hvip` is not banked per
+    /// vCPU, and the guest clears `VSSIP` through `sip`, so it is loaded and
+    /// saved around each run.
+    hvip: HvipFlags,
+    vtimer: VirtualTimer,
+}
+
+/// The `hvip` bit of VS-level interrupt `irq`.
+fn hvip_flag(irq: usize) -> RvmResult<HvipFlags> {
+    match irq {
+        INTERRUPT_VS_SOFT => Ok(HvipFlags::VSSIP),
+        INTERRUPT_VS_TIMER => Ok(HvipFlags::VSTIP),
+        INTERRUPT_VS_EXT => Ok(HvipFlags::VSEIP),
+        _ => rvm_err!(InvalidParam, "not a VS-level interrupt"),
+    }
+}
+
+/// The error of a guest memory access at `gva` that raised `scause`.
+fn guest_fault<T>(gva: GuestVirtAddr, scause: usize) -> RvmResult<T> {
+    rvm_err!(
+        InvalidParam,
+        format_args!("guest access to {:#x} faulted, scause {:#x}", gva, scause)
+    )
+}
+
+impl HextVcpu {
+    /// Create a vCPU that starts executing at `entry` in VS-mode, with guest
+    /// physical memory translated by `hgatp`.
+    pub fn new(entry: GuestPhysAddr, hgatp: u64) -> Self {
+        let mut regs = VmCpuRegisters::default();
+        // Keep the host's VSXL and other WARL fields, then ask `sret` to
+        // return to VS-mode. SPVP lets HLV/HSV access guest memory as
+        // VS-mode.
+        let hstatus = Hstatus::read() | HstatusFlags::SPV | HstatusFlags::SPVP;
+        regs.guest_regs.hstatus = hstatus.bits() as usize;
+        regs.guest_regs.sstatus = SSTATUS_SPP | SSTATUS_SPIE;
+        regs.guest_regs.sepc = entry;
+        Self {
+            regs,
+            hgatp,
+            hvip: HvipFlags::empty(),
+            vtimer: VirtualTimer::new(),
+        }
+    }
+
+    /// Enter the guest and run it until it traps back to HS-mode.
+    pub fn run(&mut self) -> RvmExitReason {
+        unsafe {
+            Csr::HGATP.write(self.hgatp);
+            if self.vtimer.load() {
+                self.hvip |= HvipFlags::VSTIP;
+            }
+            Hvip::write(self.hvip);
+            self.regs.vs_csrs.load();
+            _run_guest(&mut self.regs);
+            self.regs.vs_csrs.save();
+            self.vtimer.save();
+            self.hvip = Hvip::read();
+        }
+        self.exit_info().exit_reason()
+    }
+
+    /// Trap CSRs of the last guest exit.
+    pub fn exit_info(&self) -> HextExitInfo {
+        let trap = &self.regs.trap_csrs;
+        HextExitInfo {
+            scause: trap.scause,
+            stval: trap.stval,
+            htval: trap.htval,
+            htinst: trap.htinst,
+            hstatus: self.regs.guest_regs.hstatus,
+            sepc: self.regs.guest_regs.sepc,
+        }
+    }
+
+    pub fn regs(&self) -> &GeneralPurposeRegisters {
+        &self.regs.guest_regs.gprs
+    }
+
+    pub fn regs_mut(&mut self) -> &mut GeneralPurposeRegisters {
+        &mut self.regs.guest_regs.gprs
+    }
+
+    pub fn vs_csrs(&self) -> &GuestVsCsrs {
+        &self.regs.vs_csrs
+    }
+
+    pub fn vs_csrs_mut(&mut self) -> &mut GuestVsCsrs {
+        &mut self.regs.vs_csrs
+    }
+
+    pub fn sepc(&self) -> usize {
+        self.regs.guest_regs.sepc
+    }
+
+    /// Make VS-level interrupt `irq` (`INTERRUPT_VS_*`) pending on the next
+    /// run.
+    pub fn set_irq_pending(&mut self, irq: usize) -> RvmResult {
+        self.hvip |= hvip_flag(irq)?;
+        Ok(())
+    }
+
+    /// Withdraw the pending VS-level interrupt `irq`.
+    pub fn clear_irq_pending(&mut self, irq: usize) -> RvmResult {
+        self.hvip -= hvip_flag(irq)?;
+        Ok(())
+    }
+
+    pub fn vtimer(&self) -> &VirtualTimer {
+        &self.vtimer
+    }
+
+    /// Offset the guest `time` from the host one by `time_delta`.
+    pub fn set_time_delta(&mut self, time_delta: u64) {
+        self.vtimer.set_time_delta(time_delta);
+    }
+
+    /// Raise the virtual timer interrupt once the guest `time` reaches
+    /// `deadline`, and withdraw the pending one, like `sbi_set_timer`.
+    pub fn set_timer(&mut self, deadline: u64) {
+        self.vtimer.set_deadline(deadline);
+        self.hvip -= HvipFlags::VSTIP;
+    }
+
+    /// Run `f` with the guest's address translation and `hstatus.SPVP` in
+    /// place, so that HLV/HSV access memory as the guest would.
+    fn with_guest_translation<T>(&self, f: impl FnOnce() -> T) -> T {
+        let spvp = HstatusFlags::from_bits_truncate(self.regs.guest_regs.hstatus as u64)
+            & HstatusFlags::SPVP;
+        let host_hstatus = Hstatus::read();
+        unsafe {
+            Csr::HGATP.write(self.hgatp);
+            self.regs.vs_csrs.load();
+        }
+        Hstatus::write((host_hstatus - HstatusFlags::SPVP) | spvp);
+        let ret = f();
+        Hstatus::write(host_hstatus);
+        ret
+    }
+
+    /// Read guest virtual memory at `gva` into `buf`.
+    pub fn read_guest_virt(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> RvmResult {
+        self.with_guest_translation(|| guest_mem::read_bytes(gva, buf))
+            .or_else(|(addr, scause)| guest_fault(addr, scause))
+    }
+
+    /// Write `data` to guest virtual memory at `gva`.
+    pub fn write_guest_virt(&self, gva: GuestVirtAddr, data: &[u8]) -> RvmResult {
+        self.with_guest_translation(|| guest_mem::write_bytes(gva, data))
+            .or_else(|(addr, scause)| guest_fault(addr, scause))
+    }
+
+    /// Fetch the guest instruction at `gva`, which must be executable. A
+    /// compressed instruction is returned in the low 16 bits.
+    pub fn fetch_guest_insn(&self, gva: GuestVirtAddr) -> RvmResult<u32> {
+        self.with_guest_translation(|| guest_mem::fetch_insn(gva))
+            .or_else(|(addr, scause)| guest_fault(addr, scause))
+    }
+
+    /// Skip the trapping instruction of `instr_len` bytes.
+    pub fn advance_pc(&mut self, instr_len: usize) {
+        self.regs.guest_regs.sepc += instr_len;
+    }
+}
@@ -0,0 +1,125 @@
+//! Decoding of traps taken from the guest (V=1) into HS-mode.
+
+use super::structs::HstatusFlags;
+use crate::hv::{GuestPhysAddr, GuestVirtAddr};
+use crate::riscv64::trap::*;
+
+/// Trap CSRs recorded when the guest traps back to HS-mode.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HextExitInfo {
+    /// Trap cause, as read from `scause`.
+    pub scause: usize,
+    /// Faulting address or instruction bits, as read from `stval`.
+    pub stval: usize,
+    /// Guest physical address of a guest-page fault, shifted right by 2.
+    pub htval: usize,
+    /// Transformed trapping instruction, or 0 if not provided.
+    pub htinst: usize,
+    /// Guest `hstatus` at the time of the trap, with `GVA` and `SPV` updated.
+    pub hstatus: usize,
+    /// Guest PC at the time of the trap.
+    pub sepc: usize,
+}
+
+/// The kind of access that caused a guest-page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+/// The reason why the guest trapped back to HS-mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RvmExitReason {
+    /// `ecall` from VS-mode, normally an SBI call.
+    Ecall,
+    /// The guest physical address is not mapped, or mapped without the
+    /// required permission, in the G-stage page table.
+    GuestPageFault {
+        access: GuestAccessType,
+        gpa: GuestPhysAddr,
+        /// The guest virtual address, if the access was translated by the
+        /// guest's own page table.
+        gva: Option<GuestVirtAddr>,
+        /// Transformed instruction from `htinst`, or 0 if not provided.
+        htinst: usize,
+    },
+    /// The guest executed an instruction that is only illegal because V=1,
+    /// like `wfi` with `hstatus.VTW` set or an H-extension instruction.
+    VirtualInstruction {
+        /// Instruction bits from `stval`, or 0 if not provided.
+        insn: usize,
+    },
+    /// A VS-level interrupt (`INTERRUPT_VS_*`) that is not delegated to the
+    /// guest through `hideleg`.
+    VirtualInterrupt(usize),
+    /// A guest external interrupt (`INTERRUPT_S_GUEST_EXT`) from `hgeip`.
+    GuestExternalInterrupt,
+    /// A host interrupt arrived while the guest was running. It is handled
+    /// by the host trap handler as soon as host interrupts are enabled.
+    HostInterrupt(usize),
+    /// Any other exception that is not delegated to the guest through
+    /// `hedeleg`.
+    Exception { cause: usize, stval: usize },
+}
+
+impl HextExitInfo {
+    const INTERRUPT_BIT: usize = 1 << (usize::BITS - 1);
+
+    pub fn is_interrupt(&self) -> bool {
+        self.scause & Self::INTERRUPT_BIT != 0
+    }
+
+    pub fn cause_code(&self) -> usize {
+        self.scause & !Self::INTERRUPT_BIT
+    }
+
+    /// Guest physical address of a guest-page fault, the low 2 bits come
+    /// from `stval`.
+    pub fn fault_gpa(&self) -> GuestPhysAddr {
+        (self.htval << 2) | (self.stval & 0b11)
+    }
+
+    /// Decode the trap CSRs into an [`RvmExitReason`].
+    pub fn exit_reason(&self) -> RvmExitReason {
+        let cause = self.cause_code();
+        if self.is_interrupt() {
+            return match cause {
+                INTERRUPT_VS_SOFT | INTERRUPT_VS_TIMER | INTERRUPT_VS_EXT => {
+                    RvmExitReason::VirtualInterrupt(cause)
+                }
+                INTERRUPT_S_GUEST_EXT => RvmExitReason::GuestExternalInterrupt,
+                _ => RvmExitReason::HostInterrupt(cause),
+            };
+        }
+
+        let access = match cause {
+            EXCEPTION_ECALL_FROM_VS => return RvmExitReason::Ecall,
+            EXCEPTION_VIRTUAL_INST => {
+                return RvmExitReason::VirtualInstruction { insn: self.stval }
+            }
+            EXCEPTION_INST_GUEST_PAGE_FAULT => GuestAccessType::Fetch,
+            EXCEPTION_LOAD_GUEST_PAGE_FAULT => GuestAccessType::Load,
+            EXCEPTION_STORE_GUEST_PAGE_FAULT => GuestAccessType::Store,
+            _ => {
+                return RvmExitReason::Exception {
+                    cause,
+                    stval: self.stval,
+                }
+            }
+        };
+        let hstatus = HstatusFlags::from_bits_truncate(self.hstatus as u64);
+        let gva = if hstatus.contains(HstatusFlags::GVA) {
+            Some(self.stval)
+        } else {
+            None
+        };
+        RvmExitReason::GuestPageFault {
+            access,
+            gpa: self.fault_gpa(),
+            gva,
+            htinst: self.htinst,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Virtual time of a vCPU.
+//!
+//! The guest sees `time` shifted by `htimedelta`. Its timer interrupt comes
+//! from `vstimecmp` if the CPU implements Sstc, otherwise the hypervisor adds
+//! a host timer event at the deadline and sets `VSTIP` in `hvip` once it is
+//! reached.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::csr::Csr;
+use super::structs::{Henvcfg, HenvcfgFlags, Htimedelta};
+use crate::riscv64::timer::{self, TimerId};
+use crate::timer::Instant;
+
+/// Whether `vstimecmp` is usable, set by [`enable_sstc`].
+static SSTC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Let VS-mode use `vstimecmp` if the firmware has enabled Sstc, and return
+/// whether it did. `henvcfg.STCE` is read-only zero otherwise.
+pub(super) fn enable_sstc() -> bool {
+    Henvcfg::write(Henvcfg::read() | HenvcfgFlags::STCE);
+    let enabled = Henvcfg::read().contains(HenvcfgFlags::STCE);
+    SSTC_ENABLED.store(enabled, Ordering::Relaxed);
+    enabled
+}
+
+pub(super) fn disable_sstc() {
+    Henvcfg::write(Henvcfg::read() - HenvcfgFlags::STCE);
+}
+
+/// Whether guest timer interrupts are raised by `vstimecmp`.
+pub fn has_sstc() -> bool {
+    SSTC_ENABLED.load(Ordering::Relaxed)
+}
+
+/// The `time` offset and timer deadline of a vCPU.
+#[derive(Debug)]
+pub struct VirtualTimer {
+    /// Added to the host `time` to get the guest one.
+    time_delta: u64,
+    /// Guest `time` of the next timer interrupt, `u64::MAX` if none.
+    deadline: u64,
+    /// Host timer event for the deadline, without Sstc.
+    event: Option<TimerId>,
+}
+
+impl VirtualTimer {
+    pub const fn new() -> Self {
+        Self {
+            time_delta: 0,
+            deadline: u64::MAX,
+            event: None,
+        }
+    }
+
+    pub fn time_delta(&self) -> u64 {
+        self.time_delta
+    }
+
+    pub fn set_time_delta(&mut self, time_delta: u64) {
+        self.time_delta = time_delta;
+    }
+
+    /// The current `time` as seen by the guest.
+    pub fn guest_time(&self) -> u64 {
+        Instant::now().ticks().wrapping_add(self.time_delta)
+    }
+
+    /// Guest `time` of the next timer interrupt.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: u64) {
+        self.deadline = deadline;
+    }
+
+    /// Whether the guest `time` has reached the deadline.
+    pub fn is_expired(&self) -> bool {
+        self.guest_time() >= self.deadline
+    }
+
+    /// Load the guest view of time before entering the guest. Without Sstc,
+    /// returns whether `VSTIP` must be pending, or makes sure a host timer
+    /// event interrupts the guest at the deadline.
+    pub(super) unsafe fn load(&mut self) -> bool {
+        Htimedelta::write(self.time_delta);
+        if has_sstc() {
+            Csr::VSTIMECMP.write(self.deadline);
+            return false;
+        }
+        if self.is_expired() {
+            return true;
+        }
+        let host_deadline = self.deadline.wrapping_sub(self.time_delta);
+        if self.event.map(|id| id.deadline()) != Some(host_deadline) {
+            self.cancel_event();
+            if self.deadline != u64::MAX {
+                // The interrupt alone makes the guest exit, `VSTIP` is set
+                // on the next entry.
+                self.event = Some(timer::set_timer(host_deadline, |_| {}));
+            }
+        }
+        false
+    }
+
+    fn cancel_event(&mut self) {
+        if let Some(id) = self.event.take() {
+            timer::cancel(id);
+        }
+    }
+
+    /// Save the deadline after leaving the guest, which may have written its
+    /// `stimecmp` directly with Sstc.
+    pub(super) unsafe fn save(&mut self) {
+        if has_sstc() {
+            self.deadline = Csr::VSTIMECMP.read();
+        }
+    }
+}
+
+impl Drop for VirtualTimer {
+    fn drop(&mut self) {
+        self.cancel_event();
+    }
+}
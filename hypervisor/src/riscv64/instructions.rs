@@ -6,30 +6,42 @@ use core::arch::asm;
 // Interrupt Control
 // ============================================================================
 
-/// Enable interrupts (set MIE bit in mstatus)
+/// Enable interrupts (set SIE bit in sstatus)
 #[inline]
 pub fn enable_irqs() {
     unsafe {
-        asm!("csrsi mstatus, 0x8");
+        asm!("csrsi sstatus, 0x2");
     }
 }
 
-/// Disable interrupts (clear MIE bit in mstatus)
+/// Disable interrupts (clear SIE bit in sstatus)
 #[inline]
 pub fn disable_irqs() {
     unsafe {
-        asm!("csrci mstatus, 0x8");
+        asm!("csrci sstatus, 0x2");
     }
 }
 
 /// Check if interrupts are disabled
 #[inline]
 pub fn irqs_disabled() -> bool {
-    let mstatus: usize;
+    let sstatus: usize;
     unsafe {
-        asm!("csrr {}, mstatus", out(reg) mstatus);
+        asm!("csrr {}, sstatus", out(reg) sstatus);
     }
-    (mstatus & 0x8) == 0
+    (sstatus & 0x2) == 0
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state afterwards
+#[inline]
+pub fn with_irqs_disabled<R>(f: impl FnOnce() -> R) -> R {
+    let irqs_disabled = irqs_disabled();
+    disable_irqs();
+    let ret = f();
+    if !irqs_disabled {
+        enable_irqs();
+    }
+    ret
 }
 
 /// Wait for interrupts using WFI instruction
@@ -46,130 +58,323 @@ pub fn wait_for_ints() {
 // Interrupt Enable Bits
 // ============================================================================
 
-/// Enable machine software interrupt
+/// Enable supervisor software interrupt
 #[inline]
-pub fn enable_msie() {
+pub fn enable_ssie() {
     unsafe {
-        asm!("csrsi mie, {}", const 0x8);  // MSIE = bit 3
+        asm!("csrsi sie, {}", const 0x2); // SSIE = bit 1
     }
 }
 
-/// Enable machine timer interrupt
+/// Enable supervisor timer interrupt
 #[inline]
-pub fn enable_mtie() {
-    let mie = read_mie();
-    write_mie(mie | 0x80);  // MTIE = bit 7
+pub fn enable_stie() {
+    let sie = read_sie();
+    write_sie(sie | 0x20); // STIE = bit 5
 }
 
-/// Enable machine external interrupt
+/// Enable supervisor external interrupt
 #[inline]
-pub fn enable_meie() {
-    let mie = read_mie();
-    write_mie(mie | 0x800);  // MEIE = bit 11
+pub fn enable_seie() {
+    let sie = read_sie();
+    write_sie(sie | 0x200); // SEIE = bit 9
 }
 
-/// Disable machine software interrupt
+/// Clear the pending supervisor software interrupt
 #[inline]
-pub fn disable_msie() {
+pub fn clear_ssip() {
     unsafe {
-        asm!("csrci mie, {}", const 0x8);
+        asm!("csrci sip, {}", const 0x2); // SSIP = bit 1
     }
 }
 
-/// Disable machine timer interrupt
+/// Disable supervisor software interrupt
 #[inline]
-pub fn disable_mtie() {
-    let mie = read_mie();
-    write_mie(mie & !0x80);
+pub fn disable_ssie() {
+    unsafe {
+        asm!("csrci sie, {}", const 0x2);
+    }
 }
 
-/// Disable machine external interrupt
+/// Disable supervisor timer interrupt
 #[inline]
-pub fn disable_meie() {
-    let mie = read_mie();
-    write_mie(mie & !0x800);
+pub fn disable_stie() {
+    let sie = read_sie();
+    write_sie(sie & !0x20);
+}
+
+/// Disable supervisor external interrupt
+#[inline]
+pub fn disable_seie() {
+    let sie = read_sie();
+    write_sie(sie & !0x200);
 }
 
 // ============================================================================
 // System Control
 // ============================================================================
 
-/// Read machine status register
+/// Read supervisor status register
+#[inline]
+pub fn read_sstatus() -> usize {
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus);
+    }
+    sstatus
+}
+
+/// Write supervisor status register
+#[inline]
+pub fn write_sstatus(value: usize) {
+    unsafe {
+        asm!("csrw sstatus, {}", in(reg) value);
+    }
+}
+
+/// Read supervisor cause register
+#[inline]
+pub fn read_scause() -> usize {
+    let scause: usize;
+    unsafe {
+        asm!("csrr {}, scause", out(reg) scause);
+    }
+    scause
+}
+
+/// Read supervisor trap value register
+#[inline]
+pub fn read_stval() -> usize {
+    let stval: usize;
+    unsafe {
+        asm!("csrr {}, stval", out(reg) stval);
+    }
+    stval
+}
+
+/// Read supervisor trap program counter
+#[inline]
+pub fn read_sepc() -> usize {
+    let sepc: usize;
+    unsafe {
+        asm!("csrr {}, sepc", out(reg) sepc);
+    }
+    sepc
+}
+
+/// Read supervisor interrupt enable register
 #[inline]
-pub fn read_mstatus() -> usize {
-    let mstatus: usize;
+pub fn read_sie() -> usize {
+    let sie: usize;
     unsafe {
-        asm!("csrr {}, mstatus", out(reg) mstatus);
+        asm!("csrr {}, sie", out(reg) sie);
     }
-    mstatus
+    sie
 }
 
-/// Write machine status register
+/// Write supervisor interrupt enable register
 #[inline]
-pub fn write_mstatus(value: usize) {
+pub fn write_sie(value: usize) {
     unsafe {
-        asm!("csrw mstatus, {}", in(reg) value);
+        asm!("csrw sie, {}", in(reg) value);
     }
 }
 
-/// Read machine cause register
+/// Write supervisor scratch register
 #[inline]
-pub fn read_mcause() -> usize {
-    let mcause: usize;
+pub fn write_sscratch(value: usize) {
     unsafe {
-        asm!("csrr {}, mcause", out(reg) mcause);
+        asm!("csrw sscratch, {}", in(reg) value);
     }
-    mcause
 }
 
-/// Read machine trap value register
+// ============================================================================
+// Thread Pointer
+// ============================================================================
+
+/// Read the `tp` register, which holds the per-CPU data pointer
 #[inline]
-pub fn read_mtval() -> usize {
-    let mtval: usize;
+pub fn read_tp() -> usize {
+    let tp: usize;
     unsafe {
-        asm!("csrr {}, mtval", out(reg) mtval);
+        asm!("mv {}, tp", out(reg) tp);
     }
-    mtval
+    tp
 }
 
-/// Read machine trap program counter
+/// Write the `tp` register
+#[inline]
+pub fn write_tp(value: usize) {
+    unsafe {
+        asm!("mv tp, {}", in(reg) value);
+    }
+}
+
+// ============================================================================
+// Timer
+// ============================================================================
+
+/// Read the `time` CSR, which S-mode can access through `scounteren.TM`
+/// set by the SBI firmware.
 #[inline]
-pub fn read_mepc() -> usize {
-    let mepc: usize;
+pub fn read_time() -> u64 {
+    let time: u64;
     unsafe {
-        asm!("csrr {}, mepc", out(reg) mepc);
+        asm!("rdtime {}", out(reg) time);
     }
-    mepc
+    time
 }
 
-/// Read machine interrupt enable register
+// ============================================================================
+// Address Translation
+// ============================================================================
+
+/// Switch to the Sv39 page table at `root_paddr` and flush the TLB.
 #[inline]
-pub fn read_mie() -> usize {
-    let mie: usize;
+pub fn activate_sv39(root_paddr: usize) {
+    const SATP_MODE_SV39: usize = 8 << 60;
     unsafe {
-        asm!("csrr {}, mie", out(reg) mie);
+        asm!("csrw satp, {}", in(reg) SATP_MODE_SV39 | (root_paddr >> 12));
     }
-    mie
+    flush_tlb_all();
 }
 
-/// Write machine interrupt enable register
+/// Flush all TLB entries of the current hart.
 #[inline]
-pub fn write_mie(value: usize) {
+pub fn flush_tlb_all() {
     unsafe {
-        asm!("csrw mie, {}", in(reg) value);
+        asm!("sfence.vma");
     }
 }
 
 // ============================================================================
-// Hart (Hardware Thread) Control
+// Hypervisor Memory-Management Fences
 // ============================================================================
 
-/// Read hart ID
+/// Flush G-stage TLB entries of `gpa` for the VM with `vmid`.
+#[inline]
+pub fn hfence_gvma(gpa: usize, vmid: usize) {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.gvma {}, {}",
+            ".option pop",
+            in(reg) gpa >> 2,
+            in(reg) vmid,
+        );
+    }
+}
+
+/// Flush G-stage TLB entries of `gpa` for all VMs.
+#[inline]
+pub fn hfence_gvma_addr(gpa: usize) {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.gvma {}, zero",
+            ".option pop",
+            in(reg) gpa >> 2,
+        );
+    }
+}
+
+/// Flush all G-stage TLB entries for the VM with `vmid`.
+#[inline]
+pub fn hfence_gvma_vmid(vmid: usize) {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.gvma zero, {}",
+            ".option pop",
+            in(reg) vmid,
+        );
+    }
+}
+
+/// Flush G-stage TLB entries of all VMs.
+#[inline]
+pub fn hfence_gvma_all() {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.gvma zero, zero",
+            ".option pop",
+        );
+    }
+}
+
+/// Flush VS-stage TLB entries of guest virtual address `vaddr` in address
+/// space `asid`, for the VMID in `hgatp`.
+#[inline]
+pub fn hfence_vvma(vaddr: usize, asid: usize) {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.vvma {}, {}",
+            ".option pop",
+            in(reg) vaddr,
+            in(reg) asid,
+        );
+    }
+}
+
+/// Flush VS-stage TLB entries of guest virtual address `vaddr` in all address
+/// spaces, for the VMID in `hgatp`.
+#[inline]
+pub fn hfence_vvma_addr(vaddr: usize) {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.vvma {}, zero",
+            ".option pop",
+            in(reg) vaddr,
+        );
+    }
+}
+
+/// Flush VS-stage TLB entries of address space `asid`, for the VMID in
+/// `hgatp`.
+#[inline]
+pub fn hfence_vvma_asid(asid: usize) {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.vvma zero, {}",
+            ".option pop",
+            in(reg) asid,
+        );
+    }
+}
+
+/// Flush all VS-stage TLB entries for the VMID in `hgatp`.
+#[inline]
+pub fn hfence_vvma_all() {
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +h",
+            "hfence.vvma zero, zero",
+            ".option pop",
+        );
+    }
+}
+
+/// Synchronize the instruction and data streams of the current hart.
 #[inline]
-pub fn read_hart_id() -> usize {
-    let hart_id: usize;
+pub fn fence_i() {
     unsafe {
-        asm!("csrr {}, mhartid", out(reg) hart_id);
+        asm!(
+            ".option push",
+            ".option arch, +zifencei",
+            "fence.i",
+            ".option pop",
+        );
     }
-    hart_id
 }
@@ -0,0 +1,21 @@
+//! Inter-processor interrupts between host CPUs.
+//!
+//! IPIs are sent through the SBI IPI extension and arrive as supervisor
+//! software interrupts. They carry no message: taking the interrupt is enough
+//! to make a CPU running a guest exit to the hypervisor, which then looks for
+//! requests left by other CPUs.
+
+use crate::riscv64::{instructions, sbi};
+
+/// Interrupt CPU `cpu_id`.
+pub fn send_ipi(cpu_id: usize) {
+    let ret = sbi::send_ipi(1, cpu_id);
+    if !ret.is_ok() {
+        warn!("Failed to send IPI to CPU {}: {:?}", cpu_id, ret);
+    }
+}
+
+/// Handle the software interrupt of the current CPU.
+pub fn handle_ipi() {
+    instructions::clear_ssip();
+}
@@ -1,18 +1,25 @@
 mod boot;
 
+pub mod decode;
 pub mod instructions;
+pub mod ipi;
+pub mod page_table;
+pub mod sbi;
 pub mod timer;
 pub mod trap;
 pub mod uart;
 
 pub mod hext;
 
+pub use boot::start_secondary_cpus;
 
 pub fn init_early() {
-    uart::init();
+    if let Some((base, _)) = crate::platform::info().uart {
+        uart::init(base);
+    }
 }
 
 pub fn init() {
     trap::init();
-    timer::init();
+    timer::init(crate::platform::info().timebase_frequency);
 }
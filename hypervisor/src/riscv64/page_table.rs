@@ -0,0 +1,156 @@
+//! RISC-V Sv39/Sv48 page table entries, shared by host and G-stage tables.
+
+use core::fmt;
+
+use crate::mm::page_table::{GenericPTE, PageTable64, PagingMetaData};
+use crate::mm::{HostPhysAddr, MemFlags};
+
+bitflags::bitflags! {
+    /// Page table entry flags.
+    pub struct PTEFlags: u64 {
+        /// Valid
+        const V = 1 << 0;
+        /// Readable
+        const R = 1 << 1;
+        /// Writable
+        const W = 1 << 2;
+        /// Executable
+        const X = 1 << 3;
+        /// Accessible from U-mode. G-stage leaves must always set it.
+        const U = 1 << 4;
+        /// Global
+        const G = 1 << 5;
+        /// Accessed
+        const A = 1 << 6;
+        /// Dirty
+        const D = 1 << 7;
+    }
+}
+
+impl From<PTEFlags> for MemFlags {
+    fn from(f: PTEFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(PTEFlags::R) {
+            ret |= Self::READ;
+        }
+        if f.contains(PTEFlags::W) {
+            ret |= Self::WRITE;
+        }
+        if f.contains(PTEFlags::X) {
+            ret |= Self::EXECUTE;
+        }
+        if f.contains(PTEFlags::U) {
+            ret |= Self::USER;
+        }
+        ret
+    }
+}
+
+impl From<MemFlags> for PTEFlags {
+    fn from(f: MemFlags) -> Self {
+        // Set A and D up front, so that no hardware or software A/D update
+        // is ever needed.
+        let mut ret = Self::V | Self::A | Self::D;
+        if f.contains(MemFlags::READ) {
+            ret |= Self::R;
+        }
+        if f.contains(MemFlags::WRITE) {
+            ret |= Self::W;
+        }
+        if f.contains(MemFlags::EXECUTE) {
+            ret |= Self::X;
+        }
+        if f.contains(MemFlags::USER) {
+            ret |= Self::U;
+        }
+        ret
+    }
+}
+
+/// A 64-bit page table entry of Sv39, Sv48 and their G-stage "x4" variants.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Rv64PTE(u64);
+
+impl Rv64PTE {
+    const PHYS_ADDR_MASK: u64 = ((1 << 44) - 1) << 10; // bits 10..54
+    const LEAF_FLAGS: PTEFlags =
+        PTEFlags::from_bits_truncate(PTEFlags::R.bits() | PTEFlags::W.bits() | PTEFlags::X.bits());
+
+    const fn ppn_bits(paddr: HostPhysAddr) -> u64 {
+        ((paddr as u64) >> 2) & Self::PHYS_ADDR_MASK
+    }
+
+    fn pte_flags(&self) -> PTEFlags {
+        PTEFlags::from_bits_truncate(self.0)
+    }
+}
+
+impl GenericPTE for Rv64PTE {
+    fn new_page(paddr: HostPhysAddr, flags: MemFlags, _is_huge: bool) -> Self {
+        Self(PTEFlags::from(flags).bits() | Self::ppn_bits(paddr))
+    }
+
+    fn new_table(paddr: HostPhysAddr) -> Self {
+        Self(PTEFlags::V.bits() | Self::ppn_bits(paddr))
+    }
+
+    fn paddr(&self) -> HostPhysAddr {
+        ((self.0 & Self::PHYS_ADDR_MASK) << 2) as HostPhysAddr
+    }
+
+    fn flags(&self) -> MemFlags {
+        self.pte_flags().into()
+    }
+
+    fn set_paddr(&mut self, paddr: HostPhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | Self::ppn_bits(paddr);
+    }
+
+    fn set_flags(&mut self, flags: MemFlags, _is_huge: bool) {
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | PTEFlags::from(flags).bits();
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_present(&self) -> bool {
+        self.pte_flags().contains(PTEFlags::V)
+    }
+
+    fn is_huge(&self) -> bool {
+        self.pte_flags().intersects(Self::LEAF_FLAGS)
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for Rv64PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rv64PTE")
+            .field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.pte_flags())
+            .finish()
+    }
+}
+
+/// Sv39: 39-bit virtual addresses, 3 levels.
+pub struct Sv39;
+
+impl PagingMetaData for Sv39 {
+    const LEVELS: usize = 3;
+    const VA_MAX_BITS: usize = 39;
+
+    /// Virtual addresses are sign-extended from bit 38.
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        let top_bits = (vaddr as isize) >> (Self::VA_MAX_BITS - 1);
+        top_bits == 0 || top_bits == -1
+    }
+}
+
+/// The page table used by the hypervisor itself.
+pub type HostPageTable = PageTable64<Sv39, Rv64PTE>;
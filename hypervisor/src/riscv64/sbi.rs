@@ -0,0 +1,137 @@
+//! Calls into the SBI firmware running in M-mode, and the SBI constants shared
+//! with the SBI implementation the hypervisor provides to guests.
+//!
+//! See the [RISC-V SBI specification](https://github.com/riscv-non-isa/riscv-sbi-doc).
+#![allow(dead_code)]
+
+use core::arch::asm;
+
+/// Base extension.
+pub const EID_BASE: usize = 0x10;
+/// Timer extension ("TIME").
+pub const EID_TIME: usize = 0x5449_4D45;
+/// Hart state management extension ("HSM").
+pub const EID_HSM: usize = 0x48_534D;
+/// IPI extension ("sPI").
+pub const EID_IPI: usize = 0x73_5049;
+/// Remote fence extension ("RFNC").
+pub const EID_RFENCE: usize = 0x5246_4E43;
+/// System reset extension ("SRST").
+pub const EID_SRST: usize = 0x5352_5354;
+/// Debug console extension ("DBCN").
+pub const EID_DBCN: usize = 0x4442_434E;
+/// Legacy `sbi_set_timer`.
+pub const EID_LEGACY_SET_TIMER: usize = 0x00;
+/// Legacy `sbi_console_putchar`.
+pub const EID_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+/// Legacy `sbi_console_getchar`.
+pub const EID_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+
+const FID_BASE_GET_SPEC_VERSION: usize = 0;
+const FID_BASE_PROBE_EXTENSION: usize = 3;
+const FID_TIME_SET_TIMER: usize = 0;
+const FID_IPI_SEND_IPI: usize = 0;
+const FID_HSM_HART_START: usize = 0;
+const FID_HSM_HART_GET_STATUS: usize = 2;
+
+/// `sbi_hart_get_status` value of a started hart.
+pub const HART_STATE_STARTED: usize = 0;
+/// `sbi_hart_get_status` value of a stopped hart.
+pub const HART_STATE_STOPPED: usize = 1;
+/// `sbi_hart_get_status` value of a hart that is being started.
+pub const HART_STATE_START_PENDING: usize = 2;
+/// `sbi_hart_get_status` value of a hart that is being stopped.
+pub const HART_STATE_STOP_PENDING: usize = 3;
+
+/// Error code `SBI_SUCCESS`.
+pub const SBI_SUCCESS: isize = 0;
+/// Error code `SBI_ERR_FAILED`.
+pub const SBI_ERR_FAILED: isize = -1;
+/// Error code `SBI_ERR_NOT_SUPPORTED`.
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+/// Error code `SBI_ERR_INVALID_PARAM`.
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+/// Error code `SBI_ERR_DENIED`.
+pub const SBI_ERR_DENIED: isize = -4;
+/// Error code `SBI_ERR_INVALID_ADDRESS`.
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+/// Error code `SBI_ERR_ALREADY_AVAILABLE`.
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+/// Error code `SBI_ERR_ALREADY_STARTED`.
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+/// Error code `SBI_ERR_ALREADY_STOPPED`.
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+
+/// The `(error, value)` pair returned by an SBI call in `a0` and `a1`.
+#[derive(Debug, Clone, Copy)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub const fn success(value: usize) -> Self {
+        Self {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+
+    pub const fn error(error: isize) -> Self {
+        Self { error, value: 0 }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error == SBI_SUCCESS
+    }
+}
+
+#[inline(always)]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    SbiRet { error, value }
+}
+
+/// Get the implemented SBI specification version, as `major << 24 | minor`.
+pub fn get_spec_version() -> usize {
+    sbi_call(EID_BASE, FID_BASE_GET_SPEC_VERSION, 0, 0, 0).value
+}
+
+/// Check whether the SBI extension `eid` is available.
+pub fn probe_extension(eid: usize) -> bool {
+    let ret = sbi_call(EID_BASE, FID_BASE_PROBE_EXTENSION, eid, 0, 0);
+    ret.is_ok() && ret.value != 0
+}
+
+/// Program the next timer event at absolute time `stime_value`, and clear the
+/// pending supervisor timer interrupt.
+pub fn set_timer(stime_value: u64) {
+    sbi_call(EID_TIME, FID_TIME_SET_TIMER, stime_value as usize, 0, 0);
+}
+
+/// Send a supervisor software interrupt to the harts in `hart_mask`, whose
+/// bit 0 is hart `hart_mask_base`.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    sbi_call(EID_IPI, FID_IPI_SEND_IPI, hart_mask, hart_mask_base, 0)
+}
+
+/// Start hart `hart_id` in S-mode at physical address `start_addr`, with
+/// `a0 = hart_id` and `a1 = opaque`.
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    sbi_call(EID_HSM, FID_HSM_HART_START, hart_id, start_addr, opaque)
+}
+
+/// Get the HSM state of hart `hart_id`, as `HART_STATE_*` in `value`.
+pub fn hart_get_status(hart_id: usize) -> SbiRet {
+    sbi_call(EID_HSM, FID_HSM_HART_GET_STATUS, hart_id, 0, 0)
+}
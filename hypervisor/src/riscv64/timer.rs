@@ -1,65 +1,160 @@
 //! RISC-V timer support
 //!
-//! For QEMU without OpenSBI, we directly write to memory-mapped timer registers.
-//! In QEMU virt machine, the CLINT (Core Local Interruptor) is at 0x02000000.
+//! The current time is read from the `time` CSR, and the next timer interrupt
+//! is programmed through the SBI `set_timer` call, as `mtimecmp` is only
+//! accessible from M-mode.
+//!
+//! Each CPU has a queue of timer events, ordered by deadline. The compare
+//! register is only programmed for the earliest one, and expired events have
+//! their callback run from the timer interrupt.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
 
 use spin::Mutex;
 
-/// Timer ticks per second
-pub const TICKS_PER_SEC: u64 = 100;
-/// CPU clock frequency in Hz (10MHz for QEMU)
-pub const CLOCK_FREQ: u64 = 10_000_000;
-/// Interval between timer interrupts in CPU cycles
-const TIMER_INTERVAL: u64 = CLOCK_FREQ / TICKS_PER_SEC;
-
-/// CLINT base address in QEMU virt machine
-const CLINT_BASE: usize = 0x0200_0000;
-/// CLINT timer register offset for hart 0
-const CLINT_MTIME_OFFSET: usize = 0xBFF8;
-/// CLINT mtimecmp register offset for hart 0
-const CLINT_MTIMECMP_OFFSET: usize = 0x4000;
-
-static TIMER_COUNT: Mutex<u64> = Mutex::new(0);
-
-/// Initialize the timer by setting the first timer interrupt
-pub fn init() {
-    let deadline = read_time() + TIMER_INTERVAL;
-    write_mtimecmp(deadline);
+use crate::percpu;
+use crate::riscv64::{instructions, sbi};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Frequency of the `time` CSR in Hz, from the device tree
+static CLOCK_FREQ: AtomicU64 = AtomicU64::new(10_000_000);
+
+/// Frequency of the `time` CSR in Hz
+pub fn clock_freq() -> u64 {
+    CLOCK_FREQ.load(Ordering::Relaxed)
 }
 
-/// Handle timer interrupt - increment counter and set next interrupt
-pub fn handle_timer_interrupt() {
-    let mut count = TIMER_COUNT.lock();
-    *count += 1;
-    drop(count);
+/// Function run once the deadline of a timer event is reached, with the
+/// current `time`
+pub type TimerCallback = Box<dyn FnOnce(u64) + Send>;
 
-    // Set next timer interrupt
-    let deadline = read_time() + TIMER_INTERVAL;
-    write_mtimecmp(deadline);
+/// Handle of a pending timer event, to [`cancel`] it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId {
+    deadline: u64,
+    seq: usize,
 }
 
-/// Get the current number of ticks
-pub fn current_ticks() -> u64 {
-    *TIMER_COUNT.lock()
+impl TimerId {
+    /// `time` value at which the event fires
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
 }
 
-/// Convert ticks to nanoseconds
-pub fn ticks_to_nanos(ticks: u64) -> u64 {
-    ticks * 1_000_000_000 / CLOCK_FREQ
+/// Pending timer events of one CPU
+pub struct TimerQueue {
+    events: Mutex<BTreeMap<TimerId, TimerCallback>>,
+    next_seq: AtomicUsize,
 }
 
-/// Read the current time from CLINT mtime register
-#[inline]
-fn read_time() -> u64 {
-    unsafe {
-        core::ptr::read_volatile((CLINT_BASE + CLINT_MTIME_OFFSET) as *const u64)
+impl TimerQueue {
+    pub const fn new() -> Self {
+        Self {
+            events: Mutex::new(BTreeMap::new()),
+            next_seq: AtomicUsize::new(0),
+        }
+    }
+
+    /// Lock the events with interrupts disabled, as the timer interrupt
+    /// handler takes the same lock
+    fn with_events<R>(&self, f: impl FnOnce(&mut BTreeMap<TimerId, TimerCallback>) -> R) -> R {
+        instructions::with_irqs_disabled(|| f(&mut self.events.lock()))
+    }
+
+    /// Program the compare register for the earliest event, or disable it
+    fn program(events: &BTreeMap<TimerId, TimerCallback>) {
+        let deadline = events.keys().next().map_or(u64::MAX, |id| id.deadline);
+        sbi::set_timer(deadline);
+    }
+
+    fn add(&self, deadline: u64, callback: TimerCallback) -> TimerId {
+        let id = TimerId {
+            deadline,
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+        };
+        self.with_events(|events| {
+            let earliest = events.keys().next().is_none_or(|first| id < *first);
+            events.insert(id, callback);
+            if earliest {
+                Self::program(events);
+            }
+        });
+        id
+    }
+
+    fn remove(&self, id: TimerId) -> bool {
+        self.with_events(|events| {
+            let earliest = events.keys().next() == Some(&id);
+            let removed = events.remove(&id).is_some();
+            if earliest {
+                Self::program(events);
+            }
+            removed
+        })
+    }
+
+    /// Run the callbacks of the events expired at `now`, without the lock
+    /// held so that they can add events, then program the next one
+    fn expire(&self, now: u64) {
+        loop {
+            let expired = self.with_events(|events| match events.first_entry() {
+                Some(entry) if entry.key().deadline <= now => Some(entry.remove()),
+                _ => None,
+            });
+            match expired {
+                Some(callback) => callback(now),
+                None => break,
+            }
+        }
+        self.with_events(|events| Self::program(events));
     }
 }
 
-/// Write to CLINT mtimecmp register to set next timer interrupt
+/// Run `callback` on the current CPU from the timer interrupt once `time`
+/// reaches `deadline`. A deadline in the past fires at once.
+pub fn set_timer(deadline: u64, callback: impl FnOnce(u64) + Send + 'static) -> TimerId {
+    percpu::current()
+        .timer_queue
+        .add(deadline, Box::new(callback))
+}
+
+/// Withdraw an event of the current CPU that has not fired yet, returns
+/// whether it was pending
+pub fn cancel(id: TimerId) -> bool {
+    percpu::current().timer_queue.remove(id)
+}
+
+/// Initialize the timer of the current CPU with the frequency of the `time`
+/// CSR, with no event pending
+pub fn init(clock_freq: u64) {
+    CLOCK_FREQ.store(clock_freq, Ordering::Relaxed);
+    sbi::set_timer(u64::MAX);
+}
+
+/// Handle timer interrupt - run the expired timer events of the current CPU
+pub fn handle_timer_interrupt() {
+    percpu::current().timer_queue.expire(read_time());
+}
+
+/// Convert `time` CSR ticks to nanoseconds
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SEC / clock_freq() as u128) as u64
+}
+
+/// Convert a duration to `time` CSR ticks, rounding up so that a deadline is
+/// never early
+pub fn nanos_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * clock_freq() as u128).div_ceil(NANOS_PER_SEC);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Read the current time from the `time` CSR
 #[inline]
-fn write_mtimecmp(deadline: u64) {
-    unsafe {
-        core::ptr::write_volatile((CLINT_BASE + CLINT_MTIMECMP_OFFSET) as *mut u64, deadline)
-    }
+pub fn read_time() -> u64 {
+    instructions::read_time()
 }
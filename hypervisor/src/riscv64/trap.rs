@@ -1,6 +1,6 @@
 //! RISC-V trap (interrupt/exception) handler
 //!
-//! Handles machine-mode traps including interrupts and exceptions.
+//! Handles supervisor-mode traps including interrupts and exceptions.
 #![allow(dead_code)]
 
 use core::arch::asm;
@@ -8,7 +8,7 @@ use core::arch::global_asm;
 use log::{info, warn};
 
 use crate::riscv64::instructions;
-use crate::riscv64::timer;
+use crate::riscv64::{ipi, timer};
 
 // Declare the trap handler assembly function
 extern "C" {
@@ -95,20 +95,24 @@ trap_handler:
     ld x31, 31*8(sp)
 
     addi sp, sp, 32*8
-    mret
+    sret
 "#
 );
 
 /// Interrupt causes
 pub const INTERRUPT_U_SOFT: usize = 0;
 pub const INTERRUPT_S_SOFT: usize = 1;
+pub const INTERRUPT_VS_SOFT: usize = 2;
 pub const INTERRUPT_M_SOFT: usize = 3;
 pub const INTERRUPT_U_TIMER: usize = 4;
 pub const INTERRUPT_S_TIMER: usize = 5;
+pub const INTERRUPT_VS_TIMER: usize = 6;
 pub const INTERRUPT_M_TIMER: usize = 7;
 pub const INTERRUPT_U_EXT: usize = 8;
 pub const INTERRUPT_S_EXT: usize = 9;
+pub const INTERRUPT_VS_EXT: usize = 10;
 pub const INTERRUPT_M_EXT: usize = 11;
+pub const INTERRUPT_S_GUEST_EXT: usize = 12;
 
 /// Exception causes
 pub const EXCEPTION_INST_ADDR_MISALIGNED: usize = 0;
@@ -121,10 +125,15 @@ pub const EXCEPTION_STORE_ADDR_MISALIGNED: usize = 6;
 pub const EXCEPTION_STORE_ACCESS_FAULT: usize = 7;
 pub const EXCEPTION_ECALL_FROM_U: usize = 8;
 pub const EXCEPTION_ECALL_FROM_S: usize = 9;
+pub const EXCEPTION_ECALL_FROM_VS: usize = 10;
 pub const EXCEPTION_ECALL_FROM_M: usize = 11;
 pub const EXCEPTION_INST_PAGE_FAULT: usize = 12;
 pub const EXCEPTION_LOAD_PAGE_FAULT: usize = 13;
 pub const EXCEPTION_STORE_PAGE_FAULT: usize = 15;
+pub const EXCEPTION_INST_GUEST_PAGE_FAULT: usize = 20;
+pub const EXCEPTION_LOAD_GUEST_PAGE_FAULT: usize = 21;
+pub const EXCEPTION_VIRTUAL_INST: usize = 22;
+pub const EXCEPTION_STORE_GUEST_PAGE_FAULT: usize = 23;
 
 /// Trap context (register state)
 #[repr(C)]
@@ -171,9 +180,9 @@ pub extern "C" fn trap_handler_rust(_tf: *const TrapFrame) {
     unsafe {
         trace!("trap : {:#x?}", *_tf);
     }
-    let mcause = instructions::read_mcause();
-    let is_interrupt = (mcause & 0x8000_0000_0000_0000) != 0;
-    let cause_code = mcause & 0x7fff_ffff_ffff_ffff;
+    let scause = instructions::read_scause();
+    let is_interrupt = (scause & 0x8000_0000_0000_0000) != 0;
+    let cause_code = scause & 0x7fff_ffff_ffff_ffff;
 
     if is_interrupt {
         handle_interrupt(cause_code);
@@ -186,13 +195,13 @@ pub extern "C" fn trap_handler_rust(_tf: *const TrapFrame) {
 #[inline]
 fn handle_interrupt(cause: usize) {
     match cause {
-        INTERRUPT_M_TIMER => {
+        INTERRUPT_S_TIMER => {
             timer::handle_timer_interrupt();
         }
-        INTERRUPT_M_SOFT => {
-            // Software interrupt - can be used for IPI
+        INTERRUPT_S_SOFT => {
+            ipi::handle_ipi();
         }
-        INTERRUPT_M_EXT => {
+        INTERRUPT_S_EXT => {
             // External interrupt
         }
         _ => {
@@ -205,19 +214,19 @@ fn handle_interrupt(cause: usize) {
 #[inline]
 fn handle_exception(cause: usize) {
     match cause {
-        EXCEPTION_ECALL_FROM_M => {
+        EXCEPTION_ECALL_FROM_S => {
             // Handle system calls
-            warn!("System call from machine mode (not expected)");
+            warn!("System call from supervisor mode (not expected)");
         }
         EXCEPTION_ILLEGAL_INST => {
-            warn!("Illegal instruction at {:#x}", instructions::read_mepc());
+            warn!("Illegal instruction at {:#x}", instructions::read_sepc());
         }
         _ => {
             warn!(
-                "Unhandled exception {}: mepc={:#x}, mtval={:#x}",
+                "Unhandled exception {}: sepc={:#x}, stval={:#x}",
                 cause,
-                instructions::read_mepc(),
-                instructions::read_mtval()
+                instructions::read_sepc(),
+                instructions::read_stval()
             );
         }
     }
@@ -230,11 +239,12 @@ pub fn init() {
 
     // Set trap handler address
     unsafe {
-        asm!("csrw mtvec, {}", in(reg) trap_handler as usize);
+        asm!("csrw stvec, {}", in(reg) trap_handler as usize);
     }
 
-    // Enable timer interrupt
-    instructions::enable_mtie();
+    // Enable timer and software (IPI) interrupts
+    instructions::enable_stie();
+    instructions::enable_ssie();
 
     // Enable global interrupts
     instructions::enable_irqs();
@@ -2,7 +2,10 @@
 
 use spin::Mutex;
 
-const UART_BASE: usize = 0x1000_0000;
+use crate::mm::address::phys_to_virt;
+
+/// Used until [`init`] is called with the address from the device tree.
+const DEFAULT_UART_BASE: usize = 0x1000_0000;
 
 bitflags::bitflags! {
     /// Line status flags
@@ -75,17 +78,19 @@ impl Uart16550 {
     }
 }
 
-static UART: Mutex<Uart16550> = Mutex::new(Uart16550::new(UART_BASE));
+static UART: Mutex<Uart16550> = Mutex::new(Uart16550::new(phys_to_virt(DEFAULT_UART_BASE)));
 
 pub fn console_putchar(c: u8) {
     UART.lock().putchar(c);
 }
 
-#[allow(dead_code)]
 pub fn console_getchar() -> Option<u8> {
     UART.lock().getchar()
 }
 
-pub fn init() {
-    UART.lock().init(115200);
+/// Initialize the UART at physical address `base`.
+pub fn init(base: usize) {
+    let mut uart = UART.lock();
+    *uart = Uart16550::new(phys_to_virt(base));
+    uart.init(115200);
 }
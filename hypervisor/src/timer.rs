@@ -1,9 +1,75 @@
-use core::time::Duration;
+//! Monotonic clock of the hypervisor, read from the `time` CSR.
+
+use core::ops::{Add, AddAssign, Sub};
+pub use core::time::Duration;
 
 use crate::riscv64::timer;
 
 pub type TimeValue = Duration;
 
+/// A point in time, counted in ticks of the `time` CSR since it was reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time.
+    pub fn now() -> Self {
+        Self(timer::read_time())
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// Value of the `time` CSR at this instant, for deadlines of
+    /// `riscv64::timer`.
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed from `earlier` to `self`, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(timer::ticks_to_nanos(self.0.saturating_sub(earlier.0)))
+    }
+
+    /// Time elapsed since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the largest instant, which never comes.
+    fn add(self, duration: Duration) -> Instant {
+        Self(self.0.saturating_add(timer::nanos_to_ticks(duration)))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Time since the `time` CSR was reset, usually at power on.
 pub fn current_time() -> TimeValue {
-    TimeValue::from_nanos(timer::ticks_to_nanos(timer::current_ticks()))
+    Instant::now().duration_since(Instant::from_ticks(0))
+}
+
+/// Busy-wait for `duration`.
+pub fn delay(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
 }
@@ -68,10 +68,12 @@ pub fn read_mimpid() -> usize {
 
 /// CLINT (Core-Local Interruptor) base address for QEMU virt machine
 const CLINT_BASE: usize = 0x0200_0000;
-/// mtimecmp offset for hart 0
-const MTIMECMP_HART0: usize = 0x4000;
+/// mtimecmp offset for hart 0, each hart has its own 8-byte register
+const MTIMECMP_BASE: usize = 0x4000;
 /// mtime offset
 const MTIME: usize = 0xBFF8;
+/// msip offset for hart 0, each hart has its own 4-byte register
+const MSIP_BASE: usize = 0x0;
 
 /// Read time CSR
 #[inline]
@@ -83,11 +85,17 @@ pub fn read_time() -> usize {
     time
 }
 
+/// Address of the mtimecmp register of the current hart
+#[inline]
+fn mtimecmp_addr() -> usize {
+    CLINT_BASE + MTIMECMP_BASE + read_hart_id() * 8
+}
+
 /// Read timecmp (mtimecmp) memory-mapped register
 #[inline]
 pub fn read_timecmp() -> usize {
     unsafe {
-        ((CLINT_BASE + MTIMECMP_HART0) as *const u64).read_volatile() as usize
+        (mtimecmp_addr() as *const u64).read_volatile() as usize
     }
 }
 
@@ -95,7 +103,25 @@ pub fn read_timecmp() -> usize {
 #[inline]
 pub fn write_timecmp(value: usize) {
     unsafe {
-        ((CLINT_BASE + MTIMECMP_HART0) as *mut u64).write_volatile(value as u64);
+        (mtimecmp_addr() as *mut u64).write_volatile(value as u64);
+    }
+}
+
+/// Write the stimecmp CSR (Sstc), which raises `mip.STIP` when `time` reaches
+/// it if `menvcfg.STCE` is set
+#[inline]
+pub fn write_stimecmp(value: usize) {
+    unsafe {
+        asm!("csrw 0x14d, {}", in(reg) value);
+    }
+}
+
+/// Write the msip register of `hart_id`, raising or clearing its machine
+/// software interrupt
+#[inline]
+pub fn write_msip(hart_id: usize, value: u32) {
+    unsafe {
+        ((CLINT_BASE + MSIP_BASE + hart_id * 4) as *mut u32).write_volatile(value);
     }
 }
 
@@ -143,6 +169,14 @@ pub fn disable_timer_interrupt() {
     }
 }
 
+/// Enable machine software interrupt (set MSIE bit in mie)
+#[inline]
+pub fn enable_software_interrupt() {
+    unsafe {
+        asm!("csrs mie, {}", in(reg) 0x8); // MSIE = bit 3
+    }
+}
+
 // ============================================================================
 // Machine Trap Vector (mtvec)
 // ============================================================================
@@ -217,3 +251,107 @@ pub fn write_mtval(value: usize) {
         asm!("csrw mtval, {}", in(reg) value);
     }
 }
+
+// ============================================================================
+// Trap Delegation
+// ============================================================================
+
+/// Write machine exception delegation register
+#[inline]
+pub fn write_medeleg(value: usize) {
+    unsafe {
+        asm!("csrw medeleg, {}", in(reg) value);
+    }
+}
+
+/// Write machine interrupt delegation register
+#[inline]
+pub fn write_mideleg(value: usize) {
+    unsafe {
+        asm!("csrw mideleg, {}", in(reg) value);
+    }
+}
+
+/// Write machine counter-enable register
+#[inline]
+pub fn write_mcounteren(value: usize) {
+    unsafe {
+        asm!("csrw mcounteren, {}", in(reg) value);
+    }
+}
+
+// ============================================================================
+// Machine Environment Configuration (menvcfg)
+// ============================================================================
+
+/// `menvcfg.STCE`: S-mode timer interrupts come from `stimecmp` (Sstc)
+const MENVCFG_STCE: usize = 1 << 63;
+
+/// Set `menvcfg.STCE` if the Sstc extension is implemented, so that S-mode
+/// programs `stimecmp` itself. Returns whether it was set.
+///
+/// `menvcfg` only exists from privileged spec 1.12, so it is accessed with
+/// `mtvec` pointing just past the access: an illegal-instruction trap then
+/// skips it. Machine interrupts must be disabled.
+pub fn enable_sstc() -> bool {
+    let menvcfg: usize;
+    unsafe {
+        asm!(
+            "la     {mtvec}, 1f",
+            "csrrw  {mtvec}, mtvec, {mtvec}",
+            "li     {menvcfg}, 0",
+            "csrs   menvcfg, {stce}",
+            "csrr   {menvcfg}, menvcfg",
+            ".align 2",
+            "1:",
+            "csrw   mtvec, {mtvec}",
+            mtvec = out(reg) _,
+            menvcfg = out(reg) menvcfg,
+            stce = in(reg) MENVCFG_STCE,
+        );
+    }
+    // STCE is read-only zero without Sstc.
+    menvcfg & MENVCFG_STCE != 0
+}
+
+// ============================================================================
+// Machine Interrupt Pending (mip)
+// ============================================================================
+
+/// Raise the supervisor timer interrupt (set STIP bit in mip)
+#[inline]
+pub fn set_stip() {
+    unsafe {
+        asm!("csrs mip, {}", in(reg) 0x20); // STIP = bit 5
+    }
+}
+
+/// Clear the supervisor timer interrupt (clear STIP bit in mip)
+#[inline]
+pub fn clear_stip() {
+    unsafe {
+        asm!("csrc mip, {}", in(reg) 0x20); // STIP = bit 5
+    }
+}
+
+/// Raise the supervisor software interrupt (set SSIP bit in mip)
+#[inline]
+pub fn set_ssip() {
+    unsafe {
+        asm!("csrs mip, {}", in(reg) 0x2); // SSIP = bit 1
+    }
+}
+
+// ============================================================================
+// Physical Memory Protection
+// ============================================================================
+
+/// Give S-mode and U-mode RWX access to the whole physical address space,
+/// with a single NAPOT region in PMP entry 0.
+#[inline]
+pub fn setup_pmp_all() {
+    unsafe {
+        asm!("csrw pmpaddr0, {}", in(reg) usize::MAX >> 10);
+        asm!("csrw pmpcfg0, {}", in(reg) 0x1f); // A = NAPOT, R | W | X
+    }
+}
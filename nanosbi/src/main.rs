@@ -1,5 +1,7 @@
 // RISC-V nanoSBI entry point
-// For QEMU, the bootloader sets:
+// For QEMU, all harts start here at the same time, and the bootloader sets:
+// - a0 = hart_id
+// - a1 = device_tree_blob
 // - pc = 0x80000000 (default load address)
 
 #![no_std]
@@ -17,10 +19,21 @@ global_asm!(
 .section .text.entry
 .globl _start
 _start:
-    // Set stack pointer
+    // Park the harts we have no stack for
+    csrr t0, mhartid
+    li t1, {max_harts}
+    bgeu t0, t1, park
+
+    // Set stack pointer, each hart has its own stack below `stack_top`
     la sp, stack_top
+    li t1, {stack_size}
+    mul t1, t0, t1
+    sub sp, sp, t1
+
+    // Hart 0 clears the BSS section, the others wait for it
+    bnez t0, wait_bss_cleared
 
-    // Clear BSS section
+    // Clear BSS section, keeping a0 and a1 for `_start_rust`
     la t0, sbss
     la t1, ebss
     bgeu t0, t1, clear_bss_done
@@ -31,16 +44,37 @@ clear_bss_loop:
     bltu t0, t1, clear_bss_loop
 
 clear_bss_done:
+    fence rw, rw
+    la t0, bss_cleared
+    li t1, 1
+    sw t1, (t0)
+    j start_rust
+
+wait_bss_cleared:
+    la t0, bss_cleared
+1:
+    lw t1, (t0)
+    beqz t1, 1b
+    fence rw, rw
+
+start_rust:
     // Jump to Rust entry point
     call _start_rust
 
+park:
     // Never return
-    j .
+    wfi
+    j park
+
+.section .data
+.align 2
+bss_cleared:
+    .word 0
 
 .section .bss.stack
 .align 4
 stack_bottom:
-    .space 0x1000  # 4KB stack space
+    .space {stack_size} * {max_harts}
 stack_top:
 
 .section .sbss
@@ -50,35 +84,87 @@ sbss:
 .section .ebss
 .align 4
 ebss:
-"#
+"#,
+    max_harts = const MAX_HARTS,
+    stack_size = const STACK_SIZE,
 );
 
-pub const HYPERVISOR_ENTRY: usize = 0x8002_0000;
+/// Number of harts with a stack, the others are parked.
+pub const MAX_HARTS: usize = 8;
+/// Size of the M-mode stack of each hart.
+const STACK_SIZE: usize = 0x1000;
+
+/// Where the hypervisor is loaded, the same as the default OpenSBI jump
+/// address so that the hypervisor runs on either firmware.
+pub const HYPERVISOR_ENTRY: usize = 0x8020_0000;
+
+/// `mstatus.MPP` = 01, return to S-mode (HS-mode with the H extension).
+const MSTATUS_MPP_S: usize = 1 << 11;
+/// `mstatus.FS` = Initial, so that S-mode can use the FPU.
+const MSTATUS_FS_INITIAL: usize = 1 << 13;
+
+/// Exceptions handled by S-mode directly: misaligned fetch, access faults,
+/// illegal instruction, breakpoint, misaligned load/store, ecall from U-mode
+/// and VS-mode, page faults and the H-extension guest exceptions.
+const MEDELEG: usize = (1 << 0)
+    | (1 << 1)
+    | (1 << 2)
+    | (1 << 3)
+    | (1 << 4)
+    | (1 << 5)
+    | (1 << 6)
+    | (1 << 7)
+    | (1 << 8)
+    | (1 << 10)
+    | (1 << 12)
+    | (1 << 13)
+    | (1 << 15)
+    | (1 << 20)
+    | (1 << 21)
+    | (1 << 22)
+    | (1 << 23);
+/// Supervisor software, timer and external interrupts.
+const MIDELEG: usize = (1 << 1) | (1 << 5) | (1 << 9);
 
 #[no_mangle]
-pub extern "C" fn _start_rust() -> ! {
+pub extern "C" fn _start_rust(hart_id: usize, dtb: usize) -> ! {
     // Initialize trap handler
     trap::init();
 
-    switch_to_hs_mode_simple();
-
-    loop {
-        core::hint::spin_loop();
-    }
+    switch_to_hs_mode_simple(hart_id, dtb);
 }
 
 /// Switch to HS-mode
-fn switch_to_hs_mode_simple() {
+fn switch_to_hs_mode_simple(hart_id: usize, dtb: usize) -> ! {
     use core::arch::asm;
 
-    // Set hypervisor entry address
+    // Let S-mode handle its own traps, access all memory and read `time`
+    csr::write_medeleg(MEDELEG);
+    csr::write_mideleg(MIDELEG);
+    csr::setup_pmp_all();
+    csr::write_mcounteren(0x7); // CY, TM, IR
+    // With Sstc, S-mode and VS-mode timers are programmed without SBI calls,
+    // and `sbi_set_timer` also goes through `stimecmp`
+    sbi::timer::init(csr::enable_sstc());
+
+    // Traps from S-mode run on the boot stack of this hart, as it is no
+    // longer used
     unsafe {
-        asm!("csrw mepc, {}", in(reg) HYPERVISOR_ENTRY);
+        asm!(
+            "la {0}, stack_top",
+            "sub {0}, {0}, {1}",
+            "csrw mscratch, {0}",
+            out(reg) _,
+            in(reg) hart_id * STACK_SIZE,
+        );
     }
 
+    // Set hypervisor entry address
+    csr::write_mepc(HYPERVISOR_ENTRY);
+
     // Configure mstatus register
     // MPP = 01 (HS-mode), MIE = 0
-    let mstatus_value = 0x0000_0000_0000_0800;
+    let mstatus_value = MSTATUS_MPP_S | MSTATUS_FS_INITIAL;
     unsafe {
         asm!("csrw mstatus, {}", in(reg) mstatus_value);
     }
@@ -88,9 +174,9 @@ fn switch_to_hs_mode_simple() {
         asm!("csrw satp, zero");
     }
 
-    // Execute switching
+    // Execute switching, passing the hart ID and device tree like OpenSBI
     unsafe {
-        asm!("mret");
+        asm!("mret", in("a0") hart_id, in("a1") dtb, options(noreturn));
     }
 }
 
@@ -63,6 +63,7 @@ fn probe_extension(extension_id: usize) -> bool {
     match extension_id {
         x if x == SbiExtension::Base as usize => true,
         x if x == SbiExtension::Timer as usize => true,
+        x if x == SbiExtension::Ipi as usize => true,
         x if x == SbiExtension::HardwareFeatures as usize => true,
         _ => false,
     }
@@ -0,0 +1,43 @@
+use crate::csr;
+use crate::sbi::{SbiContext, SbiError, SbiResult};
+use crate::MAX_HARTS;
+
+/// Function ID of `sbi_send_ipi` in the IPI extension.
+const FID_SEND_IPI: usize = 0;
+
+pub fn handle_ipi_call(function: usize, context: &mut SbiContext) -> SbiResult<()> {
+    match function {
+        FID_SEND_IPI => {
+            context.a0 = match send_ipi(context.a0, context.a1) {
+                Ok(()) => SbiError::Success,
+                Err(e) => e,
+            } as usize;
+            context.a1 = 0;
+            Ok(())
+        }
+        _ => {
+            context.a0 = SbiError::NotSupported as usize;
+            context.a1 = 0;
+            Ok(())
+        }
+    }
+}
+
+/// Raise the machine software interrupt of the harts in `hart_mask`, counted
+/// from `hart_mask_base`, or of all harts if `hart_mask_base` is `-1`.
+fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+    let (hart_mask, hart_mask_base) = match hart_mask_base {
+        usize::MAX => ((1 << MAX_HARTS) - 1, 0),
+        _ => (hart_mask, hart_mask_base),
+    };
+    let harts = (0..usize::BITS as usize)
+        .filter(|i| hart_mask & (1 << i) != 0)
+        .map(|i| hart_mask_base.saturating_add(i));
+    if harts.clone().any(|hart_id| hart_id >= MAX_HARTS) {
+        return Err(SbiError::InvalidParam);
+    }
+    for hart_id in harts {
+        csr::write_msip(hart_id, 1);
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
 pub mod base;
+pub mod ipi;
 pub mod timer;
 
 use crate::trap::SbiContext;
@@ -10,6 +11,7 @@ use crate::csr::read_misa;
 pub enum SbiExtension {
     Base = 0x10,
     Timer = 0x54494D45,
+    Ipi = 0x735049,
     HardwareFeatures = 0x48574446,
 }
 
@@ -27,9 +29,6 @@ pub enum SbiFunction {
     GetMarchid = 6,
     GetMimpid = 7,
 
-    // Timer extension functions (0x54494D45)
-    SetTimer = 0x100,
-
     // Hardware feature extension functions (0x48574446)
     GetHardwareFeatures = 0x200,
 }
@@ -58,6 +57,7 @@ pub fn handle_sbi_call(context: &mut SbiContext) -> SbiResult<()> {
     match extension {
         x if x == SbiExtension::Base as usize => base::handle_base_call(function, context),
         x if x == SbiExtension::Timer as usize => timer::handle_timer_call(function, context),
+        x if x == SbiExtension::Ipi as usize => ipi::handle_ipi_call(function, context),
         x if x == SbiExtension::HardwareFeatures as usize => {
             handle_hardware_features_call(function, context)
         }
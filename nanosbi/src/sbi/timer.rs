@@ -1,9 +1,24 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::csr;
-use crate::sbi::{SbiContext, SbiError, SbiFunction, SbiResult};
+use crate::sbi::{SbiContext, SbiError, SbiResult};
+
+/// Function ID of `sbi_set_timer` in the Timer extension.
+const FID_SET_TIMER: usize = 0;
+
+/// Whether `menvcfg.STCE` is set. `mip.STIP` then follows `stimecmp` and
+/// cannot be written.
+static SSTC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Choose how `sbi_set_timer` is served, `sstc` telling whether
+/// `menvcfg.STCE` is set.
+pub fn init(sstc: bool) {
+    SSTC_ENABLED.store(sstc, Ordering::Relaxed);
+}
 
 pub fn handle_timer_call(function: usize, context: &mut SbiContext) -> SbiResult<()> {
     match function {
-        x if x == SbiFunction::SetTimer as usize => {
+        FID_SET_TIMER => {
             let time = context.a0;
             set_timer(time);
             context.a0 = SbiError::Success as usize;
@@ -19,6 +34,11 @@ pub fn handle_timer_call(function: usize, context: &mut SbiContext) -> SbiResult
 }
 
 fn set_timer(time: usize) {
+    if SSTC_ENABLED.load(Ordering::Relaxed) {
+        csr::write_stimecmp(time);
+        return;
+    }
+    csr::clear_stip();
     csr::write_timecmp(time);
     csr::enable_timer_interrupt();
 }
\ No newline at end of file
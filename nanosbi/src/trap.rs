@@ -15,80 +15,96 @@ pub struct SbiContext {
     pub a7: usize,
 }
 
+/// General-purpose registers of the interrupted hart, saved on the M-mode
+/// stack by `trap_entry`. `regs[i]` holds `x{i}`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+}
+
 pub fn init() {
     csr::write_mtvec(trap_entry as usize);
+    csr::enable_software_interrupt();
     csr::enable_m_interrupts();
 }
 
+/// Traps are only expected from S-mode and U-mode, `mscratch` holds the top
+/// of the M-mode stack while they run.
 #[unsafe(naked)]
 #[no_mangle]
 unsafe extern "C" fn trap_entry() {
     core::arch::naked_asm!(
+        "csrrw sp, mscratch, sp",
         "addi sp, sp, -256",
-        "sd ra, 0(sp)",
-        "sd gp, 8(sp)",
-        "sd tp, 16(sp)",
-        "sd t0, 24(sp)",
-        "sd t1, 32(sp)",
-        "sd t2, 40(sp)",
-        "sd s0, 48(sp)",
-        "sd s1, 56(sp)",
-        "sd a0, 64(sp)",
-        "sd a1, 72(sp)",
-        "sd a2, 80(sp)",
-        "sd a3, 88(sp)",
-        "sd a4, 96(sp)",
-        "sd a5, 104(sp)",
-        "sd a6, 112(sp)",
-        "sd a7, 120(sp)",
-        "sd s2, 128(sp)",
-        "sd s3, 136(sp)",
-        "sd s4, 144(sp)",
-        "sd s5, 152(sp)",
-        "sd s6, 160(sp)",
-        "sd s7, 168(sp)",
-        "sd s8, 176(sp)",
-        "sd s9, 184(sp)",
-        "sd s10, 192(sp)",
-        "sd s11, 200(sp)",
-        "sd t3, 208(sp)",
-        "sd t4, 216(sp)",
-        "sd t5, 224(sp)",
-        "sd t6, 232(sp)",
-
+        "sd ra, 8(sp)",
+        "sd gp, 24(sp)",
+        "sd tp, 32(sp)",
+        "sd t0, 40(sp)",
+        "sd t1, 48(sp)",
+        "sd t2, 56(sp)",
+        "sd s0, 64(sp)",
+        "sd s1, 72(sp)",
+        "sd a0, 80(sp)",
+        "sd a1, 88(sp)",
+        "sd a2, 96(sp)",
+        "sd a3, 104(sp)",
+        "sd a4, 112(sp)",
+        "sd a5, 120(sp)",
+        "sd a6, 128(sp)",
+        "sd a7, 136(sp)",
+        "sd s2, 144(sp)",
+        "sd s3, 152(sp)",
+        "sd s4, 160(sp)",
+        "sd s5, 168(sp)",
+        "sd s6, 176(sp)",
+        "sd s7, 184(sp)",
+        "sd s8, 192(sp)",
+        "sd s9, 200(sp)",
+        "sd s10, 208(sp)",
+        "sd s11, 216(sp)",
+        "sd t3, 224(sp)",
+        "sd t4, 232(sp)",
+        "sd t5, 240(sp)",
+        "sd t6, 248(sp)",
+        "csrr t0, mscratch",
+        "sd t0, 16(sp)",
+
+        "mv a0, sp",
         "call trap_handler",
 
-        "ld ra, 0(sp)",
-        "ld gp, 8(sp)",
-        "ld tp, 16(sp)",
-        "ld t0, 24(sp)",
-        "ld t1, 32(sp)",
-        "ld t2, 40(sp)",
-        "ld s0, 48(sp)",
-        "ld s1, 56(sp)",
-        "ld a0, 64(sp)",
-        "ld a1, 72(sp)",
-        "ld a2, 80(sp)",
-        "ld a3, 88(sp)",
-        "ld a4, 96(sp)",
-        "ld a5, 104(sp)",
-        "ld a6, 112(sp)",
-        "ld a7, 120(sp)",
-        "ld s2, 128(sp)",
-        "ld s3, 136(sp)",
-        "ld s4, 144(sp)",
-        "ld s5, 152(sp)",
-        "ld s6, 160(sp)",
-        "ld s7, 168(sp)",
-        "ld s8, 176(sp)",
-        "ld s9, 184(sp)",
-        "ld s10, 192(sp)",
-        "ld s11, 200(sp)",
-        "ld t3, 208(sp)",
-        "ld t4, 216(sp)",
-        "ld t5, 224(sp)",
-        "ld t6, 232(sp)",
+        "ld ra, 8(sp)",
+        "ld gp, 24(sp)",
+        "ld tp, 32(sp)",
+        "ld t0, 40(sp)",
+        "ld t1, 48(sp)",
+        "ld t2, 56(sp)",
+        "ld s0, 64(sp)",
+        "ld s1, 72(sp)",
+        "ld a0, 80(sp)",
+        "ld a1, 88(sp)",
+        "ld a2, 96(sp)",
+        "ld a3, 104(sp)",
+        "ld a4, 112(sp)",
+        "ld a5, 120(sp)",
+        "ld a6, 128(sp)",
+        "ld a7, 136(sp)",
+        "ld s2, 144(sp)",
+        "ld s3, 152(sp)",
+        "ld s4, 160(sp)",
+        "ld s5, 168(sp)",
+        "ld s6, 176(sp)",
+        "ld s7, 184(sp)",
+        "ld s8, 192(sp)",
+        "ld s9, 200(sp)",
+        "ld s10, 208(sp)",
+        "ld s11, 216(sp)",
+        "ld t3, 224(sp)",
+        "ld t4, 232(sp)",
+        "ld t5, 240(sp)",
+        "ld t6, 248(sp)",
         "addi sp, sp, 256",
+        "csrrw sp, mscratch, sp",
 
         "mret",
     );
@@ -116,7 +132,7 @@ pub const EXCEPTION_LOAD_PAGE_FAULT: usize = 13;
 pub const EXCEPTION_STORE_PAGE_FAULT: usize = 15;
 
 #[no_mangle]
-extern "C" fn trap_handler() {
+extern "C" fn trap_handler(tf: &mut TrapFrame) {
     let mcause = csr::read_mcause();
     let mepc = csr::read_mepc();
     let mtval = csr::read_mtval();
@@ -127,7 +143,7 @@ extern "C" fn trap_handler() {
     if is_interrupt {
         handle_interrupt(cause_code);
     } else {
-        handle_exception(cause_code, mepc, mtval);
+        handle_exception(tf, cause_code, mepc, mtval);
     }
 }
 
@@ -144,10 +160,10 @@ fn handle_interrupt(cause: usize) {
     }
 }
 
-fn handle_exception(code: usize, mepc: usize, mtval: usize) {
+fn handle_exception(tf: &mut TrapFrame, code: usize, mepc: usize, mtval: usize) {
     match code {
         EXCEPTION_ECALL_FROM_U => handle_ecall_from_u_mode(mepc),
-        EXCEPTION_ECALL_FROM_S => handle_ecall_from_s_mode(mepc),
+        EXCEPTION_ECALL_FROM_S => handle_ecall_from_s_mode(tf, mepc),
         EXCEPTION_ECALL_FROM_M => handle_ecall_from_m_mode(mepc),
         _ => {
             loop {
@@ -157,15 +173,11 @@ fn handle_exception(code: usize, mepc: usize, mtval: usize) {
     }
 }
 
+/// Forward the M-mode timer interrupt to S-mode. It stays masked until the
+/// next `set_timer` call, and is never enabled with Sstc.
 fn handle_m_timer_interrupt() {
     csr::disable_timer_interrupt();
-
-    // Set next timer interrupt
-    let time = csr::read_time();
-    let next_time = time.wrapping_add(10_000_000); // 100ms
-    csr::write_timecmp(next_time);
-
-    csr::enable_timer_interrupt();
+    csr::set_stip();
 }
 
 fn handle_m_external_interrupt() {
@@ -174,10 +186,11 @@ fn handle_m_external_interrupt() {
     }
 }
 
+/// Forward an IPI sent by `sbi_send_ipi` to S-mode, which clears `SSIP`
+/// itself.
 fn handle_m_software_interrupt() {
-    loop {
-        core::hint::spin_loop();
-    }
+    csr::write_msip(csr::read_hart_id(), 0);
+    csr::set_ssip();
 }
 
 fn handle_ecall_from_u_mode(mepc: usize) {
@@ -186,28 +199,24 @@ fn handle_ecall_from_u_mode(mepc: usize) {
     }
 }
 
-fn handle_ecall_from_s_mode(mepc: usize) {
-    loop {
-        core::hint::spin_loop();
-    }
-}
-
-fn handle_ecall_from_m_mode(mepc: usize) {
+fn handle_ecall_from_s_mode(tf: &mut TrapFrame, mepc: usize) {
+    const A0: usize = 10;
+    let regs = &mut tf.regs;
     let mut context = SbiContext {
-        a0: read_register(10), // a0
-        a1: read_register(11), // a1
-        a2: read_register(12), // a2
-        a3: read_register(13), // a3
-        a4: read_register(14), // a4
-        a5: read_register(15), // a5
-        a6: read_register(16), // a6
-        a7: read_register(17), // a7
+        a0: regs[A0],
+        a1: regs[A0 + 1],
+        a2: regs[A0 + 2],
+        a3: regs[A0 + 3],
+        a4: regs[A0 + 4],
+        a5: regs[A0 + 5],
+        a6: regs[A0 + 6],
+        a7: regs[A0 + 7],
     };
 
     let result = sbi::handle_sbi_call(&mut context);
 
-    write_register(10, context.a0); // a0
-    write_register(11, context.a1); // a1
+    regs[A0] = context.a0;
+    regs[A0 + 1] = context.a1;
 
     csr::write_mepc(mepc + 4);
 
@@ -218,30 +227,8 @@ fn handle_ecall_from_m_mode(mepc: usize) {
     }
 }
 
-fn read_register(reg: usize) -> usize {
-    let value: usize;
-    unsafe {
-        match reg {
-            10 => core::arch::asm!("mv {}, a0", out(reg) value),
-            11 => core::arch::asm!("mv {}, a1", out(reg) value),
-            12 => core::arch::asm!("mv {}, a2", out(reg) value),
-            13 => core::arch::asm!("mv {}, a3", out(reg) value),
-            14 => core::arch::asm!("mv {}, a4", out(reg) value),
-            15 => core::arch::asm!("mv {}, a5", out(reg) value),
-            16 => core::arch::asm!("mv {}, a6", out(reg) value),
-            17 => core::arch::asm!("mv {}, a7", out(reg) value),
-            _ => panic!("Invalid register"),
-        }
+fn handle_ecall_from_m_mode(mepc: usize) {
+    loop {
+        core::hint::spin_loop();
     }
-    value
 }
-
-fn write_register(reg: usize, value: usize) {
-    unsafe {
-        match reg {
-            10 => core::arch::asm!("mv a0, {}", in(reg) value),
-            11 => core::arch::asm!("mv a1, {}", in(reg) value),
-            _ => panic!("Invalid register"),
-        }
-    }
-}
\ No newline at end of file